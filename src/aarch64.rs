@@ -0,0 +1,694 @@
+//! Minimal AArch64 executables, with a small encoder for the A64 instruction
+//! set.
+//!
+//! Every A64 instruction is a single little-endian 32-bit word, so unlike
+//! x86-64 there is nothing to gain by choosing between encodings; the
+//! [`A64Assembler`] just packs the fields and resolves branch targets. It
+//! covers the instructions a small program needs: moving constants, integer
+//! arithmetic, loads and stores, branches, and `svc` for syscalls. On Linux
+//! the syscall number goes in `x8` and the arguments in `x0` to `x5`.
+
+use crate::{elf64_bytes, write_executable};
+use std::fmt;
+use std::path::Path;
+
+/// The `e_machine` value for AArch64.
+pub const EM_AARCH64: u16 = 183;
+
+/// A general-purpose register, either the 64-bit `x` view or the 32-bit `w`
+/// view.
+///
+/// Register 31 is the stack pointer or the zero register depending on the
+/// instruction, as in the architecture: [`Reg::SP`] and [`Reg::XZR`] have the
+/// same encoding, and only [`A64Assembler::mov`] tells them apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reg {
+    n: u8,
+    wide: bool,
+    // register 31 is meant as the stack pointer
+    sp: bool,
+}
+
+impl Reg {
+    /// The stack pointer, for `add`, `sub` and as a load/store base.
+    pub const SP: Reg = Reg {
+        n: 31,
+        wide: true,
+        sp: true,
+    };
+    /// The 64-bit zero register.
+    pub const XZR: Reg = Reg::x(31);
+    /// The 32-bit zero register.
+    pub const WZR: Reg = Reg::w(31);
+    /// The link register `x30`, where `bl` stores the return address.
+    pub const LR: Reg = Reg::x(30);
+
+    /// The 64-bit register `xn`.
+    pub const fn x(n: u8) -> Reg {
+        assert!(n < 32, "no such register");
+        Reg {
+            n,
+            wide: true,
+            sp: false,
+        }
+    }
+
+    /// The 32-bit register `wn`.
+    pub const fn w(n: u8) -> Reg {
+        assert!(n < 32, "no such register");
+        Reg {
+            n,
+            wide: false,
+            sp: false,
+        }
+    }
+
+    // the sf bit that selects 64-bit operation
+    fn sf(self) -> u32 {
+        (self.wide as u32) << 31
+    }
+
+    fn bits(self) -> u32 {
+        self.n as u32
+    }
+}
+
+/// A condition for [`A64Assembler::b_cond`], tested against the flags set by
+/// [`A64Assembler::cmp_imm`] and the other flag-setting instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cond {
+    Eq = 0,
+    Ne = 1,
+    Hs = 2,
+    Lo = 3,
+    Mi = 4,
+    Pl = 5,
+    Vs = 6,
+    Vc = 7,
+    Hi = 8,
+    Ls = 9,
+    Ge = 10,
+    Lt = 11,
+    Gt = 12,
+    Le = 13,
+    Al = 14,
+}
+
+/// A branch target, created with [`A64Assembler::create_label`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct A64Label(usize);
+
+/// An error from [`A64Assembler`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum A64Error {
+    /// `value` doesn't fit the immediate field of `instruction`.
+    Immediate {
+        instruction: &'static str,
+        value: i64,
+    },
+    /// A label was used but never set.
+    UnboundLabel,
+    /// A label was set twice.
+    LabelRedefined,
+    /// The branch or `adr` at word `index` can't reach its label.
+    OutOfRange { index: usize },
+}
+
+impl fmt::Display for A64Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            A64Error::Immediate { instruction, value } => {
+                write!(f, "immediate {value:#x} does not fit in {instruction}")
+            }
+            A64Error::UnboundLabel => write!(f, "label was never set"),
+            A64Error::LabelRedefined => write!(f, "label was set twice"),
+            A64Error::OutOfRange { index } => {
+                write!(f, "branch at {:#x} is out of range", index * 4)
+            }
+        }
+    }
+}
+
+impl std::error::Error for A64Error {}
+
+// How a label's offset (in words) is placed in the instruction.
+#[derive(Debug, Clone, Copy)]
+enum Fixup {
+    // b and bl
+    Imm26,
+    // b.cond, cbz and cbnz
+    Imm19,
+    // adr, which counts bytes and splits them into immlo and immhi
+    Adr,
+}
+
+/// Assembles A64 instructions into a position-independent sequence of words.
+///
+/// Each method appends one instruction (except [`mov_imm`](Self::mov_imm)),
+/// and fails if an immediate doesn't fit. Branches to labels are resolved by
+/// [`assemble`](Self::assemble).
+#[derive(Debug, Clone, Default)]
+pub struct A64Assembler {
+    words: Vec<u32>,
+    labels: Vec<Option<usize>>,
+    fixups: Vec<(usize, A64Label, Fixup)>,
+}
+
+fn unsigned(instruction: &'static str, value: u64, bits: u32) -> Result<u32, A64Error> {
+    if value >> bits == 0 {
+        Ok(value as u32)
+    } else {
+        Err(A64Error::Immediate {
+            instruction,
+            value: value as i64,
+        })
+    }
+}
+
+// A signed field of `bits` bits, or None if `value` doesn't fit.
+fn signed(value: i64, bits: u32) -> Option<u32> {
+    let half = 1i64 << (bits - 1);
+    if (-half..half).contains(&value) {
+        Some(value as u32 & ((1 << bits) - 1))
+    } else {
+        None
+    }
+}
+
+impl A64Assembler {
+    /// An empty program.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The instructions so far, with label references not yet resolved.
+    pub fn words(&self) -> &[u32] {
+        &self.words
+    }
+
+    /// Create a label, to be placed later with [`set_label`](Self::set_label).
+    pub fn create_label(&mut self) -> A64Label {
+        self.labels.push(None);
+        A64Label(self.labels.len() - 1)
+    }
+
+    /// Place `label` at the next instruction.
+    pub fn set_label(&mut self, label: A64Label) -> Result<(), A64Error> {
+        let slot = &mut self.labels[label.0];
+        if slot.is_some() {
+            return Err(A64Error::LabelRedefined);
+        }
+        *slot = Some(self.words.len());
+        Ok(())
+    }
+
+    fn emit(&mut self, word: u32) -> Result<(), A64Error> {
+        self.words.push(word);
+        Ok(())
+    }
+
+    fn emit_fixup(&mut self, word: u32, label: A64Label, fixup: Fixup) -> Result<(), A64Error> {
+        self.fixups.push((self.words.len(), label, fixup));
+        self.emit(word)
+    }
+
+    fn move_wide(
+        &mut self,
+        opc: u32,
+        instruction: &'static str,
+        rd: Reg,
+        imm: u16,
+        shift: u32,
+    ) -> Result<(), A64Error> {
+        let limit = if rd.wide { 48 } else { 16 };
+        if !shift.is_multiple_of(16) || shift > limit {
+            return Err(A64Error::Immediate {
+                instruction,
+                value: shift as i64,
+            });
+        }
+        let hw = shift / 16;
+        self.emit(rd.sf() | opc << 29 | 0x12800000 | hw << 21 | (imm as u32) << 5 | rd.bits())
+    }
+
+    /// `movz rd, #imm, lsl #shift`: set `rd` to `imm << shift`.
+    pub fn movz(&mut self, rd: Reg, imm: u16, shift: u32) -> Result<(), A64Error> {
+        self.move_wide(0b10, "movz", rd, imm, shift)
+    }
+
+    /// `movn rd, #imm, lsl #shift`: set `rd` to `!(imm << shift)`.
+    pub fn movn(&mut self, rd: Reg, imm: u16, shift: u32) -> Result<(), A64Error> {
+        self.move_wide(0b00, "movn", rd, imm, shift)
+    }
+
+    /// `movk rd, #imm, lsl #shift`: replace one halfword of `rd`.
+    pub fn movk(&mut self, rd: Reg, imm: u16, shift: u32) -> Result<(), A64Error> {
+        self.move_wide(0b11, "movk", rd, imm, shift)
+    }
+
+    /// Load an arbitrary constant into `rd` with a `movz` or `movn` followed
+    /// by a `movk` for each remaining halfword, using whichever start needs
+    /// fewer instructions. Returns the number of instructions.
+    pub fn mov_imm(&mut self, rd: Reg, value: u64) -> Result<usize, A64Error> {
+        let count = if rd.wide { 4 } else { 2 };
+        if !rd.wide && value > u32::MAX as u64 {
+            return Err(A64Error::Immediate {
+                instruction: "mov",
+                value: value as i64,
+            });
+        }
+        let halves: Vec<u16> = (0..count).map(|i| (value >> (16 * i)) as u16).collect();
+        let zeros = halves.iter().filter(|&&h| h == 0).count();
+        let ones = halves.iter().filter(|&&h| h == 0xffff).count();
+        // the halfwords the first instruction leaves correct
+        let (fill, first) = if ones > zeros {
+            let i = halves.iter().position(|&h| h != 0xffff).unwrap_or(0);
+            self.movn(rd, !halves[i], 16 * i as u32)?;
+            (0xffff, i)
+        } else {
+            let i = halves.iter().position(|&h| h != 0).unwrap_or(0);
+            self.movz(rd, halves[i], 16 * i as u32)?;
+            (0, i)
+        };
+        let mut n = 1;
+        for (i, &h) in halves.iter().enumerate() {
+            if i != first && h != fill {
+                self.movk(rd, h, 16 * i as u32)?;
+                n += 1;
+            }
+        }
+        Ok(n)
+    }
+
+    /// `mov rd, rm`, encoded as `orr rd, zr, rm`, or as `add rd, rm, #0` to
+    /// or from [`Reg::SP`], since `orr` reads register 31 as the zero
+    /// register.
+    pub fn mov(&mut self, rd: Reg, rm: Reg) -> Result<(), A64Error> {
+        if rd.sp || rm.sp {
+            return self.add_imm(rd, rm, 0);
+        }
+        self.emit(rd.sf() | 0x2a000000 | rm.bits() << 16 | 31 << 5 | rd.bits())
+    }
+
+    fn add_sub_imm(
+        &mut self,
+        op: u32,
+        instruction: &'static str,
+        rd: Reg,
+        rn: Reg,
+        imm: u32,
+    ) -> Result<(), A64Error> {
+        // a 12-bit immediate, optionally shifted left by 12
+        let (imm12, sh) = if imm >> 12 == 0 {
+            (imm, 0)
+        } else if imm & 0xfff == 0 {
+            (unsigned(instruction, imm as u64 >> 12, 12)?, 1)
+        } else {
+            return Err(A64Error::Immediate {
+                instruction,
+                value: imm as i64,
+            });
+        };
+        self.emit(
+            rd.sf() | op << 29 | 0x11000000 | sh << 22 | imm12 << 10 | rn.bits() << 5 | rd.bits(),
+        )
+    }
+
+    /// `add rd, rn, #imm`, where `imm` is 12 bits, possibly shifted by 12.
+    /// Register 31 is the stack pointer.
+    pub fn add_imm(&mut self, rd: Reg, rn: Reg, imm: u32) -> Result<(), A64Error> {
+        self.add_sub_imm(0b00, "add", rd, rn, imm)
+    }
+
+    /// `sub rd, rn, #imm`, with the same immediates as
+    /// [`add_imm`](Self::add_imm).
+    pub fn sub_imm(&mut self, rd: Reg, rn: Reg, imm: u32) -> Result<(), A64Error> {
+        self.add_sub_imm(0b10, "sub", rd, rn, imm)
+    }
+
+    /// `cmp rn, #imm`, encoded as `subs zr, rn, #imm`.
+    pub fn cmp_imm(&mut self, rn: Reg, imm: u32) -> Result<(), A64Error> {
+        let zr = Reg {
+            n: 31,
+            sp: false,
+            ..rn
+        };
+        self.add_sub_imm(0b11, "cmp", zr, rn, imm)
+    }
+
+    /// `add rd, rn, rm`. Register 31 is the zero register.
+    pub fn add(&mut self, rd: Reg, rn: Reg, rm: Reg) -> Result<(), A64Error> {
+        self.emit(rd.sf() | 0x0b000000 | rm.bits() << 16 | rn.bits() << 5 | rd.bits())
+    }
+
+    /// `sub rd, rn, rm`. Register 31 is the zero register.
+    pub fn sub(&mut self, rd: Reg, rn: Reg, rm: Reg) -> Result<(), A64Error> {
+        self.emit(rd.sf() | 0x4b000000 | rm.bits() << 16 | rn.bits() << 5 | rd.bits())
+    }
+
+    // Loads and stores with an unsigned offset, scaled by the access size.
+    fn load_store(
+        &mut self,
+        base: u32,
+        size: u32,
+        instruction: &'static str,
+        rt: Reg,
+        rn: Reg,
+        offset: u32,
+    ) -> Result<(), A64Error> {
+        if !offset.is_multiple_of(size) {
+            return Err(A64Error::Immediate {
+                instruction,
+                value: offset as i64,
+            });
+        }
+        let imm12 = unsigned(instruction, (offset / size) as u64, 12)?;
+        self.emit(base | imm12 << 10 | rn.bits() << 5 | rt.bits())
+    }
+
+    /// `ldr rt, [rn, #offset]`, loading 8 or 4 bytes depending on `rt`.
+    pub fn ldr(&mut self, rt: Reg, rn: Reg, offset: u32) -> Result<(), A64Error> {
+        let (base, size) = if rt.wide {
+            (0xf9400000, 8)
+        } else {
+            (0xb9400000, 4)
+        };
+        self.load_store(base, size, "ldr", rt, rn, offset)
+    }
+
+    /// `str rt, [rn, #offset]`, storing 8 or 4 bytes depending on `rt`.
+    pub fn str(&mut self, rt: Reg, rn: Reg, offset: u32) -> Result<(), A64Error> {
+        let (base, size) = if rt.wide {
+            (0xf9000000, 8)
+        } else {
+            (0xb9000000, 4)
+        };
+        self.load_store(base, size, "str", rt, rn, offset)
+    }
+
+    /// `ldrb wt, [rn, #offset]`.
+    pub fn ldrb(&mut self, rt: Reg, rn: Reg, offset: u32) -> Result<(), A64Error> {
+        self.load_store(0x39400000, 1, "ldrb", rt, rn, offset)
+    }
+
+    /// `strb wt, [rn, #offset]`.
+    pub fn strb(&mut self, rt: Reg, rn: Reg, offset: u32) -> Result<(), A64Error> {
+        self.load_store(0x39000000, 1, "strb", rt, rn, offset)
+    }
+
+    /// `adr rd, label`: the address of `label`, within 1MiB.
+    pub fn adr(&mut self, rd: Reg, label: A64Label) -> Result<(), A64Error> {
+        self.emit_fixup(0x10000000 | rd.bits(), label, Fixup::Adr)
+    }
+
+    /// `b label`.
+    pub fn b(&mut self, label: A64Label) -> Result<(), A64Error> {
+        self.emit_fixup(0x14000000, label, Fixup::Imm26)
+    }
+
+    /// `bl label`: call `label`, with the return address in [`Reg::LR`].
+    pub fn bl(&mut self, label: A64Label) -> Result<(), A64Error> {
+        self.emit_fixup(0x94000000, label, Fixup::Imm26)
+    }
+
+    /// `b.cond label`.
+    pub fn b_cond(&mut self, cond: Cond, label: A64Label) -> Result<(), A64Error> {
+        self.emit_fixup(0x54000000 | cond as u32, label, Fixup::Imm19)
+    }
+
+    /// `cbz rt, label`: branch if `rt` is zero.
+    pub fn cbz(&mut self, rt: Reg, label: A64Label) -> Result<(), A64Error> {
+        self.emit_fixup(rt.sf() | 0x34000000 | rt.bits(), label, Fixup::Imm19)
+    }
+
+    /// `cbnz rt, label`: branch if `rt` is not zero.
+    pub fn cbnz(&mut self, rt: Reg, label: A64Label) -> Result<(), A64Error> {
+        self.emit_fixup(rt.sf() | 0x35000000 | rt.bits(), label, Fixup::Imm19)
+    }
+
+    /// `ret`, returning to [`Reg::LR`].
+    pub fn ret(&mut self) -> Result<(), A64Error> {
+        self.emit(0xd65f03c0)
+    }
+
+    /// `svc #imm`; Linux ignores the immediate.
+    pub fn svc(&mut self, imm: u16) -> Result<(), A64Error> {
+        self.emit(0xd4000001 | (imm as u32) << 5)
+    }
+
+    /// `nop`.
+    pub fn nop(&mut self) -> Result<(), A64Error> {
+        self.emit(0xd503201f)
+    }
+
+    /// Resolve labels and return the little-endian machine code.
+    ///
+    /// Branches are relative, so the code runs at any (4-byte aligned)
+    /// address.
+    pub fn assemble(&self) -> Result<Vec<u8>, A64Error> {
+        let mut words = self.words.clone();
+        for &(index, label, fixup) in &self.fixups {
+            let target = self.labels[label.0].ok_or(A64Error::UnboundLabel)?;
+            let delta = target as i64 - index as i64;
+            let out_of_range = A64Error::OutOfRange { index };
+            words[index] |= match fixup {
+                Fixup::Imm26 => signed(delta, 26).ok_or(out_of_range)?,
+                Fixup::Imm19 => signed(delta, 19).ok_or(out_of_range)? << 5,
+                Fixup::Adr => {
+                    let imm = signed(delta * 4, 21).ok_or(out_of_range)?;
+                    (imm & 3) << 29 | (imm >> 2) << 5
+                }
+            };
+        }
+        Ok(words.iter().flat_map(|w| w.to_le_bytes()).collect())
+    }
+}
+
+/// Wrap `program` (raw A64 machine code) in a minimal AArch64 ELF executable,
+/// with the same layout as [`elf_bytes`](crate::elf_bytes).
+pub fn elf_aarch64_bytes(program: &[u8]) -> Vec<u8> {
+    elf64_bytes(EM_AARCH64, program)
+}
+
+/// Assemble `a` and wrap the result in a minimal AArch64 ELF executable.
+pub fn elf_from_a64(a: &A64Assembler) -> Result<Vec<u8>, A64Error> {
+    Ok(elf_aarch64_bytes(&a.assemble()?))
+}
+
+fn create_program_aarch64() -> Vec<u8> {
+    let f = || -> Result<_, A64Error> {
+        let mut a = A64Assembler::new();
+        // exit is syscall 93 in the generic syscall table
+        a.movz(Reg::x(8), 93, 0)?;
+        a.movz(Reg::x(0), 0, 0)?;
+        a.svc(0)?;
+        a.assemble()
+    };
+    f().unwrap()
+}
+
+/// Write the minimal AArch64 ELF file, which immediately calls `exit(0)`.
+pub fn write_elf_aarch64<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
+    write_executable(path, &elf_aarch64_bytes(&create_program_aarch64()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create_program_aarch64, elf_aarch64_bytes, elf_from_a64, write_elf_aarch64};
+    use super::{A64Assembler, A64Error, Cond, Reg, EM_AARCH64};
+    use crate::{parse_headers, Class, PROGRAM_VADDR};
+
+    fn words(bytes: &[u8]) -> Vec<u32> {
+        bytes
+            .chunks(4)
+            .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+            .collect()
+    }
+
+    fn bits(word: u32, lo: u32, len: u32) -> u32 {
+        (word >> lo) & ((1 << len) - 1)
+    }
+
+    // a single instruction, checked against llvm-mc's encoding
+    fn encode(f: impl FnOnce(&mut A64Assembler) -> Result<(), A64Error>) -> u32 {
+        let mut a = A64Assembler::new();
+        f(&mut a).unwrap();
+        words(&a.assemble().unwrap())[0]
+    }
+
+    #[test]
+    fn test_exit_stub() {
+        let w = words(&create_program_aarch64());
+        assert_eq!(3, w.len());
+        // movz x8, #93
+        assert_eq!(0b110100101, bits(w[0], 23, 9));
+        assert_eq!(93, bits(w[0], 5, 16));
+        assert_eq!(8, bits(w[0], 0, 5));
+        // movz x0, #0
+        assert_eq!(0b110100101, bits(w[1], 23, 9));
+        assert_eq!(0, bits(w[1], 0, 21));
+        // svc #0
+        assert_eq!(0xd4000001, w[2]);
+    }
+
+    #[test]
+    fn test_header() {
+        let bytes = elf_aarch64_bytes(&create_program_aarch64());
+        assert_eq!(120 + 12, bytes.len());
+        let (header, phdrs) = parse_headers(&bytes).unwrap();
+        assert_eq!(Class::Elf64 as u8, header.class);
+        assert_eq!(EM_AARCH64, header.machine);
+        assert_eq!(2, header._type);
+        assert_eq!(PROGRAM_VADDR, header.entry);
+        // instructions must be 4-byte aligned
+        assert_eq!(0, header.entry % 4);
+        assert_eq!(1, phdrs.len());
+        assert_eq!(12, phdrs[0].filesz);
+        assert!(crate::lint(&bytes).is_empty());
+    }
+
+    #[test]
+    fn test_encodings() {
+        let (x0, x1, x2) = (Reg::x(0), Reg::x(1), Reg::x(2));
+        assert_eq!(0xd2800ba8, encode(|a| a.movz(Reg::x(8), 93, 0)));
+        assert_eq!(0xf2a24681, encode(|a| a.movk(x1, 0x1234, 16)));
+        assert_eq!(0x91002041, encode(|a| a.add_imm(x1, x2, 8)));
+        assert_eq!(0xd10043ff, encode(|a| a.sub_imm(Reg::SP, Reg::SP, 16)));
+        assert_eq!(0xf9400420, encode(|a| a.ldr(x0, x1, 8)));
+        assert_eq!(0xb90007e3, encode(|a| a.str(Reg::w(3), Reg::SP, 4)));
+        assert_eq!(0xaa0103e0, encode(|a| a.mov(x0, x1)));
+        assert_eq!(0x910003fd, encode(|a| a.mov(Reg::x(29), Reg::SP)));
+        assert_eq!(0x9100001f, encode(|a| a.mov(Reg::SP, x0)));
+        assert_eq!(0xaa1f03e0, encode(|a| a.mov(x0, Reg::XZR)));
+        assert_eq!(0xd65f03c0, encode(|a| a.ret()));
+        assert_eq!(0xd503201f, encode(|a| a.nop()));
+    }
+
+    #[test]
+    fn test_branches() {
+        let mut a = A64Assembler::new();
+        let top = a.create_label();
+        let end = a.create_label();
+        let msg = a.create_label();
+        a.set_label(top).unwrap();
+        a.nop().unwrap();
+        a.b_cond(Cond::Ne, top).unwrap(); // b.ne #-4
+        a.cbz(Reg::x(0), end).unwrap(); // cbz x0, #8
+        a.bl(top).unwrap(); // bl #-12
+        a.set_label(end).unwrap();
+        a.adr(Reg::x(1), msg).unwrap(); // adr x1, #16
+        a.nop().unwrap();
+        a.nop().unwrap();
+        a.nop().unwrap();
+        a.set_label(msg).unwrap();
+        let w = words(&a.assemble().unwrap());
+        assert_eq!(0x54ffffe1, w[1]);
+        assert_eq!(0xb4000040, w[2]);
+        assert_eq!(0x97fffffd, w[3]);
+        assert_eq!(0x10000081, w[4]);
+    }
+
+    #[test]
+    fn test_mov_imm() {
+        let mov = |reg: Reg, value: u64| {
+            let mut a = A64Assembler::new();
+            let n = a.mov_imm(reg, value).unwrap();
+            (n, words(&a.assemble().unwrap()))
+        };
+        assert_eq!((1, vec![0xd2800000]), mov(Reg::x(0), 0));
+        // movn x0, #0
+        assert_eq!((1, vec![0x92800000]), mov(Reg::x(0), u64::MAX));
+        // movz x1, #0x1234, lsl #16
+        assert_eq!((1, vec![0xd2a24681]), mov(Reg::x(1), 0x1234_0000));
+        // movz x0, #0x5678; movk x0, #0x1234, lsl #32
+        assert_eq!(
+            (2, vec![0xd28acf00, 0xf2c24680]),
+            mov(Reg::x(0), 0x1234_0000_5678)
+        );
+        // movn x0, #0x1234: mostly ones
+        assert_eq!((1, vec![0x92824680]), mov(Reg::x(0), !0x1234));
+        // movn w0, #0
+        assert_eq!((1, vec![0x12800000]), mov(Reg::w(0), 0xffff_ffff));
+        assert_eq!(4, mov(Reg::x(0), 0x1111_2222_3333_4444).0);
+        assert!(A64Assembler::new().mov_imm(Reg::w(0), 1 << 32).is_err());
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut a = A64Assembler::new();
+        assert!(matches!(
+            a.add_imm(Reg::x(0), Reg::x(0), 0x1001),
+            Err(A64Error::Immediate {
+                instruction: "add",
+                ..
+            })
+        ));
+        // add x0, x0, #1, lsl #12
+        a.add_imm(Reg::x(0), Reg::x(0), 0x1000).unwrap();
+        assert_eq!(0x91400400, a.words()[0]);
+        assert!(a.ldr(Reg::x(0), Reg::x(1), 4).is_err());
+        assert!(a.movz(Reg::w(0), 1, 32).is_err());
+
+        let label = a.create_label();
+        a.b(label).unwrap();
+        assert_eq!(Err(A64Error::UnboundLabel), a.assemble());
+        a.set_label(label).unwrap();
+        assert_eq!(Err(A64Error::LabelRedefined), a.set_label(label));
+        assert!(a.assemble().is_ok());
+    }
+
+    #[test]
+    fn test_llvm_mc() {
+        // there are no section headers for objdump, so disassemble the code
+        // itself with llvm-mc
+        use std::io::Write;
+        let path = crate::test_util::path("tiny_aarch64");
+        write_elf_aarch64(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let hex: Vec<String> = bytes[120..].iter().map(|b| format!("{b:#04x}")).collect();
+        let child = std::process::Command::new("llvm-mc")
+            .args(["-triple=aarch64", "-disassemble"])
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn();
+        let Ok(mut child) = child else {
+            // llvm-mc is not installed
+            return;
+        };
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(hex.join(" ").as_bytes()).unwrap();
+        drop(stdin);
+        let out = child.wait_with_output().unwrap();
+        let out = String::from_utf8(out.stdout).unwrap();
+        let lines: Vec<&str> = out.lines().map(str::trim).skip(1).collect();
+        assert_eq!(vec!["mov\tx8, #93", "mov\tx0, #0", "svc\t#0"], lines);
+    }
+
+    #[test]
+    fn test_hello_words() {
+        // write(1, msg, 3); exit(0) with the message after the code
+        let mut a = A64Assembler::new();
+        let msg = a.create_label();
+        a.mov_imm(Reg::x(0), 1).unwrap();
+        a.adr(Reg::x(1), msg).unwrap();
+        a.mov_imm(Reg::x(2), 3).unwrap();
+        a.mov_imm(Reg::x(8), 64).unwrap();
+        a.svc(0).unwrap();
+        a.mov_imm(Reg::x(0), 0).unwrap();
+        a.mov_imm(Reg::x(8), 93).unwrap();
+        a.svc(0).unwrap();
+        a.set_label(msg).unwrap();
+        let mut program = a.assemble().unwrap();
+        program.extend_from_slice(b"hi\n");
+        let bytes = elf_aarch64_bytes(&program);
+        let w = words(&bytes[120..120 + 32]);
+        // adr x1 points 7 instructions ahead, where the message starts
+        assert_eq!(28, bits(w[1], 5, 19) << 2 | bits(w[1], 29, 2));
+        assert_eq!(b"hi\n", &bytes[120 + 4 + 28..]);
+        assert_eq!(
+            elf_from_a64(&a).unwrap()[120..],
+            bytes[120..bytes.len() - 3]
+        );
+    }
+}
@@ -0,0 +1,2695 @@
+//! Build ELF executables with any number of loadable segments.
+//!
+//! All code and data goes through a single [`CodeAssembler`], so labels can
+//! refer to instructions and data in any segment. Each segment is encoded as
+//! its own [`InstructionBlock`] at the address the layout assigns it.
+
+use crate::dynamic::DynamicTables;
+use crate::sections::{SectionTable, SymbolTable};
+use crate::sha1::sha1;
+use crate::{
+    align_up, elf64_hdr, elf64_phdr, lint, note_bytes, write_executable, Diagnostic, Format,
+    FormatError, Header, KnownRegisters, Phdr, Rela, Shdr, Sym, Syscalls, EM_X86_64,
+    NT_GNU_BUILD_ID, PF_R, PF_W, PF_X, PT_DYNAMIC, PT_GNU_STACK, PT_INTERP, PT_LOAD, PT_NOTE,
+    PT_PHDR, PT_TLS, R_X86_64_RELATIVE, SHF_ALLOC, SHF_EXECINSTR, SHF_TLS, SHF_WRITE, SHN_ABS,
+    SHT_NOBITS, SHT_NOTE, SHT_PROGBITS, STB_GLOBAL, STB_LOCAL, STT_FUNC, STT_NOTYPE, STT_OBJECT,
+    VADDR,
+};
+use binary_layout::Field;
+use iced_x86::code_asm::{CodeAssembler, CodeLabel};
+use iced_x86::{
+    BlockEncoder, BlockEncoderOptions, Code, Encoder, IcedError, Instruction, InstructionBlock,
+    OpKind, Register,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::ops::Range;
+use std::path::Path;
+
+/// Layout options for one `PT_LOAD` segment.
+///
+/// By default a segment is page-aligned and placed directly after the
+/// previous segment in the file, on a fresh page in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    flags: u32,
+    align: u64,
+    offset: Option<u64>,
+    vaddr: Option<u64>,
+}
+
+impl Segment {
+    /// A segment with permissions `flags`, a combination of
+    /// [`PF_R`](crate::PF_R), [`PF_W`](crate::PF_W) and [`PF_X`](crate::PF_X).
+    pub fn new(flags: u32) -> Self {
+        Segment {
+            flags,
+            align: 4096,
+            offset: None,
+            vaddr: None,
+        }
+    }
+
+    /// Set the alignment, which must be a power of two.
+    pub fn align(mut self, align: u64) -> Self {
+        self.align = align;
+        self
+    }
+
+    /// Place the segment at a fixed offset in the file.
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    /// Load the segment at a fixed virtual address.
+    pub fn vaddr(mut self, vaddr: u64) -> Self {
+        self.vaddr = Some(vaddr);
+        self
+    }
+}
+
+#[derive(Debug)]
+pub enum BuildError {
+    /// The code could not be assembled.
+    Asm(IcedError),
+    /// Two labels were given the same name.
+    DuplicateSymbol(String),
+    /// An address was requested for a symbol that was never placed.
+    UndefinedSymbol(String),
+    /// The segments could not be laid out as requested.
+    Layout(String),
+    /// Position-independent code contains an absolute address, which
+    /// would need a relocation (see [`ElfBuilder::pie`]).
+    Absolute(String),
+    /// The linked file failed [`lint`](crate::lint) (see
+    /// [`ElfBuilder::lint`]).
+    Lint(Vec<Diagnostic>),
+    /// The headers could not be written in the chosen
+    /// [format](ElfBuilder::format).
+    Format(FormatError),
+    /// A symbol was imported from the same library both as a function and
+    /// as data.
+    ImportKind(String),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::Asm(e) => write!(f, "assembly failed: {e}"),
+            BuildError::DuplicateSymbol(name) => write!(f, "duplicate symbol {name}"),
+            BuildError::UndefinedSymbol(name) => write!(f, "undefined symbol {name}"),
+            BuildError::Layout(msg) => write!(f, "bad layout: {msg}"),
+            BuildError::Absolute(what) => write!(f, "absolute address in PIE: {what}"),
+            BuildError::Lint(diagnostics) => {
+                write!(f, "invalid ELF file:")?;
+                for d in diagnostics {
+                    write!(f, "\n  {d}")?;
+                }
+                Ok(())
+            }
+            BuildError::Format(e) => write!(f, "bad format: {e}"),
+            BuildError::ImportKind(name) => {
+                write!(f, "{name} imported as both a function and data")
+            }
+            BuildError::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+impl From<IcedError> for BuildError {
+    fn from(e: IcedError) -> Self {
+        BuildError::Asm(e)
+    }
+}
+
+impl From<FormatError> for BuildError {
+    fn from(e: FormatError) -> Self {
+        BuildError::Format(e)
+    }
+}
+
+impl From<std::io::Error> for BuildError {
+    fn from(e: std::io::Error) -> Self {
+        BuildError::Io(e)
+    }
+}
+
+/// A laid-out ELF file.
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub bytes: Vec<u8>,
+    pub entry: u64,
+    /// Final addresses of the symbols placed with [`ElfBuilder::label`],
+    /// [`ElfBuilder::rodata`] and [`ElfBuilder::bss`].
+    pub symbols: BTreeMap<String, u64>,
+    /// Addresses of the `R_X86_64_RELATIVE` relocations in a PIE: the
+    /// 8-byte absolute addresses that must have the load base added.
+    pub relocations: Vec<u64>,
+}
+
+/// Where the startup stub added by [`ElfBuilder::relocate`] finds the
+/// address the kernel loaded the program at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadBase {
+    /// Subtract the link-time address of the stub from its RIP-relative
+    /// address.
+    Rip,
+    /// Subtract the offset of the program headers from the `AT_PHDR` entry
+    /// of the auxiliary vector, found past `argv` and `envp` on the stack.
+    Auxv,
+}
+
+/// Builder for an x86-64 executable made of several `PT_LOAD` segments.
+///
+/// Instructions added with [`ElfBuilder::asm`] go into the segment most
+/// recently started with [`ElfBuilder::segment`] (or the first one, if no
+/// segment has been started yet). Without any segments the builder produces
+/// the same single read-write-execute segment as [`elf_bytes`](crate::elf_bytes).
+///
+/// The entry point is the label `_start` if there is one, and otherwise the
+/// start of the first executable segment.
+pub struct ElfBuilder {
+    asm: CodeAssembler,
+    base: u64,
+    // each segment starts at an instruction index
+    segments: Vec<(Segment, usize)>,
+    // labels created for symbol names, whether or not they are placed yet
+    labels: HashMap<String, CodeLabel>,
+    // placed labels, by the index of the instruction they point to, and the
+    // size of the data they label
+    symbols: Vec<(String, usize, u64)>,
+    // bss reservations, by the index of their label's instruction
+    bss: Vec<(usize, u64)>,
+    // instructions holding read-only data, which go at the end of their segment
+    rodata: Vec<Range<usize>>,
+    fixups: Vec<Fixup>,
+    section_headers: bool,
+    symbol_table: bool,
+    // e_machine and e_flags
+    machine: (u16, u32),
+    format: Format,
+    pie: bool,
+    stub: Option<LoadBase>,
+    shared: bool,
+    // symbols in the dynamic symbol table
+    exports: Vec<String>,
+    imports: Vec<Import>,
+    interpreter: String,
+    // (name, type, descriptor) of each note other than the build ID
+    notes: Vec<(String, u32, Vec<u8>)>,
+    build_id: bool,
+    // (initialization image, zero-fill size, alignment) of the TLS block
+    tls: Option<(Vec<u8>, u64, u64)>,
+    tls_stub: bool,
+    lint: bool,
+}
+
+// A symbol from a shared library, whose address the dynamic linker puts in
+// a GOT entry.
+struct Import {
+    library: String,
+    name: String,
+    // the label of the GOT entry, and the index of its empty instruction
+    label: CodeLabel,
+    index: usize,
+    // functions are bound with jump slots, and data with GLOB_DAT
+    function: bool,
+}
+
+// An absolute address to patch into the output once the layout is known.
+struct Fixup {
+    // instruction containing the address, and where in its encoding
+    index: usize,
+    offset: usize,
+    size: usize,
+    symbol: String,
+    // whether the address moves with the load base, or is a link-time
+    // constant
+    relocate: bool,
+}
+
+// The offset of the program headers, which the auxv stubs rely on.
+const PHOFF: u64 = elf64_hdr::SIZE.unwrap() as u64;
+
+// The offset of e_phnum and the size of a program header, which the stubs use
+// to walk the program headers.
+const PHNUM: i32 = elf64_hdr::phnum::OFFSET as i32;
+const PHENTSIZE: i32 = elf64_phdr::SIZE.unwrap() as i32;
+
+// The auxiliary vector entry holding the address of the program headers.
+const AT_PHDR: i32 = 3;
+
+// Load the value of the auxiliary vector entry `_type` into `rax`, with the
+// stack as the kernel left it; uses `rsi`.
+fn load_auxv(a: &mut CodeAssembler, _type: i32) -> Result<(), IcedError> {
+    use iced_x86::code_asm::*;
+    // skip argc, argv and envp
+    a.mov(rax, qword_ptr(rsp))?;
+    a.lea(rsi, qword_ptr(rsp + rax * 8 + 16))?;
+    let mut env = a.create_label();
+    a.set_label(&mut env)?;
+    a.lodsq()?;
+    a.test(rax, rax)?;
+    a.jnz(env)?;
+    // lodsq leaves the flags from cmp alone
+    let mut aux = a.create_label();
+    a.set_label(&mut aux)?;
+    a.lodsq()?;
+    a.cmp(eax, _type)?;
+    a.lodsq()?;
+    a.jne(aux)
+}
+
+/// Alignment of each reservation made with [`ElfBuilder::bss`].
+pub const BSS_ALIGN: u64 = 16;
+
+/// The default dynamic linker for programs with imports (see
+/// [`ElfBuilder::interpreter`]).
+pub const INTERPRETER: &str = "/lib64/ld-linux-x86-64.so.2";
+
+// The instructions of one segment, split into those in the file and the bss
+// labels.
+struct Part {
+    segment: Segment,
+    code: Vec<usize>,
+    // read-only data is at the end of the code, starting at this position
+    rodata_start: usize,
+    bss: Vec<(usize, u64)>,
+}
+
+// The final layout of all segments.
+struct Encoded {
+    phdrs: Vec<Phdr>,
+    // contents of each segment in the file
+    contents: Vec<Vec<u8>>,
+    // address of every instruction
+    addrs: Vec<u64>,
+}
+
+impl Part {
+    // Addresses of the bss reservations if the file contents end at `start`,
+    // and the end of the bss.
+    fn bss_addrs(&self, start: u64) -> (Vec<u64>, u64) {
+        let mut end = start;
+        let mut addrs = vec![];
+        for &(_, size) in &self.bss {
+            let addr = align_up(end, BSS_ALIGN);
+            addrs.push(addr);
+            end = addr + size;
+        }
+        (addrs, end)
+    }
+}
+
+// Describe the code, read-only data and bss of a segment with sections.
+fn add_sections(sections: &mut SectionTable, part: &Part, phdr: &Phdr, addrs: &[u64]) {
+    let file_end = phdr.vaddr + phdr.filesz;
+    let rodata = part
+        .code
+        .get(part.rodata_start)
+        .map_or(file_end, |&index| addrs[index]);
+    let mut flags = SHF_ALLOC;
+    if phdr.flags & PF_W != 0 {
+        flags |= SHF_WRITE;
+    }
+    let section = |_type, flags, addr, end| Shdr {
+        _type,
+        flags,
+        addr,
+        offset: phdr.offset + (addr - phdr.vaddr),
+        size: end - addr,
+        addralign: 1,
+        ..Shdr::default()
+    };
+    if rodata > phdr.vaddr {
+        let (name, flags) = if phdr.flags & PF_X != 0 {
+            (".text", flags | SHF_EXECINSTR)
+        } else {
+            (".data", flags)
+        };
+        sections.add(name, section(SHT_PROGBITS, flags, phdr.vaddr, rodata));
+    }
+    if file_end > rodata {
+        sections.add(".rodata", section(SHT_PROGBITS, flags, rodata, file_end));
+    }
+    if phdr.memsz > phdr.filesz {
+        let bss = Shdr {
+            addralign: BSS_ALIGN,
+            ..section(SHT_NOBITS, flags, file_end, phdr.vaddr + phdr.memsz)
+        };
+        sections.add(".bss", bss);
+    }
+}
+
+// The symbol table entry for a label at `addr`.
+fn symbol(sections: &SectionTable, addr: u64, size: u64, global: bool) -> Sym {
+    let (shndx, _type) = match sections.containing(addr) {
+        Some((i, shdr)) if shdr.flags & SHF_EXECINSTR != 0 => (i, STT_FUNC),
+        Some((i, _)) => (i, STT_OBJECT),
+        None => (SHN_ABS, STT_NOTYPE),
+    };
+    Sym {
+        bind: if global { STB_GLOBAL } else { STB_LOCAL },
+        _type,
+        shndx,
+        value: addr,
+        size,
+        ..Sym::default()
+    }
+}
+
+impl Default for ElfBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// encoding can change the size of branches and therefore the layout; this
+// many rounds is plenty for the layout to stop changing
+const MAX_PASSES: usize = 16;
+
+fn overlaps(start1: u64, len1: u64, start2: u64, len2: u64) -> bool {
+    len1 > 0 && len2 > 0 && start1 < start2 + len2 && start2 < start1 + len1
+}
+
+impl ElfBuilder {
+    pub fn new() -> Self {
+        ElfBuilder {
+            asm: CodeAssembler::new(64).unwrap(),
+            base: VADDR,
+            segments: vec![],
+            labels: HashMap::new(),
+            symbols: vec![],
+            bss: vec![],
+            rodata: vec![],
+            fixups: vec![],
+            section_headers: false,
+            symbol_table: false,
+            machine: (EM_X86_64, 0),
+            format: Format::ELF64_LSB,
+            pie: false,
+            stub: None,
+            shared: false,
+            exports: vec![],
+            imports: vec![],
+            interpreter: INTERPRETER.to_string(),
+            notes: vec![],
+            build_id: false,
+            tls: None,
+            tls_stub: false,
+            lint: cfg!(debug_assertions),
+        }
+    }
+
+    /// Set the lowest address used for segments without a fixed address
+    /// (default [`VADDR`]).
+    pub fn base(&mut self, base: u64) -> &mut Self {
+        self.base = base;
+        self
+    }
+
+    /// Add section headers describing the segments (default off).
+    ///
+    /// The loader doesn't need them, but tools like objdump and gdb do. Each
+    /// segment gets a `.text` (or `.data`, if it is not executable) section for
+    /// its code, a `.rodata` section for data added with
+    /// [`ElfBuilder::rodata`] and a `.bss` section for its bss.
+    pub fn section_headers(&mut self, enable: bool) -> &mut Self {
+        self.section_headers = enable;
+        self
+    }
+
+    /// Check the linked file with [`lint`](crate::lint), failing with
+    /// [`BuildError::Lint`] if it finds problems (default on in debug builds
+    /// only).
+    pub fn lint(&mut self, enable: bool) -> &mut Self {
+        self.lint = enable;
+        self
+    }
+
+    /// Add a symbol table with the symbols placed with [`ElfBuilder::label`],
+    /// [`ElfBuilder::rodata`] and [`ElfBuilder::bss`] (default off).
+    ///
+    /// This implies [`ElfBuilder::section_headers`]. `_start` is a global
+    /// symbol and the rest are local, as if they came from an assembler.
+    pub fn symbol_table(&mut self, enable: bool) -> &mut Self {
+        self.symbol_table = enable;
+        self
+    }
+
+    /// Build a position-independent executable (default off).
+    ///
+    /// The file is `ET_DYN`, so the kernel loads it at a random base address,
+    /// and segment addresses, symbols and the entry point are offsets from
+    /// that base, starting at 0 rather than [`ElfBuilder::base`]. Code should
+    /// use RIP-relative operands such as `ptr(label)`: linking fails with
+    /// [`BuildError::Absolute`] for a memory operand with an absolute address
+    /// or a 32-bit [`ElfBuilder::mov_addr`]. 64-bit addresses in writable
+    /// segments are recorded as relocations, for [`ElfBuilder::relocate`] to
+    /// apply at startup; without the stub (or a dynamic linker) they are an
+    /// error too.
+    pub fn pie(&mut self, enable: bool) -> &mut Self {
+        self.pie = enable;
+        self
+    }
+
+    /// Build a shared library that `dlopen` can load (default off).
+    ///
+    /// This implies [`ElfBuilder::pie`]. The library has a `PT_DYNAMIC`
+    /// segment describing the symbols named with [`ElfBuilder::export`], and
+    /// 64-bit addresses in writable segments become `R_X86_64_RELATIVE`
+    /// relocations for the dynamic linker to apply, so no
+    /// [`ElfBuilder::relocate`] stub is needed.
+    pub fn shared(&mut self, enable: bool) -> &mut Self {
+        self.shared = enable;
+        self
+    }
+
+    /// Add the symbol `name` to the dynamic symbol table, so that `dlsym`
+    /// can find it.
+    ///
+    /// Exporting a symbol gives any file a `PT_DYNAMIC` segment, not just a
+    /// [shared library](ElfBuilder::shared). The tables are loaded with the
+    /// ELF headers. Exported symbols refer to sections, so this turns on
+    /// [`ElfBuilder::section_headers`], which also describe the tables.
+    pub fn export(&mut self, name: &str) -> &mut Self {
+        if !self.exports.iter().any(|e| e == name) {
+            self.exports.push(name.to_string());
+        }
+        self
+    }
+
+    /// Import the function `name` from the shared library `library` (such
+    /// as `libc.so.6`), returning the label of its GOT entry.
+    ///
+    /// Call the function through the entry with `call(qword_ptr(label))`.
+    /// Importing makes the file dynamically linked: the kernel starts the
+    /// [dynamic linker](ElfBuilder::interpreter), which loads `library` (a
+    /// `DT_NEEDED` entry) and fills in the entry (an `R_X86_64_JUMP_SLOT`
+    /// relocation) before jumping to the entry point. The GOT goes after the
+    /// dynamic tables, in the segment holding the ELF headers, which becomes
+    /// writable.
+    ///
+    /// The dynamic linker doesn't initialize the C library's stdio as
+    /// `__libc_start_main` would, but buffered output is still flushed by
+    /// calling `exit`.
+    ///
+    /// Importing the same function from the same library again returns the
+    /// same label; importing it as data is an error.
+    pub fn import(&mut self, library: &str, name: &str) -> Result<CodeLabel, BuildError> {
+        self.add_import(library, name, true)
+    }
+
+    /// Import the data symbol `name` from the shared library `library`,
+    /// returning the label of a GOT entry holding its address (an
+    /// `R_X86_64_GLOB_DAT` relocation).
+    ///
+    /// See [`ElfBuilder::import`].
+    pub fn import_data(&mut self, library: &str, name: &str) -> Result<CodeLabel, BuildError> {
+        self.add_import(library, name, false)
+    }
+
+    fn add_import(
+        &mut self,
+        library: &str,
+        name: &str,
+        function: bool,
+    ) -> Result<CodeLabel, BuildError> {
+        let existing = (self.imports.iter()).find(|i| i.library == library && i.name == name);
+        if let Some(import) = existing {
+            if import.function != function {
+                return Err(BuildError::ImportKind(name.to_string()));
+            }
+            return Ok(import.label);
+        }
+        let mut label = self.asm.create_label();
+        self.asm.set_label(&mut label)?;
+        // the empty instruction carrying the label moves to the GOT at link
+        // time
+        self.imports.push(Import {
+            library: library.to_string(),
+            name: name.to_string(),
+            label,
+            index: self.asm.instructions().len(),
+            function,
+        });
+        self.asm.zero_bytes()?;
+        Ok(label)
+    }
+
+    /// Set the dynamic linker that runs a program with imports (default
+    /// [`INTERPRETER`]).
+    pub fn interpreter(&mut self, path: &str) -> &mut Self {
+        self.interpreter = path.to_string();
+        self
+    }
+
+    /// Add a note with the vendor `name` (such as `"GNU"`), a `_type` whose
+    /// meaning depends on the vendor, and the descriptor `desc`.
+    ///
+    /// Notes are loaded with the ELF headers, and described by a `PT_NOTE`
+    /// segment (and a `.note` section, with
+    /// [`ElfBuilder::section_headers`]).
+    pub fn note(&mut self, name: &str, _type: u32, desc: &[u8]) -> &mut Self {
+        self.notes.push((name.to_string(), _type, desc.to_vec()));
+        self
+    }
+
+    /// Add an `NT_GNU_BUILD_ID` note (default off).
+    ///
+    /// The ID is the SHA-1 of the whole file with the ID itself zeroed, so it
+    /// only depends on the contents, like `ld --build-id=sha1`. It is the
+    /// last note, in a `.note.gnu.build-id` section of its own.
+    pub fn build_id(&mut self, enable: bool) -> &mut Self {
+        self.build_id = enable;
+        self
+    }
+
+    /// Set the `e_machine` and `e_flags` header fields (default
+    /// [`EM_X86_64`] with no flags).
+    ///
+    /// The assembler only encodes x86-64, but code for another architecture
+    /// can be added as bytes with `db`; the layout doesn't depend on the
+    /// instruction set.
+    pub fn machine(&mut self, machine: u16, flags: u32) -> &mut Self {
+        self.machine = (machine, flags);
+        self
+    }
+
+    /// Set the class and byte order of the ELF header and program headers
+    /// (default [`Format::ELF64_LSB`]).
+    ///
+    /// Like [`ElfBuilder::machine`], this is for code added as bytes for
+    /// another architecture: the code and data are written as given. Section
+    /// headers, symbols, imports and exports, notes and the startup stubs are
+    /// only written as ELF-64 little-endian, so with another format they fail
+    /// with [`FormatError::Elf64Only`]. [`lint`](crate::lint) only checks
+    /// ELF-64 little-endian files, so other output isn't linted.
+    pub fn format(&mut self, format: Format) -> &mut Self {
+        self.format = format;
+        self
+    }
+
+    /// Start a new segment; subsequent instructions are placed in it.
+    pub fn segment(&mut self, segment: Segment) -> &mut Self {
+        let start = self.asm.instructions().len();
+        self.segments.push((segment, start));
+        self
+    }
+
+    /// The assembler for adding code and data to the current segment.
+    pub fn asm(&mut self) -> &mut CodeAssembler {
+        &mut self.asm
+    }
+
+    /// The label for the symbol `name`, which can be referenced before it is
+    /// placed with [`ElfBuilder::label`], [`ElfBuilder::rodata`] or
+    /// [`ElfBuilder::bss`].
+    pub fn symbol(&mut self, name: &str) -> CodeLabel {
+        if let Some(&label) = self.labels.get(name) {
+            return label;
+        }
+        let label = self.asm.create_label();
+        self.labels.insert(name.to_string(), label);
+        label
+    }
+
+    /// Place the label named `name` at the current position.
+    ///
+    /// The label is attached to an empty instruction, so the next instruction
+    /// can still get its own label.
+    pub fn label(&mut self, name: &str) -> Result<CodeLabel, IcedError> {
+        let mut label = self.symbol(name);
+        if self.symbols.iter().any(|(placed, _, _)| placed == name) {
+            // reported as a duplicate when linking
+            label = self.asm.create_label();
+        }
+        self.asm.set_label(&mut label)?;
+        self.symbols
+            .push((name.to_string(), self.asm.instructions().len(), 0));
+        self.asm.zero_bytes()?;
+        Ok(label)
+    }
+
+    /// Add read-only data labelled `name` to the current segment.
+    ///
+    /// The data is placed after all of the segment's code, wherever in the
+    /// segment it is added, so that it never runs into the entry point. Code
+    /// can load it through the returned label (which is RIP-relative) or
+    /// through its absolute address with [`ElfBuilder::mov_addr`].
+    pub fn rodata(&mut self, name: &str, bytes: &[u8]) -> Result<CodeLabel, IcedError> {
+        let start = self.asm.instructions().len();
+        let label = self.label(name)?;
+        self.symbols.last_mut().unwrap().2 = bytes.len() as u64;
+        self.asm.db(bytes)?;
+        self.rodata.push(start..self.asm.instructions().len());
+        Ok(label)
+    }
+
+    /// Load the absolute address of the symbol `name` into `reg`.
+    ///
+    /// A 32-bit register gets a 5-byte `mov r32, imm32` (which zero-extends
+    /// and so needs the symbol to be below 4 GiB), and a 64-bit register a
+    /// 10-byte `mov r64, imm64`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `reg` is not a 32- or 64-bit general-purpose register.
+    pub fn mov_addr<R: Into<Register>>(&mut self, reg: R, name: &str) -> Result<(), IcedError> {
+        let reg = reg.into();
+        let instr = if reg.is_gpr32() {
+            Instruction::with2(Code::Mov_r32_imm32, reg, 0u32)?
+        } else {
+            assert!(reg.is_gpr64(), "{reg:?} cannot hold an address");
+            Instruction::with2(Code::Mov_r64_imm64, reg, 0u64)?
+        };
+        let size = if reg.is_gpr32() { 4 } else { 8 };
+        // the immediate is at the end of the instruction
+        let len = Encoder::new(64).encode(&instr, 0)?;
+        self.fixups.push(Fixup {
+            index: self.asm.instructions().len(),
+            offset: len - size,
+            size,
+            symbol: name.to_string(),
+            relocate: true,
+        });
+        self.asm.add_instruction(instr)
+    }
+
+    /// Add the 8-byte absolute address of the symbol `name` as data, as in a
+    /// table of pointers.
+    ///
+    /// In a [PIE](ElfBuilder::pie) this records an `R_X86_64_RELATIVE`
+    /// relocation, which must be in a writable segment so that the stub
+    /// added with [`ElfBuilder::relocate`] can apply it.
+    pub fn pointer(&mut self, name: &str) -> Result<(), IcedError> {
+        self.fixups.push(Fixup {
+            index: self.asm.instructions().len(),
+            offset: 0,
+            size: 8,
+            symbol: name.to_string(),
+            relocate: true,
+        });
+        self.asm.dq(&[0])
+    }
+
+    /// Add a startup stub, labelled `_start`, that applies the relocations
+    /// of a PIE and then falls through to the code that follows.
+    ///
+    /// The relocations are stored after the program headers as a table of
+    /// 4-byte addresses ending in 0, in a read-only segment that also maps
+    /// the ELF headers. The stub adds the load base (found as `from` says)
+    /// to each one, and leaves the base in `rbx`; it also uses `rax`, `rcx`
+    /// and `rsi`.
+    pub fn relocate(&mut self, from: LoadBase) -> Result<(), IcedError> {
+        use iced_x86::code_asm::*;
+        self.stub = Some(from);
+        let start = self.label("_start")?;
+        let a = &mut self.asm;
+        match from {
+            LoadBase::Rip => {
+                a.lea(rbx, ptr(start))?;
+                // the link-time address of _start, patched in when linking
+                let instr = Instruction::with2(Code::Sub_rm64_imm32, Register::RBX, 0)?;
+                self.fixups.push(Fixup {
+                    index: a.instructions().len(),
+                    offset: 3,
+                    size: 4,
+                    symbol: "_start".to_string(),
+                    relocate: false,
+                });
+                a.add_instruction(instr)?;
+            }
+            LoadBase::Auxv => {
+                load_auxv(a, AT_PHDR)?;
+                a.lea(rbx, qword_ptr(rax - PHOFF as i32))?;
+            }
+        }
+        // the table follows the program headers
+        a.movzx(ecx, word_ptr(rbx + PHNUM))?;
+        a.imul_3(ecx, ecx, PHENTSIZE)?;
+        a.lea(rsi, qword_ptr(rbx + rcx + PHOFF as i32))?;
+        let mut next = a.create_label();
+        let mut done = a.create_label();
+        a.set_label(&mut next)?;
+        a.lodsd()?;
+        a.test(eax, eax)?;
+        a.jz(done)?;
+        a.add(qword_ptr(rbx + rax), rbx)?;
+        a.jmp(next)?;
+        a.set_label(&mut done)?;
+        a.zero_bytes()
+    }
+
+    /// Give each thread a block of thread-local storage holding `init`
+    /// followed by `zero_size` zero bytes, aligned to `align` (a power of
+    /// two, at most 4096).
+    ///
+    /// The initialization image is loaded with the ELF headers and
+    /// described by a `PT_TLS` segment. Code reaches the block through `fs`,
+    /// at the displacements given by [`ElfBuilder::tls_offset`]. Without a
+    /// C library to set `fs` up, start the program with
+    /// [`ElfBuilder::setup_tls`].
+    pub fn tls(&mut self, init: &[u8], zero_size: u64, align: u64) -> &mut Self {
+        self.tls = Some((init.to_vec(), zero_size, align));
+        self
+    }
+
+    /// The displacement from the thread pointer of byte `offset` of the TLS
+    /// block, for operands like `dword_ptr(b.tls_offset(8)).fs()`.
+    ///
+    /// On x86-64 the block ends at the thread pointer, rounded up to its
+    /// alignment, so the displacements are negative.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no TLS block (see [`ElfBuilder::tls`]).
+    pub fn tls_offset(&self, offset: u64) -> i32 {
+        let (init, zero_size, align) = self.tls.as_ref().expect("no TLS block");
+        let size = align_up(init.len() as u64 + zero_size, *align);
+        (offset as i64 - size as i64) as i32
+    }
+
+    /// Add a startup snippet that gives the main thread its TLS block, for
+    /// programs without a C library.
+    ///
+    /// The snippet maps the block with `mmap`, copies in the initialization
+    /// image, stores the thread pointer at `fs:0` as the ABI requires, and
+    /// sets `fs` with `arch_prctl(ARCH_SET_FS)`. It finds the `PT_TLS`
+    /// segment through the `AT_PHDR` entry of the auxiliary vector, so it
+    /// must run with the stack as the kernel left it: first thing, or
+    /// straight after the [`ElfBuilder::relocate`] stub. It uses `rax`,
+    /// `rcx`, `rdx`, `rsi`, `rdi` and `r8` to `r11`.
+    ///
+    /// A dynamic linker sets up TLS itself, so the snippet is an error in
+    /// dynamically linked output.
+    pub fn setup_tls(&mut self) -> Result<(), IcedError> {
+        use iced_x86::code_asm::*;
+        self.tls_stub = true;
+        let a = &mut self.asm;
+        load_auxv(a, AT_PHDR)?;
+        // the image is at its file offset from the headers
+        a.lea(r8, qword_ptr(rax - PHOFF as i32))?;
+        let mut find = a.create_label();
+        a.set_label(&mut find)?;
+        a.cmp(dword_ptr(rax), PT_TLS as i32)?;
+        a.lea(rax, qword_ptr(rax + PHENTSIZE))?;
+        a.jne(find)?;
+        // rax is the end of the PT_TLS header; round p_memsz up to p_align
+        let field = |offset: usize| qword_ptr(rax + (offset as i32 - PHENTSIZE));
+        a.add(r8, field(elf64_phdr::offset::OFFSET))?;
+        a.mov(rcx, field(elf64_phdr::align::OFFSET))?;
+        a.mov(rsi, field(elf64_phdr::memsz::OFFSET))?;
+        a.lea(rsi, qword_ptr(rsi + rcx - 1))?;
+        a.neg(rcx)?;
+        a.and(rsi, rcx)?;
+        a.push(r8)?;
+        a.push(field(elf64_phdr::filesz::OFFSET))?;
+        a.push(rsi)?;
+        // mmap(0, size + 8, PROT_READ | PROT_WRITE,
+        //      MAP_PRIVATE | MAP_ANONYMOUS, -1, 0)
+        a.add(rsi, 8)?;
+        a.mmap(0, rsi, 3, 0x22, -1, 0, &mut KnownRegisters::new())?;
+        // the thread pointer follows the block, and points to itself
+        a.pop(rdx)?;
+        a.add(rdx, rax)?;
+        a.mov(qword_ptr(rdx), rdx)?;
+        // copy p_filesz bytes of the image
+        a.pop(rcx)?;
+        a.pop(rsi)?;
+        a.mov(rdi, rax)?;
+        a.rep().movsb()?;
+        // arch_prctl(ARCH_SET_FS, thread pointer)
+        a.arch_prctl(0x1002, rdx, &mut KnownRegisters::new())
+    }
+
+    /// Reserve `size` bytes of zero-initialized memory at the end of the
+    /// current segment, returning a label for its address.
+    ///
+    /// The memory takes no space in the file: the segment's `p_memsz` is
+    /// larger than its `p_filesz`. Each reservation is aligned to
+    /// [`BSS_ALIGN`] bytes. The segment must be writable.
+    pub fn bss(&mut self, name: &str, size: u64) -> Result<CodeLabel, IcedError> {
+        let label = self.label(name)?;
+        self.symbols.last_mut().unwrap().2 = size;
+        // the empty instruction carrying the label moves to the bss at link time
+        self.bss.push((self.asm.instructions().len() - 1, size));
+        Ok(label)
+    }
+
+    fn position_independent(&self) -> bool {
+        self.pie || self.shared
+    }
+
+    // The number of relocations in a PIE.
+    fn relocation_count(&self) -> usize {
+        if !self.position_independent() {
+            return 0;
+        }
+        self.fixups.iter().filter(|f| f.relocate).count()
+    }
+
+    // The number of relocations if this is a PIE with a relocation table,
+    // which goes after the program headers in a segment of its own.
+    fn relocation_table(&self) -> Option<usize> {
+        let count = self.relocation_count();
+        let table = self.stub.is_some() || count > 0;
+        (self.pie && !self.is_dynamic() && table).then_some(count)
+    }
+
+    fn is_dynamic(&self) -> bool {
+        self.shared || !self.exports.is_empty() || !self.imports.is_empty()
+    }
+
+    // Whether the kernel should start the dynamic linker, rather than
+    // jumping straight to the entry point.
+    fn has_interpreter(&self) -> bool {
+        !self.imports.is_empty() && !self.shared
+    }
+
+    // The dynamic linking tables, which go after the program headers (and
+    // relocation table).
+    fn dynamic(&self) -> Option<DynamicTables> {
+        if !self.is_dynamic() {
+            return None;
+        }
+        let exports = self.exports.iter().map(String::as_str);
+        let mut tables = DynamicTables::new(exports, self.relocation_count());
+        for (i, import) in self.imports.iter().enumerate() {
+            if !self.imports[..i]
+                .iter()
+                .any(|i| i.library == import.library)
+            {
+                tables.need(&import.library);
+            }
+        }
+        for import in &self.imports {
+            tables.import(&import.name, import.function);
+        }
+        if self.has_interpreter() {
+            tables.interpreter(&self.interpreter);
+        }
+        Some(tables)
+    }
+
+    // The address of the GOT entry of each import.
+    fn got_addrs(&self, parts: usize) -> Vec<u64> {
+        let Some(tables) = self.dynamic() else {
+            return vec![];
+        };
+        let got = self.headers_vaddr() + self.dynamic_offset(parts) as u64 + tables.got();
+        (0..self.imports.len())
+            .map(|i| got + 8 * i as u64)
+            .collect()
+    }
+
+    // Each note, encoded, with the build ID last and still zero.
+    fn encoded_notes(&self) -> Vec<Vec<u8>> {
+        let build_id = self
+            .build_id
+            .then(|| note_bytes("GNU", NT_GNU_BUILD_ID, &[0; 20]));
+        (self.notes.iter())
+            .map(|(name, _type, desc)| note_bytes(name, *_type, desc))
+            .chain(build_id)
+            .collect()
+    }
+
+    fn has_notes(&self) -> bool {
+        !self.notes.is_empty() || self.build_id
+    }
+
+    // The first part of the output, if any, that is only written as ELF-64
+    // little-endian.
+    fn elf64_only(&self) -> Option<&'static str> {
+        if self.section_headers || self.symbol_table {
+            Some("section headers")
+        } else if self.is_dynamic() {
+            Some("dynamic linking tables")
+        } else if self.has_notes() {
+            Some("notes")
+        } else if self.stub.is_some() {
+            Some("relocation stubs")
+        } else if self.tls_stub {
+            Some("TLS stubs")
+        } else {
+            None
+        }
+    }
+
+    // Whether a segment maps the ELF headers and the tables after them.
+    fn headers_loaded(&self) -> bool {
+        self.relocation_table().is_some()
+            || self.is_dynamic()
+            || self.has_notes()
+            || self.tls.is_some()
+    }
+
+    // The address of the ELF headers, if they are loaded.
+    fn headers_vaddr(&self) -> u64 {
+        if self.position_independent() {
+            0
+        } else {
+            self.base
+        }
+    }
+
+    // Whether the program headers are described by a PT_PHDR segment, which
+    // the dynamic linker needs to find the load base of a PIE.
+    fn has_phdr_segment(&self) -> bool {
+        self.has_interpreter() && self.position_independent()
+    }
+
+    // The number of program headers, given the number of segments.
+    fn phnum(&self, parts: usize) -> usize {
+        // PT_DYNAMIC and PT_GNU_STACK
+        let dynamic = if self.is_dynamic() { 2 } else { 0 };
+        parts
+            + self.headers_loaded() as usize
+            + dynamic
+            + self.has_interpreter() as usize
+            + self.has_phdr_segment() as usize
+            + self.has_notes() as usize
+            + self.tls.is_some() as usize
+    }
+
+    // The end of the program headers and relocation table.
+    fn table_end(&self, parts: usize) -> usize {
+        let table_size = self.relocation_table().map_or(0, |n| 4 * (n + 1));
+        self.format.ehsize() + self.phnum(parts) * self.format.phentsize() + table_size
+    }
+
+    // The offset of the dynamic linking tables.
+    fn dynamic_offset(&self, parts: usize) -> usize {
+        align_up(self.table_end(parts) as u64, 8) as usize
+    }
+
+    // The end of the dynamic linking tables, or of the relocation table.
+    fn dynamic_end(&self, parts: usize) -> usize {
+        match self.dynamic() {
+            Some(tables) => self.dynamic_offset(parts) + tables.size(),
+            None => self.table_end(parts),
+        }
+    }
+
+    // The offset of the notes.
+    fn notes_offset(&self, parts: usize) -> usize {
+        align_up(self.dynamic_end(parts) as u64, 4) as usize
+    }
+
+    // The end of the notes, or of the tables if there are none.
+    fn notes_end(&self, parts: usize) -> usize {
+        if !self.has_notes() {
+            return self.dynamic_end(parts);
+        }
+        let notes: usize = self.encoded_notes().iter().map(Vec::len).sum();
+        self.notes_offset(parts) + notes
+    }
+
+    // The offset of the TLS initialization image.
+    fn tls_image_offset(&self, parts: usize) -> usize {
+        let align = self.tls.as_ref().map_or(1, |tls| tls.2);
+        align_up(self.notes_end(parts) as u64, align) as usize
+    }
+
+    // The size of the headers, tables, notes and TLS image before the first
+    // segment.
+    fn headers_size(&self, parts: usize) -> usize {
+        match &self.tls {
+            Some((init, _, _)) => self.tls_image_offset(parts) + init.len(),
+            None => self.notes_end(parts),
+        }
+    }
+
+    fn segments(&self) -> Vec<Part> {
+        let instructions = self.asm.instructions();
+        let n = instructions.len();
+        let default = [(Segment::new(PF_R | PF_W | PF_X), 0)];
+        let segments = if self.segments.is_empty() {
+            &default[..]
+        } else {
+            &self.segments[..]
+        };
+        let mut parts = vec![];
+        for (i, &(segment, start)) in segments.iter().enumerate() {
+            let start = if i == 0 { 0 } else { start };
+            let end = segments.get(i + 1).map_or(n, |&(_, end)| end);
+            let mut part = Part {
+                segment,
+                code: vec![],
+                rodata_start: 0,
+                bss: vec![],
+            };
+            let mut rodata = vec![];
+            for index in start..end {
+                if self.imports.iter().any(|i| i.index == index) {
+                    continue;
+                }
+                if let Some(&bss) = self.bss.iter().find(|&&(bss, _)| bss == index) {
+                    part.bss.push(bss);
+                } else if self.rodata.iter().any(|r| r.contains(&index)) {
+                    rodata.push(index);
+                } else {
+                    part.code.push(index);
+                }
+            }
+            part.rodata_start = part.code.len();
+            part.code.extend(rodata);
+            parts.push(part);
+        }
+        parts
+    }
+
+    // Assign each segment a file offset and address, given the size of its contents.
+    fn layout(&self, parts: &[Part], sizes: &[u64]) -> Result<Vec<Phdr>, BuildError> {
+        let headers_size = self.headers_size(parts.len());
+        let mut file_end = headers_size as u64;
+        let mut mem_end = self.headers_vaddr();
+        if self.headers_loaded() {
+            // the headers are loaded at the base
+            mem_end += headers_size as u64;
+        }
+        let mut phdrs: Vec<Phdr> = vec![];
+        for (i, (part, &size)) in parts.iter().zip(sizes).enumerate() {
+            let segment = &part.segment;
+            let align = segment.align;
+            if !align.is_power_of_two() {
+                return Err(BuildError::Layout(format!(
+                    "segment {i} alignment {align:#x} is not a power of two"
+                )));
+            }
+            let offset = segment.offset.unwrap_or(file_end);
+            let vaddr = segment
+                .vaddr
+                .unwrap_or_else(|| align_up(mem_end, align) + offset % align);
+            if offset % align != vaddr % align {
+                return Err(BuildError::Layout(format!(
+                    "segment {i} offset {offset:#x} and address {vaddr:#x} differ modulo {align:#x}"
+                )));
+            }
+            // the kernel only clears the rest of the last file page if it
+            // can write to it
+            if !part.bss.is_empty() && segment.flags & PF_W == 0 {
+                return Err(BuildError::Layout(format!(
+                    "segment {i} has bss but is not writable"
+                )));
+            }
+            let (_, bss_end) = part.bss_addrs(vaddr + size);
+            let phdr = Phdr {
+                _type: PT_LOAD,
+                flags: segment.flags,
+                offset,
+                vaddr,
+                filesz: size,
+                memsz: bss_end - vaddr,
+                align,
+                ..Phdr::default()
+            };
+            if phdr.filesz > 0 && offset < headers_size as u64 {
+                return Err(BuildError::Layout(format!(
+                    "segment {i} overlaps the ELF headers"
+                )));
+            }
+            for (j, other) in phdrs.iter().enumerate() {
+                if overlaps(offset, phdr.filesz, other.offset, other.filesz) {
+                    return Err(BuildError::Layout(format!(
+                        "segments {j} and {i} overlap in the file"
+                    )));
+                }
+                if overlaps(vaddr, phdr.memsz, other.vaddr, other.memsz) {
+                    return Err(BuildError::Layout(format!(
+                        "segments {j} and {i} overlap in memory"
+                    )));
+                }
+            }
+            file_end = file_end.max(offset + phdr.filesz);
+            mem_end = mem_end.max(vaddr + phdr.memsz);
+            phdrs.push(phdr);
+        }
+        Ok(phdrs)
+    }
+
+    // Lay out and encode all segments, repeating until the layout is stable.
+    fn encode(&self, parts: &[Part]) -> Result<Encoded, BuildError> {
+        let instructions = self.asm.instructions();
+        let code: Vec<Vec<Instruction>> = parts
+            .iter()
+            .map(|part| part.code.iter().map(|&i| instructions[i]).collect())
+            .collect();
+        let mut sizes = vec![0; parts.len()];
+        for _ in 0..MAX_PASSES {
+            let phdrs = self.layout(parts, &sizes)?;
+            let mut blocks = vec![];
+            // the segment of each block, or None for a block holding just a
+            // label
+            let mut owners = vec![];
+            let mut addrs = vec![0; instructions.len()];
+            for (i, ((part, code), phdr)) in parts.iter().zip(&code).zip(&phdrs).enumerate() {
+                blocks.push((phdr.vaddr, code.as_slice()));
+                owners.push(Some(i));
+                // each bss label is encoded on its own, at its final address
+                let (bss_addrs, _) = part.bss_addrs(phdr.vaddr + phdr.filesz);
+                for (&(index, _), addr) in part.bss.iter().zip(bss_addrs) {
+                    addrs[index] = addr;
+                    blocks.push((addr, std::slice::from_ref(&instructions[index])));
+                    owners.push(None);
+                }
+            }
+            // and so is each GOT entry's label
+            for (import, addr) in self.imports.iter().zip(self.got_addrs(parts.len())) {
+                addrs[import.index] = addr;
+                blocks.push((addr, std::slice::from_ref(&instructions[import.index])));
+                owners.push(None);
+            }
+            let instruction_blocks: Vec<InstructionBlock> = (blocks.iter())
+                .map(|&(rip, code)| InstructionBlock::new(code, rip))
+                .collect();
+            let results = BlockEncoder::encode_slice(
+                64,
+                &instruction_blocks,
+                BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS,
+            )?;
+            // the results are sorted by address, not in the order of the
+            // blocks
+            let mut order: Vec<usize> = (0..blocks.len()).collect();
+            order.sort_by_key(|&i| blocks[i].0);
+            let mut contents = vec![vec![]; parts.len()];
+            for (&i, result) in order.iter().zip(results) {
+                // the label blocks are empty and their addresses already known
+                let Some(segment) = owners[i] else {
+                    continue;
+                };
+                let code = &parts[segment].code;
+                for (&index, &offset) in code.iter().zip(&result.new_instruction_offsets) {
+                    addrs[index] = result.rip + offset as u64;
+                }
+                contents[segment] = result.code_buffer;
+            }
+            let new_sizes: Vec<u64> = contents.iter().map(|c| c.len() as u64).collect();
+            if new_sizes == sizes {
+                return Ok(Encoded {
+                    phdrs,
+                    contents,
+                    addrs,
+                });
+            }
+            sizes = new_sizes;
+        }
+        Err(BuildError::Layout(
+            "segment sizes did not converge".to_string(),
+        ))
+    }
+
+    // Check that nothing in a PIE depends on the load address.
+    fn check_position_independent(&self) -> Result<(), BuildError> {
+        if let Some(fixup) = self.fixups.iter().find(|f| f.relocate && f.size < 8) {
+            return Err(BuildError::Absolute(format!(
+                "32-bit address of {}",
+                fixup.symbol
+            )));
+        }
+        for instr in self.asm.instructions() {
+            let memory = (0..instr.op_count()).any(|i| instr.op_kind(i) == OpKind::Memory);
+            // fs and gs point at thread-local storage, not the image, and an
+            // index without a displacement is just arithmetic in an lea
+            let absolute = instr.memory_base() == Register::None
+                && !matches!(instr.segment_prefix(), Register::FS | Register::GS)
+                && (instr.memory_index() == Register::None || instr.memory_displacement64() != 0);
+            if memory && absolute {
+                return Err(BuildError::Absolute(format!("{instr}")));
+            }
+        }
+        Ok(())
+    }
+
+    /// Lay out the file and resolve all labels.
+    pub fn link(&self) -> Result<Image, BuildError> {
+        if self.format != Format::ELF64_LSB {
+            if let Some(what) = self.elf64_only() {
+                return Err(FormatError::Elf64Only(what).into());
+            }
+        }
+        if self.position_independent() {
+            self.check_position_independent()?;
+        } else if self.stub.is_some() {
+            return Err(BuildError::Layout(
+                "the relocation stub needs a PIE".to_string(),
+            ));
+        }
+        if self.stub.is_some() && self.is_dynamic() {
+            return Err(BuildError::Layout(
+                "the dynamic linker applies the relocations, not the stub".to_string(),
+            ));
+        }
+        if self.pie && !self.is_dynamic() && self.stub.is_none() {
+            // nothing would apply the relocations
+            if let Some(fixup) = self.fixups.iter().find(|f| f.relocate) {
+                return Err(BuildError::Absolute(format!(
+                    "address of {} without a relocation stub",
+                    fixup.symbol
+                )));
+            }
+        }
+        match &self.tls {
+            Some((_, _, align)) if !align.is_power_of_two() || *align > 4096 => {
+                return Err(BuildError::Layout(format!("bad TLS alignment {align:#x}")));
+            }
+            None if self.tls_stub => {
+                return Err(BuildError::Layout(
+                    "the TLS stub needs a TLS block".to_string(),
+                ));
+            }
+            _ if self.tls_stub && self.is_dynamic() => {
+                return Err(BuildError::Layout(
+                    "the dynamic linker sets up TLS, not the stub".to_string(),
+                ));
+            }
+            _ => {}
+        }
+        let parts = self.segments();
+        let Encoded {
+            phdrs,
+            contents,
+            addrs,
+        } = self.encode(&parts)?;
+
+        let mut symbols = BTreeMap::new();
+        for (name, index, _) in &self.symbols {
+            if symbols.insert(name.clone(), addrs[*index]).is_some() {
+                return Err(BuildError::DuplicateSymbol(name.clone()));
+            }
+        }
+
+        let mut contents = contents;
+        let mut relocations = vec![];
+        let mut relas = vec![];
+        for fixup in &self.fixups {
+            let target = *symbols
+                .get(&fixup.symbol)
+                .ok_or_else(|| BuildError::UndefinedSymbol(fixup.symbol.clone()))?;
+            let addr = addrs[fixup.index] + fixup.offset as u64;
+            let value = &target.to_le_bytes()[..fixup.size];
+            if fixup.size < 8 && target >> (8 * fixup.size) != 0 {
+                return Err(BuildError::Layout(format!(
+                    "address of {} does not fit in {} bytes",
+                    fixup.symbol, fixup.size
+                )));
+            }
+            let (i, phdr) = phdrs
+                .iter()
+                .enumerate()
+                .find(|(_, p)| p.vaddr <= addr && addr < p.vaddr + p.filesz)
+                .unwrap();
+            if self.position_independent() && fixup.relocate {
+                if phdr.flags & PF_W == 0 {
+                    return Err(BuildError::Absolute(format!(
+                        "address of {} in read-only segment {i}",
+                        fixup.symbol
+                    )));
+                }
+                relocations.push(addr);
+                relas.push(Rela {
+                    offset: addr,
+                    _type: R_X86_64_RELATIVE,
+                    addend: target as i64,
+                    ..Rela::default()
+                });
+            }
+            let start = (addr - phdr.vaddr) as usize;
+            contents[i][start..start + fixup.size].copy_from_slice(value);
+        }
+
+        let entry = match symbols.get("_start") {
+            Some(&addr) => addr,
+            None => {
+                phdrs
+                    .iter()
+                    .find(|p| p.flags & PF_X != 0)
+                    .unwrap_or(&phdrs[0])
+                    .vaddr
+            }
+        };
+
+        let phoff = self.format.ehsize();
+        let phentsize = self.format.phentsize();
+        let headers_size = self.headers_size(parts.len());
+        let headers_vaddr = self.headers_vaddr();
+        let phdr_segment = self.has_phdr_segment().then(|| {
+            let size = (self.phnum(parts.len()) * phentsize) as u64;
+            Phdr {
+                _type: PT_PHDR,
+                flags: PF_R,
+                offset: phoff as u64,
+                vaddr: headers_vaddr + phoff as u64,
+                filesz: size,
+                memsz: size,
+                align: 8,
+                ..Phdr::default()
+            }
+        });
+        let dynamic = self.dynamic();
+        let dynamic_offset = self.dynamic_offset(parts.len()) as u64;
+        let interp = dynamic.as_ref().and_then(|tables| tables.interp());
+        let interp = interp.map(|(start, size)| Phdr {
+            _type: PT_INTERP,
+            flags: PF_R,
+            offset: dynamic_offset + start,
+            vaddr: headers_vaddr + dynamic_offset + start,
+            filesz: size,
+            memsz: size,
+            align: 1,
+            ..Phdr::default()
+        });
+        // the dynamic linker fills in the GOT, and older ones (glibc before
+        // 2.35) relocate the addresses in .dynamic in place
+        let writable = if self.is_dynamic() { PF_W } else { 0 };
+        let headers = self.headers_loaded().then_some(Phdr {
+            _type: PT_LOAD,
+            flags: PF_R | writable,
+            offset: 0,
+            vaddr: headers_vaddr,
+            filesz: headers_size as u64,
+            memsz: headers_size as u64,
+            align: 4096,
+            ..Phdr::default()
+        });
+        let dynamic_phdrs = dynamic.iter().flat_map(|tables| {
+            let (start, size) = tables.dynamic();
+            let dynamic = Phdr {
+                _type: PT_DYNAMIC,
+                flags: PF_R | PF_W,
+                offset: dynamic_offset + start,
+                vaddr: headers_vaddr + dynamic_offset + start,
+                filesz: size,
+                memsz: size,
+                align: 8,
+                ..Phdr::default()
+            };
+            let stack = Phdr {
+                _type: PT_GNU_STACK,
+                flags: PF_R | PF_W,
+                align: 16,
+                ..Phdr::default()
+            };
+            [dynamic, stack]
+        });
+        let notes = self.encoded_notes().concat();
+        let notes_offset = self.notes_offset(parts.len()) as u64;
+        let note = self.has_notes().then_some(Phdr {
+            _type: PT_NOTE,
+            flags: PF_R,
+            offset: notes_offset,
+            vaddr: headers_vaddr + notes_offset,
+            filesz: notes.len() as u64,
+            memsz: notes.len() as u64,
+            align: 4,
+            ..Phdr::default()
+        });
+        let tls_offset = self.tls_image_offset(parts.len()) as u64;
+        let tls = self.tls.as_ref().map(|(init, zero_size, align)| Phdr {
+            _type: PT_TLS,
+            flags: PF_R,
+            offset: tls_offset,
+            vaddr: headers_vaddr + tls_offset,
+            filesz: init.len() as u64,
+            memsz: init.len() as u64 + zero_size,
+            align: *align,
+            ..Phdr::default()
+        });
+        // PT_PHDR and PT_INTERP must come before any PT_LOAD
+        let all_phdrs: Vec<Phdr> = (phdr_segment.into_iter())
+            .chain(interp)
+            .chain(headers)
+            .chain(phdrs.iter().copied())
+            .chain(dynamic_phdrs)
+            .chain(note)
+            .chain(tls)
+            .collect();
+        let len = phdrs
+            .iter()
+            .map(|p| (p.offset + p.filesz) as usize)
+            .fold(headers_size, usize::max);
+        let mut bytes = vec![0u8; len];
+        for (phdr, code) in phdrs.iter().zip(&contents) {
+            let offset = phdr.offset as usize;
+            bytes[offset..offset + code.len()].copy_from_slice(code);
+        }
+        let header = Header {
+            // ET_DYN or ET_EXEC
+            _type: if self.position_independent() { 3 } else { 2 },
+            machine: self.machine.0,
+            version: 1, // EV_CURRENT
+            entry,
+            phoff: phoff as u64,
+            flags: self.machine.1,
+            ehsize: phoff as u16,
+            phentsize: phentsize as u16,
+            phnum: all_phdrs.len() as u16,
+            ..Header::default()
+        };
+        self.format.write_header(&mut bytes, &header)?;
+        for (i, phdr) in all_phdrs.iter().enumerate() {
+            let start = phoff + i * phentsize;
+            self.format.write_phdr(&mut bytes[start..], phdr)?;
+        }
+        if self.relocation_table().is_some() {
+            // 0 ends the table, and is never the address of a relocation
+            let start = phoff + all_phdrs.len() * phentsize;
+            for (i, &addr) in relocations.iter().chain(&[0]).enumerate() {
+                let addr = u32::try_from(addr).map_err(|_| {
+                    BuildError::Layout(format!("relocation at {addr:#x} is above 4 GiB"))
+                })?;
+                bytes[start + 4 * i..][..4].copy_from_slice(&addr.to_le_bytes());
+            }
+        }
+
+        // exported symbols need section indexes
+        let with_sections = self.section_headers || self.symbol_table || !self.exports.is_empty();
+        let mut sections = with_sections.then(|| {
+            let mut sections = SectionTable::new();
+            for (part, phdr) in parts.iter().zip(&phdrs) {
+                add_sections(&mut sections, part, phdr, &addrs);
+            }
+            sections
+        });
+        if let Some(tables) = &dynamic {
+            let mut syms = vec![];
+            for name in &self.exports {
+                let addr = *symbols
+                    .get(name)
+                    .ok_or_else(|| BuildError::UndefinedSymbol(name.clone()))?;
+                let (_, _, size) = self.symbols.iter().find(|s| &s.0 == name).unwrap();
+                syms.push(symbol(sections.as_ref().unwrap(), addr, *size, true));
+            }
+            let offset = dynamic_offset as usize;
+            let addr = headers_vaddr + dynamic_offset;
+            tables.write(&mut bytes[offset..], addr, &syms, &relas);
+            if let Some(sections) = &mut sections {
+                tables.add_sections(sections, addr, dynamic_offset);
+            }
+        }
+        let notes_offset = notes_offset as usize;
+        bytes[notes_offset..][..notes.len()].copy_from_slice(&notes);
+        if let Some((init, _, _)) = &self.tls {
+            bytes[tls_offset as usize..][..init.len()].copy_from_slice(init);
+        }
+        // the build ID is the last note: a header, "GNU\0" and the 20-byte ID
+        let build_id_size = if self.build_id { 12 + 4 + 20 } else { 0 };
+        if let Some(sections) = &mut sections {
+            let note = |offset: usize, size: usize| Shdr {
+                _type: SHT_NOTE,
+                flags: SHF_ALLOC,
+                addr: headers_vaddr + offset as u64,
+                offset: offset as u64,
+                size: size as u64,
+                addralign: 4,
+                ..Shdr::default()
+            };
+            let vendor_size = notes.len() - build_id_size;
+            if vendor_size > 0 {
+                sections.add(".note", note(notes_offset, vendor_size));
+            }
+            if self.build_id {
+                let offset = notes_offset + vendor_size;
+                sections.add(".note.gnu.build-id", note(offset, build_id_size));
+            }
+            if let Some((init, zero_size, align)) = &self.tls {
+                let flags = SHF_ALLOC | SHF_WRITE | SHF_TLS;
+                let section = |_type, offset: u64, size| Shdr {
+                    _type,
+                    flags,
+                    addr: headers_vaddr + offset,
+                    offset,
+                    size,
+                    addralign: *align,
+                    ..Shdr::default()
+                };
+                let size = init.len() as u64;
+                if size > 0 {
+                    sections.add(".tdata", section(SHT_PROGBITS, tls_offset, size));
+                }
+                if *zero_size > 0 {
+                    let tbss = section(SHT_NOBITS, tls_offset + size, *zero_size);
+                    sections.add(".tbss", tbss);
+                }
+            }
+        }
+        if let Some(mut sections) = sections {
+            if self.symbol_table {
+                let mut symtab = SymbolTable::new();
+                for (name, index, size) in &self.symbols {
+                    let global = name == "_start" || self.exports.contains(name);
+                    symtab.add(name, symbol(&sections, addrs[*index], *size, global));
+                }
+                symtab.finish(&mut bytes, &mut sections);
+            }
+            sections.finish(&mut bytes);
+        }
+        if self.build_id {
+            let id = sha1(&bytes);
+            bytes[notes_offset + notes.len() - 20..][..20].copy_from_slice(&id);
+        }
+
+        if self.lint && self.format == Format::ELF64_LSB {
+            let diagnostics = lint(&bytes);
+            if !diagnostics.is_empty() {
+                return Err(BuildError::Lint(diagnostics));
+            }
+        }
+
+        Ok(Image {
+            bytes,
+            entry,
+            symbols,
+            relocations,
+        })
+    }
+
+    /// Build the ELF file.
+    pub fn build(&self) -> Result<Vec<u8>, BuildError> {
+        Ok(self.link()?.bytes)
+    }
+
+    /// Build the ELF file and write it to `path` as an executable.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), BuildError> {
+        write_executable(path, &self.build()?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{align_up, BuildError, ElfBuilder, LoadBase, Segment, BSS_ALIGN};
+    use crate::test_util::{exit_code, output, run};
+    use crate::{elf64_hdr, elf64_phdr, elf64_shdr, elf_from_asm, PF_R, PF_W, PF_X, PROGRAM_VADDR};
+    use crate::{elf64_nhdr, NT_GNU_BUILD_ID, PT_NOTE, SHT_NOTE};
+    use crate::{elf64_sym, STB_GLOBAL, STT_FUNC, STT_OBJECT};
+    use crate::{parse_headers, Format, FormatError, PT_TLS, SHF_TLS, VADDR};
+    use crate::{PT_DYNAMIC, PT_GNU_STACK, PT_INTERP, PT_LOAD, PT_PHDR};
+    use crate::{
+        SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE, SHT_NOBITS, SHT_PROGBITS, SHT_STRTAB, SHT_SYMTAB,
+    };
+    use iced_x86::code_asm::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    fn exit_stub(a: &mut CodeAssembler) {
+        a.push(60).unwrap();
+        a.pop(rax).unwrap();
+        a.xor(edi, edi).unwrap();
+        a.syscall().unwrap();
+    }
+
+    fn phdr(bytes: &[u8], i: usize) -> elf64_phdr::View<&[u8]> {
+        let start = elf64_hdr::SIZE.unwrap() + i * elf64_phdr::SIZE.unwrap();
+        elf64_phdr::View::new(&bytes[start..])
+    }
+
+    #[test]
+    fn test_default_matches_elf_from_asm() {
+        let mut b = ElfBuilder::new();
+        exit_stub(b.asm());
+        let mut a = CodeAssembler::new(64).unwrap();
+        exit_stub(&mut a);
+        assert_eq!(elf_from_asm(&mut a).unwrap(), b.build().unwrap());
+    }
+
+    // text, rodata and data segments; returns the value in rodata via data
+    fn w_xor_x() -> ElfBuilder {
+        let mut b = ElfBuilder::new();
+        b.segment(Segment::new(PF_R | PF_X));
+        b.label("_start").unwrap();
+        let value = b.asm().create_label();
+        let slot = b.asm().create_label();
+        let a = b.asm();
+        a.mov(eax, dword_ptr(value)).unwrap();
+        a.mov(dword_ptr(slot), eax).unwrap();
+        a.mov(edi, dword_ptr(slot)).unwrap();
+        a.push(60).unwrap();
+        a.pop(rax).unwrap();
+        a.syscall().unwrap();
+        b.segment(Segment::new(PF_R));
+        let mut value = value;
+        b.asm().set_label(&mut value).unwrap();
+        b.asm().dd(&[42]).unwrap();
+        b.segment(Segment::new(PF_R | PF_W));
+        let mut slot = slot;
+        b.asm().set_label(&mut slot).unwrap();
+        b.asm().dd(&[0]).unwrap();
+        b
+    }
+
+    #[test]
+    fn test_w_xor_x_segments() {
+        let image = w_xor_x().link().unwrap();
+        let bytes = &image.bytes;
+        let hdr = elf64_hdr::View::new(&bytes[..]);
+        assert_eq!(3, hdr.phnum().read());
+        assert_eq!(64, hdr.phoff().read());
+        assert_eq!(image.symbols["_start"], hdr.entry().read());
+        let flags: Vec<u32> = (0..3).map(|i| phdr(bytes, i).flags().read()).collect();
+        assert_eq!(vec![PF_R | PF_X, PF_R, PF_R | PF_W], flags);
+        for i in 0..3 {
+            let p = phdr(bytes, i);
+            assert_eq!(p.offset().read() % 4096, p.vaddr().read() % 4096);
+        }
+        // all segments share the file, but not pages in memory
+        assert_eq!(64 + 3 * 56 + 23 + 4 + 4, bytes.len());
+        assert_eq!(42, exit_code(&run("w_xor_x", bytes)));
+    }
+
+    #[test]
+    fn test_read_only_segment() {
+        let mut b = ElfBuilder::new();
+        b.segment(Segment::new(PF_R | PF_X));
+        let mut value = b.asm().create_label();
+        b.asm().mov(dword_ptr(value), eax).unwrap();
+        exit_stub(b.asm());
+        b.segment(Segment::new(PF_R));
+        b.asm().set_label(&mut value).unwrap();
+        b.asm().dd(&[0]).unwrap();
+        let status = run("read_only_segment", &b.build().unwrap());
+        assert_eq!(Some(11), status.signal()); // SIGSEGV
+    }
+
+    #[test]
+    fn test_entry_in_data_segment() {
+        let mut b = ElfBuilder::new();
+        b.lint(true);
+        b.segment(Segment::new(PF_R | PF_X));
+        exit_stub(b.asm());
+        b.segment(Segment::new(PF_R | PF_W));
+        b.label("_start").unwrap();
+        b.asm().dd(&[0]).unwrap();
+        match b.link() {
+            Err(BuildError::Lint(diagnostics)) => {
+                assert_eq!(1, diagnostics.len());
+                assert_eq!("e_entry", diagnostics[0].field);
+            }
+            r => panic!("expected a lint error, got {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_fixed_placement() {
+        let mut b = ElfBuilder::new();
+        b.segment(
+            Segment::new(PF_R | PF_X)
+                .align(0x10000)
+                .offset(0x200)
+                .vaddr(0x1230200),
+        );
+        exit_stub(b.asm());
+        let bytes = b.build().unwrap();
+        let p = phdr(&bytes, 0);
+        assert_eq!(0x200, p.offset().read());
+        assert_eq!(0x1230200, p.vaddr().read());
+        assert_eq!(0x10000, p.align().read());
+        assert_eq!(0x1230200, elf64_hdr::View::new(&bytes[..]).entry().read());
+        assert_eq!(0x200 + 7, bytes.len());
+        assert_eq!(0, exit_code(&run("fixed_placement", &bytes)));
+    }
+
+    #[test]
+    fn test_bad_layouts() {
+        let mut b = ElfBuilder::new();
+        b.segment(Segment::new(PF_R | PF_X).offset(0x200).vaddr(0x400100));
+        exit_stub(b.asm());
+        assert!(matches!(b.build(), Err(BuildError::Layout(_))));
+
+        let mut b = ElfBuilder::new();
+        b.segment(Segment::new(PF_R | PF_X).align(3));
+        exit_stub(b.asm());
+        assert!(matches!(b.build(), Err(BuildError::Layout(_))));
+
+        let mut b = ElfBuilder::new();
+        b.segment(Segment::new(PF_R | PF_X).offset(0x100));
+        exit_stub(b.asm());
+        b.segment(Segment::new(PF_R).offset(0x104));
+        b.asm().dd(&[0]).unwrap();
+        assert!(matches!(b.build(), Err(BuildError::Layout(_))));
+
+        let mut b = ElfBuilder::new();
+        b.label("x").unwrap();
+        exit_stub(b.asm());
+        b.label("x").unwrap();
+        b.asm().nop().unwrap();
+        assert!(matches!(b.build(), Err(BuildError::DuplicateSymbol(_))));
+    }
+
+    #[test]
+    fn test_bss_in_code_segment() {
+        let mut b = ElfBuilder::new();
+        let buf = b.symbol("buf");
+        let a = b.asm();
+        // store at the end of the buffer and load it back
+        a.lea(rbx, ptr(buf)).unwrap();
+        a.mov(dword_ptr(rbx + 0xffffc), 42).unwrap();
+        a.mov(edi, dword_ptr(rbx + 0xffffc)).unwrap();
+        // the rest is zero
+        a.add(edi, dword_ptr(rbx + 0x1234)).unwrap();
+        a.push(60).unwrap();
+        a.pop(rax).unwrap();
+        a.syscall().unwrap();
+        b.bss("buf", 0x100000).unwrap();
+        let image = b.link().unwrap();
+        let p = phdr(&image.bytes, 0);
+        let (vaddr, filesz) = (p.vaddr().read(), p.filesz().read());
+        assert_eq!(image.bytes.len() as u64, 120 + filesz);
+        let addr = image.symbols["buf"];
+        assert!(vaddr + filesz <= addr && addr < vaddr + filesz + BSS_ALIGN);
+        assert_eq!(0, addr % BSS_ALIGN);
+        assert_eq!(addr + 0x100000, vaddr + p.memsz().read());
+        assert_eq!(42, exit_code(&run("bss_in_code_segment", &image.bytes)));
+    }
+
+    #[test]
+    fn test_bss_segment() {
+        let mut b = ElfBuilder::new();
+        b.segment(Segment::new(PF_R | PF_X));
+        let counter = b.symbol("counter");
+        let other = b.symbol("other");
+        let a = b.asm();
+        a.mov(edi, dword_ptr(counter)).unwrap();
+        a.add(edi, 2).unwrap();
+        a.mov(dword_ptr(other), edi).unwrap();
+        a.add(edi, dword_ptr(other)).unwrap();
+        a.push(60).unwrap();
+        a.pop(rax).unwrap();
+        a.syscall().unwrap();
+        b.segment(Segment::new(PF_R | PF_W));
+        // a segment with only bss takes no space in the file
+        b.bss("counter", 64).unwrap();
+        b.bss("other", 8).unwrap();
+        let image = b.link().unwrap();
+        let p = phdr(&image.bytes, 1);
+        assert_eq!(0, p.filesz().read());
+        assert_eq!(64 + 2 * 56 + 26, image.bytes.len());
+        let counter = image.symbols["counter"];
+        assert_eq!(align_up(p.vaddr().read(), BSS_ALIGN), counter);
+        assert_eq!(counter + 64, image.symbols["other"]);
+        assert_eq!(counter + 64 + 8, p.vaddr().read() + p.memsz().read());
+        assert_eq!(4, exit_code(&run("bss_segment", &image.bytes)));
+    }
+
+    #[test]
+    fn test_bss_followed_by_file_data() {
+        // the next segment's bytes follow the bss in the file, but the bss
+        // must still read as zero
+        let mut b = ElfBuilder::new();
+        b.segment(Segment::new(PF_R | PF_X));
+        let x = b.symbol("x");
+        b.asm().mov(edi, dword_ptr(x)).unwrap();
+        b.asm().push(60).unwrap();
+        b.asm().pop(rax).unwrap();
+        b.asm().syscall().unwrap();
+        b.segment(Segment::new(PF_R | PF_W));
+        b.asm().dd(&[1]).unwrap();
+        b.bss("x", 4).unwrap();
+        b.segment(Segment::new(PF_R));
+        b.asm().dd(&[0xffff_ffff; 8]).unwrap();
+        let status = run("bss_followed_by_file_data", &b.build().unwrap());
+        assert_eq!(0, exit_code(&status));
+    }
+
+    #[test]
+    fn test_read_only_bss() {
+        let mut b = ElfBuilder::new();
+        b.segment(Segment::new(PF_R | PF_X));
+        exit_stub(b.asm());
+        b.bss("x", 4).unwrap();
+        assert!(matches!(b.build(), Err(BuildError::Layout(_))));
+    }
+
+    // write(1, msg, len)
+    fn write_stdout(a: &mut CodeAssembler, len: u32) {
+        a.mov(edx, len).unwrap();
+        a.push(1).unwrap();
+        a.pop(rax).unwrap();
+        a.mov(edi, eax).unwrap();
+        a.syscall().unwrap();
+    }
+
+    #[test]
+    fn test_rodata_in_code_segment() {
+        let mut b = ElfBuilder::new();
+        // placed after the code even though it is added first
+        let msg = b.rodata("msg", b"hello\n").unwrap();
+        b.asm().lea(rsi, ptr(msg)).unwrap();
+        write_stdout(b.asm(), 6);
+        exit_stub(b.asm());
+        let image = b.link().unwrap();
+        assert_eq!(PROGRAM_VADDR, image.entry);
+        assert_eq!(
+            image.symbols["msg"] + 6,
+            PROGRAM_VADDR + image.bytes.len() as u64 - 120
+        );
+        let out = output("rodata_in_code_segment", &image.bytes);
+        assert_eq!(b"hello\n", &out.stdout[..]);
+        assert_eq!(0, exit_code(&out.status));
+    }
+
+    #[test]
+    fn test_rodata_segment() {
+        let mut b = ElfBuilder::new();
+        b.segment(Segment::new(PF_R | PF_X));
+        b.mov_addr(esi, "msg").unwrap();
+        write_stdout(b.asm(), 6);
+        b.mov_addr(rbx, "table").unwrap();
+        b.asm().movzx(edi, byte_ptr(rbx + 3)).unwrap();
+        b.asm().push(60).unwrap();
+        b.asm().pop(rax).unwrap();
+        b.asm().syscall().unwrap();
+        b.segment(Segment::new(PF_R));
+        b.rodata("msg", b"hello\n").unwrap();
+        b.rodata("table", &[1, 2, 3, 42]).unwrap();
+        let image = b.link().unwrap();
+        let p = phdr(&image.bytes, 1);
+        assert_eq!(PF_R, p.flags().read());
+        assert_eq!(p.vaddr().read(), image.symbols["msg"]);
+        assert_eq!(p.vaddr().read() + 6, image.symbols["table"]);
+        let out = output("rodata_segment", &image.bytes);
+        assert_eq!(b"hello\n", &out.stdout[..]);
+        assert_eq!(42, exit_code(&out.status));
+    }
+
+    #[test]
+    fn test_format() {
+        // svc 1 (exit) on s390x, which is only compared, not run
+        let code = [0x0a, 0x01];
+        for (format, machine) in [(Format::ELF64_MSB, 22), (Format::ELF32_MSB, 8)] {
+            let mut b = ElfBuilder::new();
+            b.machine(machine, 0).format(format);
+            b.asm().db(&code).unwrap();
+            let bytes = b.build().unwrap();
+            let (header, phdrs) = parse_headers(&bytes).unwrap();
+            assert_eq!(
+                (format.class as u8, format.encoding as u8, machine),
+                (header.class, header.data, header.machine)
+            );
+            assert_eq!(format.ehsize() as u64, header.phoff);
+            assert_eq!(1, phdrs.len());
+            let entry = (header.entry - phdrs[0].vaddr + phdrs[0].offset) as usize;
+            assert_eq!(&code, &bytes[entry..]);
+        }
+    }
+
+    #[test]
+    fn test_format_errors() {
+        let mut b = ElfBuilder::new();
+        b.format(Format::ELF32_LSB);
+        exit_stub(b.asm());
+        b.base(1 << 32);
+        assert!(matches!(
+            b.build(),
+            Err(BuildError::Format(FormatError::TooLarge { .. }))
+        ));
+        b.base(VADDR).section_headers(true);
+        assert!(matches!(
+            b.build(),
+            Err(BuildError::Format(FormatError::Elf64Only(
+                "section headers"
+            )))
+        ));
+        b.format(Format::ELF64_MSB).section_headers(false);
+        b.build_id(true);
+        assert!(matches!(
+            b.build(),
+            Err(BuildError::Format(FormatError::Elf64Only("notes")))
+        ));
+    }
+
+    #[test]
+    fn test_undefined_symbol() {
+        let mut b = ElfBuilder::new();
+        b.mov_addr(esi, "msg").unwrap();
+        exit_stub(b.asm());
+        assert!(matches!(b.build(), Err(BuildError::UndefinedSymbol(_))));
+    }
+
+    // (name, type, flags, addr, size) of each section
+    fn sections(bytes: &[u8]) -> Vec<(String, u32, u64, u64, u64)> {
+        let hdr = elf64_hdr::View::new(bytes);
+        let shoff = hdr.shoff().read() as usize;
+        let shentsize = hdr.shentsize().read() as usize;
+        let shdr = |i: usize| elf64_shdr::View::new(&bytes[shoff + i * shentsize..]);
+        let strtab = shdr(hdr.shstrndx().read() as usize).offset().read() as usize;
+        (0..hdr.shnum().read() as usize)
+            .map(|i| {
+                let s = shdr(i);
+                let name = &bytes[strtab + s.name().read() as usize..];
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap()];
+                (
+                    String::from_utf8(name.to_vec()).unwrap(),
+                    s._type().read(),
+                    s.flags().read(),
+                    s.addr().read(),
+                    s.size().read(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_no_section_headers() {
+        let bytes = w_xor_x().build().unwrap();
+        let hdr = elf64_hdr::View::new(&bytes[..]);
+        assert_eq!(0, hdr.shoff().read());
+        assert_eq!(0, hdr.shnum().read());
+        assert_eq!(0, hdr.shstrndx().read());
+    }
+
+    fn hello_sections() -> ElfBuilder {
+        let mut b = ElfBuilder::new();
+        b.section_headers(true);
+        b.segment(Segment::new(PF_R | PF_X));
+        let msg = b.symbol("msg");
+        b.asm().lea(rsi, ptr(msg)).unwrap();
+        write_stdout(b.asm(), 6);
+        exit_stub(b.asm());
+        b.rodata("answer", &[42]).unwrap();
+        b.segment(Segment::new(PF_R));
+        b.rodata("msg", b"hello\n").unwrap();
+        b.segment(Segment::new(PF_R | PF_W));
+        b.asm().dq(&[1]).unwrap();
+        b.bss("buf", 100).unwrap();
+        b
+    }
+
+    #[test]
+    fn test_section_headers() {
+        let image = hello_sections().link().unwrap();
+        let bytes = &image.bytes;
+        let sections = sections(bytes);
+        let names: Vec<&str> = sections.iter().map(|s| &s.0[..]).collect();
+        assert_eq!(
+            vec![
+                "",
+                ".text",
+                ".rodata",
+                ".rodata",
+                ".data",
+                ".bss",
+                ".shstrtab"
+            ],
+            names
+        );
+        let (_, _type, flags, addr, size) = sections[1];
+        assert_eq!((SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR), (_type, flags));
+        assert_eq!(
+            (image.entry, image.symbols["answer"] - image.entry),
+            (addr, size)
+        );
+        assert_eq!((image.symbols["answer"], 1), (sections[2].3, sections[2].4));
+        assert_eq!((image.symbols["msg"], 6), (sections[3].3, sections[3].4));
+        assert_eq!(SHF_ALLOC | SHF_WRITE, sections[4].2);
+        let (_, _type, flags, addr, size) = sections[5];
+        assert_eq!((SHT_NOBITS, SHF_ALLOC | SHF_WRITE), (_type, flags));
+        assert_eq!(image.symbols["buf"] + 100, addr + size);
+        assert_eq!((SHT_STRTAB, 0), (sections[6].1, sections[6].3));
+
+        let out = output("section_headers", bytes);
+        assert_eq!(b"hello\n", &out.stdout[..]);
+    }
+
+    #[test]
+    fn test_objdump_sections() {
+        let path = crate::test_util::path("objdump_sections");
+        hello_sections().write(&path).unwrap();
+        let out = std::process::Command::new("objdump")
+            .arg("-d")
+            .arg(&path)
+            .output();
+        std::fs::remove_file(&path).unwrap();
+        let Ok(out) = out else {
+            // objdump is not installed
+            return;
+        };
+        let out = String::from_utf8(out.stdout).unwrap();
+        assert!(out.contains("Disassembly of section .text"), "{out}");
+        assert!(out.contains("syscall"), "{out}");
+    }
+
+    // (name, info, shndx, value, size) of each symbol
+    fn symbols(bytes: &[u8]) -> Vec<(String, u8, u16, u64, u64)> {
+        let hdr = elf64_hdr::View::new(bytes);
+        let shoff = hdr.shoff().read() as usize;
+        let shentsize = hdr.shentsize().read() as usize;
+        let shdr = |i: usize| elf64_shdr::View::new(&bytes[shoff + i * shentsize..]);
+        let symtab = (0..hdr.shnum().read() as usize)
+            .map(shdr)
+            .find(|s| s._type().read() == SHT_SYMTAB)
+            .unwrap();
+        let strtab = shdr(symtab.link().read() as usize).offset().read() as usize;
+        let entsize = symtab.entsize().read() as usize;
+        let start = symtab.offset().read() as usize;
+        (0..symtab.size().read() as usize / entsize)
+            .map(|i| {
+                let sym = elf64_sym::View::new(&bytes[start + i * entsize..]);
+                let name = &bytes[strtab + sym.name().read() as usize..];
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap()];
+                (
+                    String::from_utf8(name.to_vec()).unwrap(),
+                    sym.info().read(),
+                    sym.shndx().read(),
+                    sym.value().read(),
+                    sym.size().read(),
+                )
+            })
+            .collect()
+    }
+
+    fn loop_program() -> ElfBuilder {
+        let mut b = ElfBuilder::new();
+        b.symbol_table(true);
+        let msg = b.rodata("msg", b"hi\n").unwrap();
+        b.label("_start").unwrap();
+        b.asm().mov(ebx, 3).unwrap();
+        let top = b.label("loop").unwrap();
+        b.asm().lea(rsi, ptr(msg)).unwrap();
+        write_stdout(b.asm(), 3);
+        b.asm().dec(ebx).unwrap();
+        b.asm().jnz(top).unwrap();
+        exit_stub(b.asm());
+        b.bss("scratch", 32).unwrap();
+        b
+    }
+
+    #[test]
+    fn test_symbol_table() {
+        let image = loop_program().link().unwrap();
+        let bytes = &image.bytes;
+        let names: Vec<String> = sections(bytes).into_iter().map(|s| s.0).collect();
+        assert_eq!(
+            vec![
+                "",
+                ".text",
+                ".rodata",
+                ".bss",
+                ".strtab",
+                ".symtab",
+                ".shstrtab"
+            ],
+            names
+        );
+        let syms = symbols(bytes);
+        let expected = vec![
+            (String::new(), 0, 0, 0, 0),
+            ("msg".to_string(), STT_OBJECT, 2, image.symbols["msg"], 3),
+            ("loop".to_string(), STT_FUNC, 1, image.symbols["loop"], 0),
+            (
+                "scratch".to_string(),
+                STT_OBJECT,
+                3,
+                image.symbols["scratch"],
+                32,
+            ),
+            (
+                "_start".to_string(),
+                (STB_GLOBAL << 4) | STT_FUNC,
+                1,
+                image.entry,
+                0,
+            ),
+        ];
+        assert_eq!(expected, syms);
+        assert_eq!(PROGRAM_VADDR, image.entry);
+
+        let out = output("symbol_table", bytes);
+        assert_eq!(b"hi\nhi\nhi\n", &out.stdout[..]);
+    }
+
+    #[test]
+    fn test_nm_symbols() {
+        let path = crate::test_util::path("nm_symbols");
+        loop_program().write(&path).unwrap();
+        let out = std::process::Command::new("nm").arg(&path).output();
+        std::fs::remove_file(&path).unwrap();
+        let Ok(out) = out else {
+            // nm is not installed
+            return;
+        };
+        let out = String::from_utf8(out.stdout).unwrap();
+        assert!(
+            out.contains(&format!("{PROGRAM_VADDR:016x} T _start")),
+            "{out}"
+        );
+        assert!(out.contains(" t loop"), "{out}");
+        // the default segment is writable
+        assert!(out.contains(" d msg"), "{out}");
+        assert!(out.contains(" b scratch"), "{out}");
+    }
+
+    fn pie_program() -> ElfBuilder {
+        let mut b = ElfBuilder::new();
+        b.pie(true);
+        b.segment(Segment::new(PF_R | PF_X));
+        let counter = b.symbol("counter");
+        let msg = b.symbol("msg");
+        b.asm().lea(rsi, ptr(msg)).unwrap();
+        write_stdout(b.asm(), 6);
+        b.asm().add(dword_ptr(counter), 42).unwrap();
+        b.asm().mov(edi, dword_ptr(counter)).unwrap();
+        b.asm().push(60).unwrap();
+        b.asm().pop(rax).unwrap();
+        b.asm().syscall().unwrap();
+        b.segment(Segment::new(PF_R));
+        b.rodata("msg", b"hello\n").unwrap();
+        b.segment(Segment::new(PF_R | PF_W));
+        b.bss("counter", 4).unwrap();
+        b
+    }
+
+    #[test]
+    fn test_pie() {
+        let image = pie_program().link().unwrap();
+        let hdr = elf64_hdr::View::new(&image.bytes[..]);
+        assert_eq!(3, hdr._type().read()); // ET_DYN
+
+        // addresses are relative to a load base chosen by the kernel
+        assert_eq!(hdr.entry().read(), image.entry);
+        assert!(image.entry < 0x1000);
+        assert_eq!(0x1000, phdr(&image.bytes, 1).vaddr().read() & !0xfff);
+        let out = output("pie", &image.bytes);
+        assert_eq!(b"hello\n", &out.stdout[..]);
+        assert_eq!(42, exit_code(&out.status));
+    }
+
+    #[test]
+    fn test_pie_absolute() {
+        // a 32-bit address can't be relocated, and a 64-bit one only in
+        // writable memory
+        let mut b = pie_program();
+        b.mov_addr(esi, "msg").unwrap();
+        assert!(matches!(b.build(), Err(BuildError::Absolute(_))));
+        let mut b = pie_program();
+        b.segment(Segment::new(PF_R | PF_X));
+        b.mov_addr(rsi, "msg").unwrap();
+        assert!(matches!(b.build(), Err(BuildError::Absolute(_))));
+
+        let mut b = pie_program();
+        b.asm().mov(rax, qword_ptr(0x1000)).unwrap();
+        assert!(matches!(b.build(), Err(BuildError::Absolute(_))));
+        b.pie(false);
+        assert!(b.build().is_ok());
+
+        // thread-local and register-relative operands are fine
+        let mut b = pie_program();
+        b.asm().mov(rax, qword_ptr(0).fs()).unwrap();
+        b.asm().mov(rax, qword_ptr(rsp + 8)).unwrap();
+        b.asm().lea(rax, ptr(rdi * 2)).unwrap();
+        assert!(b.build().is_ok());
+    }
+
+    fn static_pie(from: LoadBase) -> ElfBuilder {
+        let mut b = ElfBuilder::new();
+        b.pie(true);
+        b.segment(Segment::new(PF_R | PF_X));
+        b.relocate(from).unwrap();
+        // print the strings through a table of absolute pointers
+        let table = b.symbol("table");
+        b.asm().lea(rbp, ptr(table)).unwrap();
+        for i in 0..2 {
+            b.asm().mov(rsi, qword_ptr(rbp + 8 * i)).unwrap();
+            write_stdout(b.asm(), 3);
+        }
+        exit_stub(b.asm());
+        b.segment(Segment::new(PF_R));
+        b.rodata("hi", b"hi\n").unwrap();
+        b.rodata("yo", b"yo\n").unwrap();
+        b.segment(Segment::new(PF_R | PF_W));
+        b.label("table").unwrap();
+        b.pointer("yo").unwrap();
+        b.pointer("hi").unwrap();
+        b
+    }
+
+    #[test]
+    fn test_static_pie() {
+        for from in [LoadBase::Rip, LoadBase::Auxv] {
+            let image = static_pie(from).link().unwrap();
+            let table = image.symbols["table"];
+            assert_eq!(vec![table, table + 8], image.relocations);
+            // the headers and relocation table get a segment of their own
+            let headers = phdr(&image.bytes, 0);
+            assert_eq!(
+                (0, 0, PF_R),
+                (
+                    headers.offset().read(),
+                    headers.vaddr().read(),
+                    headers.flags().read()
+                )
+            );
+            let start = 64 + 4 * 56;
+            let mut want = vec![];
+            for addr in [table, table + 8, 0] {
+                want.extend_from_slice(&(addr as u32).to_le_bytes());
+            }
+            assert_eq!(want, image.bytes[start..start + 12]);
+            assert_eq!(start as u64 + 12, headers.filesz().read());
+
+            let out = output(&format!("static_pie_{from:?}"), &image.bytes);
+            assert_eq!(b"yo\nhi\n", &out.stdout[..], "{from:?}");
+            assert_eq!(0, exit_code(&out.status));
+        }
+    }
+
+    #[test]
+    fn test_pointer_without_pie() {
+        let mut b = static_pie(LoadBase::Rip);
+        b.pie(false);
+        assert!(matches!(b.build(), Err(BuildError::Layout(_))));
+
+        // without the stub the addresses are simply absolute
+        let mut b = ElfBuilder::new();
+        let table = b.symbol("table");
+        b.asm().mov(rsi, qword_ptr(table)).unwrap();
+        write_stdout(b.asm(), 3);
+        exit_stub(b.asm());
+        b.rodata("msg", b"hi\n").unwrap();
+        b.label("table").unwrap();
+        b.pointer("msg").unwrap();
+        let image = b.link().unwrap();
+        assert!(image.relocations.is_empty());
+        assert_eq!(b"hi\n", &output("pointer", &image.bytes).stdout[..]);
+    }
+
+    #[test]
+    fn test_pointer_without_stub() {
+        // nothing would apply the relocation, leaving an offset as a pointer
+        let mut b = ElfBuilder::new();
+        b.pie(true);
+        exit_stub(b.asm());
+        b.segment(Segment::new(PF_R | PF_W));
+        b.rodata("msg", b"hi\n").unwrap();
+        b.pointer("msg").unwrap();
+        assert!(matches!(b.build(), Err(BuildError::Absolute(_))));
+
+        let mut b = ElfBuilder::new();
+        b.pie(true);
+        b.segment(Segment::new(PF_R | PF_W | PF_X));
+        b.mov_addr(rsi, "_start").unwrap();
+        exit_stub(b.asm());
+        b.label("_start").unwrap();
+        assert!(matches!(b.build(), Err(BuildError::Absolute(_))));
+    }
+
+    #[test]
+    fn test_pointer_read_only() {
+        let mut b = static_pie(LoadBase::Rip);
+        b.segment(Segment::new(PF_R));
+        b.pointer("hi").unwrap();
+        assert!(matches!(b.build(), Err(BuildError::Absolute(_))));
+    }
+
+    // exports `add_one(x) -> x + 1`, `answer` and `get_answer()`, which reads
+    // `answer` through a relocated pointer
+    fn shared_library() -> ElfBuilder {
+        let mut b = ElfBuilder::new();
+        b.shared(true)
+            .export("add_one")
+            .export("answer")
+            .export("get_answer");
+        b.segment(Segment::new(PF_R | PF_X));
+        b.label("add_one").unwrap();
+        b.asm().lea(eax, dword_ptr(rdi + 1)).unwrap();
+        b.asm().ret().unwrap();
+        b.label("get_answer").unwrap();
+        let pointer = b.symbol("pointer");
+        b.asm().mov(rax, qword_ptr(pointer)).unwrap();
+        b.asm().mov(eax, dword_ptr(rax)).unwrap();
+        b.asm().ret().unwrap();
+        b.rodata("answer", &42u32.to_le_bytes()).unwrap();
+        b.segment(Segment::new(PF_R | PF_W));
+        b.label("pointer").unwrap();
+        b.pointer("answer").unwrap();
+        b
+    }
+
+    #[test]
+    fn test_shared() {
+        let image = shared_library().link().unwrap();
+        let elf = crate::parse(&image.bytes).unwrap();
+        assert_eq!(3, elf.header._type); // ET_DYN
+        let types: Vec<u32> = elf.segments.iter().map(|p| p._type).collect();
+        assert_eq!(
+            vec![PT_LOAD, PT_LOAD, PT_LOAD, PT_DYNAMIC, PT_GNU_STACK],
+            types
+        );
+        // the tables are loaded with the headers
+        let dynamic = elf.segments[3];
+        assert!(dynamic.offset + dynamic.filesz <= elf.segments[0].filesz);
+        assert_eq!(dynamic.offset, dynamic.vaddr);
+        // older dynamic linkers write to .dynamic
+        assert_eq!(PF_R | PF_W, dynamic.flags);
+        assert_eq!(PF_R | PF_W, elf.segments[0].flags);
+
+        let exports: Vec<_> = (elf.dynamic_symbols.iter())
+            .map(|s| (s.name.as_str(), s.sym.value, s.sym._type, s.sym.bind))
+            .collect();
+        assert_eq!(
+            vec![
+                ("add_one", image.symbols["add_one"], STT_FUNC, STB_GLOBAL),
+                ("answer", image.symbols["answer"], STT_OBJECT, STB_GLOBAL),
+                (
+                    "get_answer",
+                    image.symbols["get_answer"],
+                    STT_FUNC,
+                    STB_GLOBAL
+                ),
+            ],
+            exports
+        );
+        for name in [".hash", ".dynsym", ".dynstr", ".rela.dyn", ".dynamic"] {
+            let section = elf.section(name).unwrap();
+            assert!(section.header.offset >= 64 + 5 * 56, "{name}");
+        }
+        // the pointer is relocated by the dynamic linker, not a stub
+        assert_eq!(vec![image.symbols["pointer"]], image.relocations);
+        assert!(crate::lint(&image.bytes).is_empty());
+    }
+
+    #[test]
+    fn test_export_undefined() {
+        let mut b = shared_library();
+        b.export("missing");
+        match b.build() {
+            Err(BuildError::UndefinedSymbol(name)) => assert_eq!("missing", name),
+            r => panic!("expected an undefined symbol, got {:?}", r.map(|_| ())),
+        }
+        let mut b = shared_library();
+        b.relocate(LoadBase::Rip).unwrap();
+        assert!(matches!(b.build(), Err(BuildError::Layout(_))));
+    }
+
+    #[test]
+    fn test_dlopen() {
+        use std::ffi::{c_char, c_int, c_void, CStr, CString};
+        extern "C" {
+            fn dlopen(filename: *const c_char, flags: c_int) -> *mut c_void;
+            fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+            fn dlerror() -> *const c_char;
+            fn dlclose(handle: *mut c_void) -> c_int;
+        }
+        const RTLD_NOW: c_int = 2;
+
+        let path = crate::test_util::path("dlopen.so");
+        shared_library().write(&path).unwrap();
+        let filename = CString::new(path.to_str().unwrap()).unwrap();
+        unsafe {
+            let handle = dlopen(filename.as_ptr(), RTLD_NOW);
+            std::fs::remove_file(&path).unwrap();
+            assert!(!handle.is_null(), "{:?}", CStr::from_ptr(dlerror()));
+            let sym = |name: &CStr| {
+                let sym = dlsym(handle, name.as_ptr());
+                assert!(!sym.is_null(), "{:?}", CStr::from_ptr(dlerror()));
+                sym
+            };
+            let add_one: extern "C" fn(i32) -> i32 = std::mem::transmute(sym(c"add_one"));
+            assert_eq!(42, add_one(41));
+            let get_answer: extern "C" fn() -> i32 = std::mem::transmute(sym(c"get_answer"));
+            assert_eq!(42, get_answer());
+            assert_eq!(42, (sym(c"answer") as *const i32).read_unaligned());
+            assert_eq!(0, dlclose(handle));
+        }
+    }
+
+    // the smallest dynamically linked hello world: puts and exit from libc
+    fn dynamic_hello() -> ElfBuilder {
+        let mut b = ElfBuilder::new();
+        let puts = b.import("libc.so.6", "puts").unwrap();
+        let exit = b.import("libc.so.6", "exit").unwrap();
+        let msg = b.symbol("msg");
+        b.asm().lea(rdi, ptr(msg)).unwrap();
+        b.asm().call(qword_ptr(puts)).unwrap();
+        b.asm().xor(edi, edi).unwrap();
+        b.asm().call(qword_ptr(exit)).unwrap();
+        b.rodata("msg", b"hello\0").unwrap();
+        b
+    }
+
+    #[test]
+    fn test_import() {
+        let image = dynamic_hello().link().unwrap();
+        let elf = crate::parse(&image.bytes).unwrap();
+        assert_eq!(2, elf.header._type); // ET_EXEC
+        let types: Vec<u32> = elf.segments.iter().map(|p| p._type).collect();
+        assert_eq!(
+            vec![PT_INTERP, PT_LOAD, PT_LOAD, PT_DYNAMIC, PT_GNU_STACK],
+            types
+        );
+        let interp = elf.segments[0];
+        let path = &image.bytes[interp.offset as usize..][..interp.filesz as usize];
+        assert_eq!(b"/lib64/ld-linux-x86-64.so.2\0", path);
+        // the GOT is with the headers, which the dynamic linker writes to
+        assert_eq!(PF_R | PF_W, elf.segments[1].flags);
+
+        assert!(elf.sections.is_empty());
+        // the static tiny_c is around 20 KB
+        assert!(image.bytes.len() < 1024, "{}", image.bytes.len());
+        assert!(crate::lint(&image.bytes).is_empty());
+
+        let mut b = dynamic_hello();
+        b.section_headers(true);
+        let elf = crate::parse(&b.build().unwrap()).unwrap();
+        let imports: Vec<_> = (elf.dynamic_symbols.iter())
+            .map(|s| (s.name.as_str(), s.sym.shndx, s.sym._type))
+            .collect();
+        assert_eq!(vec![("puts", 0, STT_FUNC), ("exit", 0, STT_FUNC)], imports);
+        let got = elf.section(".got").unwrap().header;
+        assert_eq!(16, got.size);
+        assert!(elf.section(".rela.plt").is_some());
+        assert!(elf.section(".rela.dyn").is_none());
+
+        let out = output("dynamic_hello", &image.bytes);
+        assert_eq!(b"hello\n", &out.stdout[..]);
+        assert_eq!(0, exit_code(&out.status));
+    }
+
+    #[test]
+    fn test_import_pie() {
+        let mut b = dynamic_hello();
+        b.pie(true);
+        let image = b.link().unwrap();
+        let elf = crate::parse(&image.bytes).unwrap();
+        assert_eq!(3, elf.header._type); // ET_DYN
+        let phdr = elf.segments[0];
+        assert_eq!((PT_PHDR, 64, 64), (phdr._type, phdr.offset, phdr.vaddr));
+        assert_eq!(elf.segments.len() as u64 * 56, phdr.filesz);
+
+        let out = output("dynamic_hello_pie", &image.bytes);
+        assert_eq!(b"hello\n", &out.stdout[..]);
+    }
+
+    #[test]
+    fn test_import_data() {
+        // fputs(msg, stdout), through the address of libc's stdout variable
+        let mut b = ElfBuilder::new();
+        b.section_headers(true);
+        let fputs = b.import("libc.so.6", "fputs").unwrap();
+        let stdout = b.import_data("libc.so.6", "stdout").unwrap();
+        let exit = b.import("libc.so.6", "exit").unwrap();
+        let msg = b.symbol("msg");
+        b.asm().lea(rdi, ptr(msg)).unwrap();
+        b.asm().mov(rsi, qword_ptr(stdout)).unwrap();
+        b.asm().mov(rsi, qword_ptr(rsi)).unwrap();
+        b.asm().call(qword_ptr(fputs)).unwrap();
+        b.asm().mov(edi, 7).unwrap();
+        b.asm().call(qword_ptr(exit)).unwrap();
+        b.rodata("msg", b"data\n\0").unwrap();
+        // importing again gives the same entry
+        assert_eq!(stdout, b.import_data("libc.so.6", "stdout").unwrap());
+        assert!(matches!(
+            b.import("libc.so.6", "stdout"),
+            Err(BuildError::ImportKind(name)) if name == "stdout"
+        ));
+        // but the same name from another library is a separate import
+        let mut other = ElfBuilder::new();
+        let ours = other.import_data("libc.so.6", "stdout").unwrap();
+        assert_ne!(ours, other.import("libfoo.so", "stdout").unwrap());
+        let image = b.link().unwrap();
+
+        let elf = crate::parse(&image.bytes).unwrap();
+        let stdout = &elf.dynamic_symbols[1];
+        assert_eq!(
+            ("stdout", STT_OBJECT),
+            (stdout.name.as_str(), stdout.sym._type)
+        );
+        // one DT_NEEDED entry for the one library
+        assert_eq!(
+            1,
+            image
+                .bytes
+                .windows(10)
+                .filter(|w| w == b"libc.so.6\0")
+                .count()
+        );
+        assert!(elf.section(".rela.dyn").is_some());
+
+        let out = output("import_data", &image.bytes);
+        assert_eq!(b"data\n", &out.stdout[..]);
+        assert_eq!(7, exit_code(&out.status));
+    }
+
+    // The (name, type, descriptor) of each note in the PT_NOTE segment.
+    fn parse_notes(bytes: &[u8]) -> Vec<(String, u32, Vec<u8>)> {
+        let elf = crate::parse(bytes).unwrap();
+        let note = elf.segments.iter().find(|p| p._type == PT_NOTE).unwrap();
+        let mut rest = &bytes[note.offset as usize..][..note.filesz as usize];
+        let mut notes = vec![];
+        while !rest.is_empty() {
+            let view = elf64_nhdr::View::new(rest);
+            let namesz = view.namesz().read() as usize;
+            let descsz = view.descsz().read() as usize;
+            let _type = view._type().read();
+            let name_end = elf64_nhdr::SIZE.unwrap() + align_up(namesz as u64, 4) as usize;
+            let name = std::str::from_utf8(&rest[elf64_nhdr::SIZE.unwrap()..][..namesz - 1]);
+            let desc = rest[name_end..][..descsz].to_vec();
+            notes.push((name.unwrap().to_string(), _type, desc));
+            rest = &rest[name_end + align_up(descsz as u64, 4) as usize..];
+        }
+        notes
+    }
+
+    #[test]
+    fn test_notes() {
+        let mut b = hello_sections();
+        b.note("Vendor", 0x1234, b"abcde");
+        b.build_id(true);
+        let bytes = b.build().unwrap();
+
+        let notes = parse_notes(&bytes);
+        assert_eq!(2, notes.len());
+        assert_eq!(("Vendor".to_string(), 0x1234, b"abcde".to_vec()), notes[0]);
+        let (name, _type, id) = &notes[1];
+        assert_eq!(("GNU", NT_GNU_BUILD_ID, 20), (&name[..], *_type, id.len()));
+
+        // the ID is the hash of the file without it
+        let start = bytes.windows(20).position(|w| w == id).unwrap();
+        let mut zeroed = bytes.clone();
+        zeroed[start..][..20].fill(0);
+        assert_eq!(&crate::sha1::sha1(&zeroed)[..], &id[..]);
+
+        // the same input gives the same ID, and a different one another
+        let mut b = hello_sections();
+        b.note("Vendor", 0x1234, b"abcde");
+        b.build_id(true);
+        assert_eq!(bytes, b.build().unwrap());
+        let mut b = hello_sections();
+        b.note("Vendor", 0x1234, b"abcdf");
+        b.build_id(true);
+        assert_ne!(id, &parse_notes(&b.build().unwrap())[1].2);
+
+        let elf = crate::parse(&bytes).unwrap();
+        let note = elf.section(".note").unwrap();
+        assert_eq!((SHT_NOTE, 28), (note.header._type, note.header.size));
+        let build_id = elf.section(".note.gnu.build-id").unwrap();
+        assert_eq!(36, build_id.header.size);
+        assert_eq!(b"hello\n", &output("notes", &bytes).stdout[..]);
+
+        let path = crate::test_util::path("notes");
+        std::fs::write(&path, &bytes).unwrap();
+        let out = std::process::Command::new("readelf")
+            .arg("-n")
+            .arg(&path)
+            .output();
+        std::fs::remove_file(&path).unwrap();
+        let Ok(out) = out else {
+            // readelf is not installed
+            return;
+        };
+        let hex: String = id.iter().map(|b| format!("{b:02x}")).collect();
+        let out = String::from_utf8(out.stdout).unwrap();
+        assert!(out.contains(&format!("Build ID: {hex}")), "{out}");
+    }
+
+    #[test]
+    fn test_build_id_only() {
+        // notes alone load the headers, with no sections
+        let mut b = ElfBuilder::new();
+        exit_stub(b.asm());
+        b.build_id(true);
+        let bytes = b.build().unwrap();
+        assert_eq!(0, elf64_hdr::View::new(&bytes[..]).shnum().read());
+        assert_eq!(1, parse_notes(&bytes).len());
+        assert_eq!(0, exit_code(&output("build_id", &bytes).status));
+    }
+
+    // Copy "tls\n" from the initialization image to the zero-filled part of
+    // the TLS block, and print the copy through the pointer at fs:0.
+    fn tls_hello(pie: bool) -> ElfBuilder {
+        let mut b = ElfBuilder::new();
+        b.pie(pie);
+        b.tls(b"tls\n", 12, 16);
+        if pie {
+            b.relocate(LoadBase::Rip).unwrap();
+        }
+        b.setup_tls().unwrap();
+        let (init, copy, zero) = (b.tls_offset(0), b.tls_offset(4), b.tls_offset(8));
+        let a = b.asm();
+        a.mov(eax, dword_ptr(init).fs()).unwrap();
+        a.mov(dword_ptr(copy).fs(), eax).unwrap();
+        a.mov(rsi, qword_ptr(0).fs()).unwrap();
+        a.add(rsi, copy).unwrap();
+        write_stdout(a, 4);
+        a.movzx(edi, byte_ptr(zero).fs()).unwrap();
+        a.add(edi, 42).unwrap();
+        a.mov(eax, 60).unwrap();
+        a.syscall().unwrap();
+        b
+    }
+
+    #[test]
+    fn test_tls() {
+        let b = tls_hello(false);
+        assert_eq!(-16, b.tls_offset(0));
+        let bytes = b.build().unwrap();
+        let elf = crate::parse(&bytes).unwrap();
+        let tls = elf.segments.iter().find(|p| p._type == PT_TLS).unwrap();
+        assert_eq!((4, 16, 16), (tls.filesz, tls.memsz, tls.align));
+        assert_eq!(0, tls.offset % 16);
+        assert_eq!(b"tls\n", &bytes[tls.offset as usize..][..4]);
+
+        let out = output("tls", &bytes);
+        assert_eq!(b"tls\n", &out.stdout[..]);
+        assert_eq!(42, exit_code(&out.status));
+    }
+
+    #[test]
+    fn test_tls_pie() {
+        let out = output("tls_pie", &tls_hello(true).build().unwrap());
+        assert_eq!(b"tls\n", &out.stdout[..]);
+        assert_eq!(42, exit_code(&out.status));
+    }
+
+    #[test]
+    fn test_tls_sections() {
+        let mut b = tls_hello(false);
+        b.section_headers(true);
+        let bytes = b.build().unwrap();
+        let elf = crate::parse(&bytes).unwrap();
+        let tdata = &elf.section(".tdata").unwrap().header;
+        let tbss = &elf.section(".tbss").unwrap().header;
+        assert_eq!((SHT_PROGBITS, 4), (tdata._type, tdata.size));
+        assert_eq!((SHT_NOBITS, 12), (tbss._type, tbss.size));
+        assert_ne!(0, tdata.flags & tbss.flags & SHF_TLS);
+        assert_eq!(tdata.addr + 4, tbss.addr);
+        assert_eq!(b"tls\n", &output("tls_sections", &bytes).stdout[..]);
+    }
+
+    #[test]
+    fn test_tls_errors() {
+        let mut b = ElfBuilder::new();
+        b.setup_tls().unwrap();
+        exit_stub(b.asm());
+        assert!(matches!(b.build(), Err(BuildError::Layout(_))));
+        b.tls(&[], 8, 3);
+        assert!(matches!(b.build(), Err(BuildError::Layout(_))));
+        b.tls(&[], 8, 8);
+        b.build().unwrap();
+        b.import("libc.so.6", "exit").unwrap();
+        assert!(matches!(b.build(), Err(BuildError::Layout(_))));
+    }
+}
@@ -0,0 +1,410 @@
+//! Tables for the dynamic linker: the dynamic symbol table with its hash and
+//! string tables, the relocations, and the `PT_DYNAMIC` array pointing at
+//! them, followed by the GOT and the path of the dynamic linker itself.
+
+use crate::sections::SectionTable;
+use crate::{
+    align_up, elf64_dyn, elf64_rela, elf64_sym, set_elf64_dyn, set_elf64_rela, set_elf64_sym, Rela,
+    Shdr, Sym, DT_BIND_NOW, DT_HASH, DT_JMPREL, DT_NEEDED, DT_NULL, DT_PLTREL, DT_PLTRELSZ,
+    DT_RELA, DT_RELAENT, DT_RELASZ, DT_STRSZ, DT_STRTAB, DT_SYMENT, DT_SYMTAB, R_X86_64_GLOB_DAT,
+    R_X86_64_JUMP_SLOT, SHF_ALLOC, SHF_INFO_LINK, SHF_WRITE, SHN_UNDEF, SHT_DYNAMIC, SHT_DYNSYM,
+    SHT_HASH, SHT_PROGBITS, SHT_RELA, SHT_STRTAB, STB_GLOBAL, STT_FUNC, STT_OBJECT,
+};
+
+/// The dynamic linking tables, laid out one after the other: `.hash`,
+/// `.dynsym`, `.dynstr`, `.rela.dyn`, `.rela.plt`, `.dynamic`, `.got` and
+/// `.interp`.
+pub(crate) struct DynamicTables {
+    // the string table, which starts with the empty name
+    names: Vec<u8>,
+    // offset in the string table of each symbol's name, after the null
+    // symbol: the exported symbols and then the imported ones
+    symbols: Vec<u32>,
+    exports: usize,
+    // whether each import is a function, bound through a jump slot, rather
+    // than data
+    functions: Vec<bool>,
+    // offset in the string table of each needed library's name
+    needed: Vec<u32>,
+    // the number of R_X86_64_RELATIVE relocations
+    relocations: usize,
+    // the path of the dynamic linker, with its terminating 0
+    interpreter: Vec<u8>,
+}
+
+// Offsets of the tables from the start of the first, the hash table.
+struct Offsets {
+    symtab: u64,
+    strtab: u64,
+    rela: u64,
+    jmprel: u64,
+    dynamic: u64,
+}
+
+/// The System V hash of a symbol name, as used in `DT_HASH` tables.
+fn hash(name: &[u8]) -> u32 {
+    let mut h: u32 = 0;
+    for &c in name {
+        h = (h << 4).wrapping_add(c as u32);
+        let g = h & 0xf000_0000;
+        h ^= g >> 24;
+        h &= !g;
+    }
+    h
+}
+
+impl DynamicTables {
+    /// Tables for the exported symbols `exports`, in that order, and
+    /// `relocations` relative relocations.
+    pub fn new<'a>(exports: impl IntoIterator<Item = &'a str>, relocations: usize) -> Self {
+        let mut tables = DynamicTables {
+            names: vec![0],
+            symbols: vec![],
+            exports: 0,
+            functions: vec![],
+            needed: vec![],
+            relocations,
+            interpreter: vec![],
+        };
+        for name in exports {
+            let name = tables.name(name);
+            tables.symbols.push(name);
+        }
+        tables.exports = tables.symbols.len();
+        tables
+    }
+
+    fn name(&mut self, name: &str) -> u32 {
+        let offset = self.names.len() as u32;
+        self.names.extend_from_slice(name.as_bytes());
+        self.names.push(0);
+        offset
+    }
+
+    /// Add a `DT_NEEDED` entry for `library`.
+    pub fn need(&mut self, library: &str) {
+        let name = self.name(library);
+        self.needed.push(name);
+    }
+
+    /// Import the symbol `name`, which gets the next GOT entry.
+    pub fn import(&mut self, name: &str, function: bool) {
+        let name = self.name(name);
+        self.symbols.push(name);
+        self.functions.push(function);
+    }
+
+    /// Name `path` as the dynamic linker, in a `PT_INTERP` segment.
+    pub fn interpreter(&mut self, path: &str) {
+        self.interpreter = path.as_bytes().to_vec();
+        self.interpreter.push(0);
+    }
+
+    // one bucket per symbol keeps the chains short
+    fn nbucket(&self) -> usize {
+        self.symbols.len().max(1)
+    }
+
+    // the number of symbol table entries, including the null symbol
+    fn nsyms(&self) -> usize {
+        1 + self.symbols.len()
+    }
+
+    fn imports(&self) -> usize {
+        self.functions.len()
+    }
+
+    // the relocations in DT_RELA and DT_JMPREL
+    fn rela_count(&self) -> usize {
+        self.relocations + self.functions.iter().filter(|&&f| !f).count()
+    }
+
+    fn jmprel_count(&self) -> usize {
+        self.functions.iter().filter(|&&f| f).count()
+    }
+
+    fn offsets(&self) -> Offsets {
+        let rela_size = elf64_rela::SIZE.unwrap();
+        let hash_size = 4 * (2 + self.nbucket() + self.nsyms()) as u64;
+        let symtab = align_up(hash_size, 8);
+        let strtab = symtab + (self.nsyms() * elf64_sym::SIZE.unwrap()) as u64;
+        let rela = align_up(strtab + self.names.len() as u64, 8);
+        let jmprel = rela + (self.rela_count() * rela_size) as u64;
+        let dynamic = jmprel + (self.jmprel_count() * rela_size) as u64;
+        Offsets {
+            symtab,
+            strtab,
+            rela,
+            jmprel,
+            dynamic,
+        }
+    }
+
+    // The entries of the dynamic array, if the tables are loaded at `addr`.
+    fn entries(&self, addr: u64) -> Vec<(i64, u64)> {
+        let offsets = self.offsets();
+        let mut entries: Vec<(i64, u64)> = (self.needed.iter())
+            .map(|&name| (DT_NEEDED, name as u64))
+            .collect();
+        entries.extend([
+            (DT_HASH, addr),
+            (DT_STRTAB, addr + offsets.strtab),
+            (DT_SYMTAB, addr + offsets.symtab),
+            (DT_STRSZ, self.names.len() as u64),
+            (DT_SYMENT, elf64_sym::SIZE.unwrap() as u64),
+        ]);
+        let entsize = elf64_rela::SIZE.unwrap() as u64;
+        if self.rela_count() > 0 {
+            entries.push((DT_RELA, addr + offsets.rela));
+            entries.push((DT_RELASZ, self.rela_count() as u64 * entsize));
+            entries.push((DT_RELAENT, entsize));
+        }
+        if self.jmprel_count() > 0 {
+            entries.push((DT_JMPREL, addr + offsets.jmprel));
+            entries.push((DT_PLTRELSZ, self.jmprel_count() as u64 * entsize));
+            entries.push((DT_PLTREL, DT_RELA as u64));
+            // lazy binding would need a PLT to call into the dynamic linker
+            entries.push((DT_BIND_NOW, 0));
+        }
+        entries.push((DT_NULL, 0));
+        entries
+    }
+
+    /// The offset and size of the `PT_DYNAMIC` array from the start of the
+    /// tables.
+    pub fn dynamic(&self) -> (u64, u64) {
+        let size = self.entries(0).len() * elf64_dyn::SIZE.unwrap();
+        (self.offsets().dynamic, size as u64)
+    }
+
+    /// The offset of the GOT, which has an 8-byte entry for each import in
+    /// the order they were added.
+    pub fn got(&self) -> u64 {
+        let (start, size) = self.dynamic();
+        start + size
+    }
+
+    /// The offset and size of the dynamic linker's path, if there is one.
+    pub fn interp(&self) -> Option<(u64, u64)> {
+        let start = self.got() + 8 * self.imports() as u64;
+        (!self.interpreter.is_empty()).then_some((start, self.interpreter.len() as u64))
+    }
+
+    /// The size of all the tables, which must start 8-byte aligned.
+    pub fn size(&self) -> usize {
+        let got_end = self.got() + 8 * self.imports() as u64;
+        self.interp().map_or(got_end, |(start, size)| start + size) as usize
+    }
+
+    /// Write the tables to the start of `bytes`, for loading at `addr`.
+    ///
+    /// `syms` are the exported symbols in the order their names were given,
+    /// and `relas` the relative relocations.
+    pub fn write(&self, bytes: &mut [u8], addr: u64, syms: &[Sym], relas: &[Rela]) {
+        assert_eq!(self.exports, syms.len());
+        assert_eq!(self.relocations, relas.len());
+        let offsets = self.offsets();
+        let word = |bytes: &mut [u8], i: usize, value: u32| {
+            bytes[4 * i..][..4].copy_from_slice(&value.to_le_bytes())
+        };
+
+        // the hash table: nbucket, nchain, the buckets and then the chains,
+        // where each bucket and chain entry is the index of the next symbol
+        // with the same hash modulo nbucket, or 0
+        let nbucket = self.nbucket();
+        word(bytes, 0, nbucket as u32);
+        word(bytes, 1, self.nsyms() as u32);
+        for (i, &name) in self.symbols.iter().enumerate() {
+            let index = i + 1;
+            let name = &self.names[name as usize..];
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap()];
+            let bucket = 2 + hash(name) as usize % nbucket;
+            let head = u32::from_le_bytes(bytes[4 * bucket..][..4].try_into().unwrap());
+            word(bytes, 2 + nbucket + index, head);
+            word(bytes, bucket, index as u32);
+        }
+
+        let imports = self.functions.iter().map(|&function| Sym {
+            bind: STB_GLOBAL,
+            _type: if function { STT_FUNC } else { STT_OBJECT },
+            shndx: SHN_UNDEF,
+            ..Sym::default()
+        });
+        let entsize = elf64_sym::SIZE.unwrap();
+        let symtab = offsets.symtab as usize;
+        let all_syms = syms.iter().copied().chain(imports);
+        for (i, (&name, sym)) in self.symbols.iter().zip(all_syms).enumerate() {
+            let view = elf64_sym::View::new(&mut bytes[symtab + (i + 1) * entsize..]);
+            set_elf64_sym(view, name, &sym);
+        }
+        let strtab = offsets.strtab as usize;
+        bytes[strtab..strtab + self.names.len()].copy_from_slice(&self.names);
+
+        // each GOT entry gets the address of its symbol
+        let got = addr + self.got();
+        let (mut rela, mut jmprel) = (relas.to_vec(), vec![]);
+        for (i, &function) in self.functions.iter().enumerate() {
+            let entry = Rela {
+                offset: got + 8 * i as u64,
+                symbol: (1 + self.exports + i) as u32,
+                _type: if function {
+                    R_X86_64_JUMP_SLOT
+                } else {
+                    R_X86_64_GLOB_DAT
+                },
+                addend: 0,
+            };
+            if function {
+                jmprel.push(entry);
+            } else {
+                rela.push(entry);
+            }
+        }
+        let entsize = elf64_rela::SIZE.unwrap();
+        let table = rela.iter().chain(&jmprel);
+        for (i, rela) in table.enumerate() {
+            let view = elf64_rela::View::new(&mut bytes[offsets.rela as usize + i * entsize..]);
+            set_elf64_rela(view, rela);
+        }
+
+        let entsize = elf64_dyn::SIZE.unwrap();
+        for (i, &(tag, val)) in self.entries(addr).iter().enumerate() {
+            let view = elf64_dyn::View::new(&mut bytes[offsets.dynamic as usize + i * entsize..]);
+            set_elf64_dyn(view, tag, val);
+        }
+
+        if let Some((start, size)) = self.interp() {
+            bytes[start as usize..(start + size) as usize].copy_from_slice(&self.interpreter);
+        }
+    }
+
+    /// Describe the tables, loaded at `addr` from file offset `offset`, with
+    /// sections.
+    pub fn add_sections(&self, sections: &mut SectionTable, addr: u64, offset: u64) {
+        let offsets = self.offsets();
+        let section = |_type, start: u64, end: u64, addralign, entsize| Shdr {
+            _type,
+            flags: SHF_ALLOC,
+            addr: addr + start,
+            offset: offset + start,
+            size: end - start,
+            addralign,
+            entsize,
+            ..Shdr::default()
+        };
+        if let Some((start, size)) = self.interp() {
+            sections.add(".interp", section(SHT_PROGBITS, start, start + size, 1, 0));
+        }
+        let strtab = offsets.strtab + self.names.len() as u64;
+        let dynstr = sections.add(".dynstr", section(SHT_STRTAB, offsets.strtab, strtab, 1, 0));
+        let sym_size = elf64_sym::SIZE.unwrap() as u64;
+        let dynsym = Shdr {
+            link: dynstr as u32,
+            // all dynamic symbols are global
+            info: 1,
+            ..section(SHT_DYNSYM, offsets.symtab, offsets.strtab, 8, sym_size)
+        };
+        let dynsym = sections.add(".dynsym", dynsym);
+        let hash_end = 4 * (2 + self.nbucket() + self.nsyms()) as u64;
+        let hash = Shdr {
+            link: dynsym as u32,
+            ..section(SHT_HASH, 0, hash_end, 8, 4)
+        };
+        sections.add(".hash", hash);
+        let rela_size = elf64_rela::SIZE.unwrap() as u64;
+        if self.rela_count() > 0 {
+            let rela = Shdr {
+                link: dynsym as u32,
+                ..section(SHT_RELA, offsets.rela, offsets.jmprel, 8, rela_size)
+            };
+            sections.add(".rela.dyn", rela);
+        }
+        let (start, size) = self.dynamic();
+        let dynamic = Shdr {
+            link: dynstr as u32,
+            flags: SHF_ALLOC | SHF_WRITE,
+            ..section(
+                SHT_DYNAMIC,
+                start,
+                start + size,
+                8,
+                elf64_dyn::SIZE.unwrap() as u64,
+            )
+        };
+        sections.add(".dynamic", dynamic);
+        if self.imports() > 0 {
+            let got_end = self.got() + 8 * self.imports() as u64;
+            let got = Shdr {
+                flags: SHF_ALLOC | SHF_WRITE,
+                ..section(SHT_PROGBITS, self.got(), got_end, 8, 8)
+            };
+            let got = sections.add(".got", got);
+            if self.jmprel_count() > 0 {
+                let rela = Shdr {
+                    flags: SHF_ALLOC | SHF_INFO_LINK,
+                    link: dynsym as u32,
+                    // the section the relocations apply to
+                    info: got as u32,
+                    ..section(SHT_RELA, offsets.jmprel, offsets.dynamic, 8, rela_size)
+                };
+                sections.add(".rela.plt", rela);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash, DynamicTables};
+    use crate::{elf64_dyn, elf64_sym, Sym, DT_HASH, DT_NULL, DT_STRSZ, DT_SYMTAB};
+
+    #[test]
+    fn test_hash() {
+        assert_eq!(0, hash(b""));
+        assert_eq!(0x0006cf04, hash(b"exit"));
+        assert_eq!(0x077905a6, hash(b"printf"));
+        // long enough for the top nibble to be folded back in
+        assert_eq!(0x0177ff8e, hash(b"__libc_start_main"));
+    }
+
+    #[test]
+    fn test_tables() {
+        let names = ["alpha", "beta", "gamma", "delta", "epsilon"];
+        let tables = DynamicTables::new(names, 0);
+        let syms: Vec<Sym> = (1..=names.len())
+            .map(|value| Sym {
+                value: value as u64,
+                ..Sym::default()
+            })
+            .collect();
+        let mut bytes = vec![0; tables.size()];
+        tables.write(&mut bytes, 0x1000, &syms, &[]);
+
+        let (start, size) = tables.dynamic();
+        let entries: Vec<(i64, u64)> = (0..size as usize / 16)
+            .map(|i| {
+                let view = elf64_dyn::View::new(&bytes[start as usize + 16 * i..]);
+                (view.tag().read(), view.val().read())
+            })
+            .collect();
+        assert_eq!((DT_HASH, 0x1000), entries[0]);
+        assert_eq!((DT_NULL, 0), *entries.last().unwrap());
+        let strsz = entries.iter().find(|e| e.0 == DT_STRSZ).unwrap().1;
+        assert_eq!(1 + 6 + 5 + 6 + 6 + 8, strsz);
+        let symtab = entries.iter().find(|e| e.0 == DT_SYMTAB).unwrap().1 - 0x1000;
+
+        // every name is found through its bucket and chain
+        let word = |i: usize| u32::from_le_bytes(bytes[4 * i..][..4].try_into().unwrap()) as usize;
+        let nbucket = word(0);
+        assert_eq!(1 + names.len(), word(1));
+        for (i, name) in names.iter().enumerate() {
+            let mut index = word(2 + hash(name.as_bytes()) as usize % nbucket);
+            while index != 0 && index != i + 1 {
+                index = word(2 + nbucket + index);
+            }
+            assert_eq!(i + 1, index, "{name}");
+            let sym = elf64_sym::View::new(&bytes[symtab as usize + index * 24..]);
+            assert_eq!(index as u64, sym.value().read());
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Minimal 32-bit (i386) executables, as in the original teensy ELF article.
+//!
+//! The ELF-32 structures have the same fields as their 64-bit counterparts,
+//! but addresses and offsets are 4 bytes and the program header fields are in
+//! a different order. Linux runs these binaries on x86-64 kernels built with
+//! IA32 emulation, using the `int 0x80` syscall interface.
+
+use crate::{elf64_ident, set_ident, write_executable, Format, PF_R, PF_W, PF_X, PT_LOAD};
+use binary_layout::prelude::*;
+use iced_x86::code_asm::CodeAssembler;
+use iced_x86::IcedError;
+use std::path::Path;
+
+type Elf32_Addr = u32;
+type Elf32_Off = u32;
+type Elf32_Half = u16;
+type Elf32_Word = u32;
+
+define_layout!(elf32_hdr, LittleEndian, {
+    ident: elf64_ident::NestedView, // the identification bytes are the same
+    _type: Elf32_Half,
+    machine: Elf32_Half,
+    version: Elf32_Word,
+    entry: Elf32_Addr, // virtual address of entry point
+    phoff: Elf32_Off, // program header
+    shoff: Elf32_Off, // section header
+    flags: Elf32_Word, // processor-specific
+    ehsize: Elf32_Half,
+    phentsize: Elf32_Half,
+    phnum: Elf32_Half, // number of program header entries
+    shentsize: Elf32_Half, // size of section header entry
+    shnum: Elf32_Half, // number of section header entries
+    shstrndx: Elf32_Half, // section name string table index
+});
+
+// unlike ELF-64, p_flags comes after the sizes
+define_layout!(elf32_phdr, LittleEndian, {
+    _type: Elf32_Word,
+    offset: Elf32_Off,
+    vaddr: Elf32_Addr,
+    paddr: Elf32_Addr,
+    filesz: Elf32_Word,
+    memsz: Elf32_Word,
+    flags: Elf32_Word,
+    align: Elf32_Word,
+});
+
+define_layout!(elf32_file, LittleEndian, {
+    hdr: elf32_hdr::NestedView,
+    phdr: elf32_phdr::NestedView,
+    program: [u8],
+});
+
+const PROGRAM_OFFSET32: u32 = {
+    let sz1 = match elf32_hdr::SIZE {
+        Some(s) => s,
+        None => panic!("unsized"),
+    };
+    let sz2 = match elf32_phdr::SIZE {
+        Some(s) => s,
+        None => panic!("unsized"),
+    };
+    (sz1 + sz2) as u32
+};
+
+/// The traditional load address for i386 executables.
+pub const VADDR32: u32 = 0x08048000;
+
+/// Virtual address where a 32-bit program is loaded, which is also the entry
+/// point (see [`elf32_from_asm`]).
+pub const PROGRAM_VADDR32: u32 = VADDR32 + PROGRAM_OFFSET32;
+
+/// Wrap `program` (raw i386 machine code) in a minimal 32-bit ELF executable.
+///
+/// The layout is the same as [`elf_bytes`](crate::elf_bytes), with the code
+/// loaded right after the headers.
+pub fn elf32_bytes(program: &[u8]) -> Vec<u8> {
+    let hdr_sz = elf32_hdr::SIZE.unwrap();
+    let phdr_sz = elf32_phdr::SIZE.unwrap();
+    let mut buf = vec![0u8; hdr_sz + phdr_sz + program.len()];
+    let mut file = elf32_file::View::new(&mut buf);
+
+    let mut hdr = file.hdr_mut();
+    set_ident(hdr.ident_mut(), Format::ELF32_LSB);
+    hdr._type_mut().write(2); // ET_EXEC
+    hdr.machine_mut().write(3); // EM_386
+    hdr.version_mut().write(1); // EV_CURRENT
+    hdr.entry_mut().write(PROGRAM_VADDR32);
+    hdr.phoff_mut().write(hdr_sz as u32);
+    hdr.ehsize_mut().write(hdr_sz as u16);
+    hdr.phentsize_mut().write(phdr_sz as u16);
+    hdr.phnum_mut().write(1);
+
+    let mut phdr = file.phdr_mut();
+    phdr._type_mut().write(PT_LOAD);
+    phdr.offset_mut().write(PROGRAM_OFFSET32);
+    phdr.vaddr_mut().write(PROGRAM_VADDR32);
+    phdr.filesz_mut().write(program.len() as u32);
+    phdr.memsz_mut().write(program.len() as u32);
+    phdr.flags_mut().write(PF_X | PF_W | PF_R);
+    phdr.align_mut().write(4096);
+
+    file.program_mut().copy_from_slice(program);
+    buf
+}
+
+/// Assemble `a` (created with `CodeAssembler::new(32)`) at
+/// [`PROGRAM_VADDR32`] and wrap the result in a minimal 32-bit ELF
+/// executable.
+pub fn elf32_from_asm(a: &mut CodeAssembler) -> Result<Vec<u8>, IcedError> {
+    Ok(elf32_bytes(&a.assemble(PROGRAM_VADDR32 as u64)?))
+}
+
+fn create_program32() -> Vec<u8> {
+    use iced_x86::code_asm::*;
+    let f = || -> Result<_, IcedError> {
+        let mut a = CodeAssembler::new(32)?;
+        // exit is syscall 1 for int 0x80, and inc is a single byte in 32-bit
+        // mode
+        a.xor(eax, eax)?;
+        a.inc(eax)?;
+        a.xor(ebx, ebx)?;
+        a.int(0x80)?;
+        let bytes = a.assemble(PROGRAM_VADDR32 as u64)?;
+        Ok(bytes)
+    };
+    f().unwrap()
+}
+
+/// Write the minimal 32-bit ELF file, which immediately calls `exit(0)`.
+pub fn write_elf32<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
+    write_executable(path, &elf32_bytes(&create_program32()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create_program32, elf32_bytes, elf32_from_asm, elf32_hdr, elf32_phdr};
+    use super::{write_elf32, PROGRAM_VADDR32};
+    use crate::test_util::{exit_code, path, run, run_path};
+    use crate::{create_program, elf_bytes};
+    use iced_x86::code_asm::*;
+
+    #[test]
+    fn layout_sizes_ok() {
+        assert_eq!(52, elf32_hdr::SIZE.unwrap());
+        assert_eq!(32, elf32_phdr::SIZE.unwrap());
+    }
+
+    #[test]
+    fn test_tiny32_size() {
+        let bytes = elf32_bytes(&create_program32());
+        assert_eq!(7, create_program32().len());
+        assert_eq!(52 + 32 + 7, bytes.len());
+        // 36 bytes smaller than the 64-bit version, all from the headers
+        let bytes64 = elf_bytes(&create_program());
+        assert_eq!(36, bytes64.len() - bytes.len());
+    }
+
+    #[test]
+    fn test_tiny32_header() {
+        let bytes = elf32_bytes(&create_program32());
+        assert_eq!(&[0x7f, b'E', b'L', b'F', 1, 1, 1], &bytes[..7]);
+        let hdr = elf32_hdr::View::new(&bytes[..]);
+        assert_eq!(3, hdr.machine().read());
+        assert_eq!(PROGRAM_VADDR32, hdr.entry().read());
+        let phdr = elf32_phdr::View::new(&bytes[52..]);
+        assert_eq!(84, phdr.offset().read());
+        assert_eq!(7, phdr.filesz().read());
+    }
+
+    #[test]
+    fn test_run_tiny32() {
+        let path = path("tiny32");
+        write_elf32(&path).unwrap();
+        assert_eq!(0, exit_code(&run_path(&path)));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_exit_code32() {
+        let mut a = CodeAssembler::new(32).unwrap();
+        a.xor(eax, eax).unwrap();
+        a.inc(eax).unwrap();
+        a.mov(bl, 42).unwrap();
+        a.int(0x80).unwrap();
+        let bytes = elf32_from_asm(&mut a).unwrap();
+        assert_eq!(42, exit_code(&run("exit_code32", &bytes)));
+    }
+
+    #[test]
+    fn test_write32() {
+        // write(1, msg, len); there is no RIP-relative addressing, so find
+        // msg from the return address of a call over it
+        let mut a = CodeAssembler::new(32).unwrap();
+        let mut after = a.create_label();
+        a.call(after).unwrap();
+        a.db(b"hi\n").unwrap();
+        a.set_label(&mut after).unwrap();
+        a.pop(ecx).unwrap();
+        a.mov(eax, 4).unwrap();
+        a.mov(ebx, 1).unwrap();
+        a.mov(edx, 3).unwrap();
+        a.int(0x80).unwrap();
+        a.mov(eax, 1).unwrap();
+        a.xor(ebx, ebx).unwrap();
+        a.int(0x80).unwrap();
+        let bytes = elf32_from_asm(&mut a).unwrap();
+        let out = crate::test_util::output("write32", &bytes);
+        assert_eq!(b"hi\n", &out.stdout[..]);
+    }
+}
@@ -0,0 +1,487 @@
+//! Write and read the ELF and program headers for any class and data
+//! encoding.
+//!
+//! The `elf64_*` and `elf32_*` layouts fix both the word size and the byte
+//! order, so they only describe little-endian x86 files. Here the fields are
+//! written in order with the sizes for the chosen [`Class`] and the byte
+//! order for the chosen [`Encoding`], which covers targets like s390x
+//! (64-bit big-endian) and MIPS (32-bit big-endian) as well.
+//!
+//! [`ElfBuilder::format`](crate::ElfBuilder::format) writes an executable's
+//! headers in another format. The section headers, symbol tables and dynamic
+//! linking tables still use the `elf64_*` layouts, so only ELF-64
+//! little-endian files can have them.
+
+use crate::elf32::{elf32_hdr, elf32_phdr};
+use crate::{
+    elf64_hdr, elf64_ident, elf64_phdr, set_ident, Header, Phdr, PF_R, PF_W, PF_X, PT_LOAD,
+};
+use std::fmt;
+
+/// The word size of an ELF file (`EI_CLASS`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    Elf32 = 1,
+    Elf64 = 2,
+}
+
+/// The byte order of an ELF file (`EI_DATA`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// Little-endian
+    Lsb = 1,
+    /// Big-endian
+    Msb = 2,
+}
+
+/// A combination of class and data encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Format {
+    pub class: Class,
+    pub encoding: Encoding,
+}
+
+/// An error from writing headers in a [`Format`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormatError {
+    /// An address, offset or size does not fit in an ELF-32 field.
+    TooLarge { field: &'static str, value: u64 },
+    /// Something other than the headers, such as section headers, was
+    /// requested, but is only written as ELF-64 little-endian.
+    Elf64Only(&'static str),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::TooLarge { field, value } => {
+                write!(f, "{field} {value:#x} does not fit in an ELF-32 file")
+            }
+            FormatError::Elf64Only(what) => {
+                write!(f, "{what} only exist in ELF-64 little-endian files")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+// Reads or writes consecutive header fields.
+struct Cursor<B> {
+    format: Format,
+    buf: B,
+    pos: usize,
+}
+
+impl<B: AsMut<[u8]>> Cursor<B> {
+    fn put<const N: usize>(&mut self, le: [u8; N], be: [u8; N]) {
+        let bytes = match self.format.encoding {
+            Encoding::Lsb => le,
+            Encoding::Msb => be,
+        };
+        self.buf.as_mut()[self.pos..self.pos + N].copy_from_slice(&bytes);
+        self.pos += N;
+    }
+
+    fn half(&mut self, v: u16) {
+        self.put(v.to_le_bytes(), v.to_be_bytes());
+    }
+
+    fn word(&mut self, v: u32) {
+        self.put(v.to_le_bytes(), v.to_be_bytes());
+    }
+
+    // An address, offset or size, which is a word in ELF-32 and a double word
+    // in ELF-64.
+    fn sized(&mut self, field: &'static str, v: u64) -> Result<(), FormatError> {
+        match self.format.class {
+            Class::Elf32 => {
+                let v = u32::try_from(v).map_err(|_| FormatError::TooLarge { field, value: v })?;
+                self.word(v)
+            }
+            Class::Elf64 => self.put(v.to_le_bytes(), v.to_be_bytes()),
+        }
+        Ok(())
+    }
+}
+
+impl<B: AsRef<[u8]>> Cursor<B> {
+    fn get<const N: usize>(&mut self) -> [u8; N] {
+        let bytes = self.buf.as_ref()[self.pos..self.pos + N]
+            .try_into()
+            .unwrap();
+        self.pos += N;
+        bytes
+    }
+
+    fn read_half(&mut self) -> u16 {
+        let bytes = self.get();
+        match self.format.encoding {
+            Encoding::Lsb => u16::from_le_bytes(bytes),
+            Encoding::Msb => u16::from_be_bytes(bytes),
+        }
+    }
+
+    fn read_word(&mut self) -> u32 {
+        let bytes = self.get();
+        match self.format.encoding {
+            Encoding::Lsb => u32::from_le_bytes(bytes),
+            Encoding::Msb => u32::from_be_bytes(bytes),
+        }
+    }
+
+    fn read_sized(&mut self) -> u64 {
+        match self.format.class {
+            Class::Elf32 => self.read_word() as u64,
+            Class::Elf64 => {
+                let bytes = self.get();
+                match self.format.encoding {
+                    Encoding::Lsb => u64::from_le_bytes(bytes),
+                    Encoding::Msb => u64::from_be_bytes(bytes),
+                }
+            }
+        }
+    }
+}
+
+impl Format {
+    /// The format of x86-64 files, which everything else in this crate writes.
+    pub const ELF64_LSB: Format = Format::new(Class::Elf64, Encoding::Lsb);
+    /// The format of i386 files.
+    pub const ELF32_LSB: Format = Format::new(Class::Elf32, Encoding::Lsb);
+    /// The format of s390x files, for example.
+    pub const ELF64_MSB: Format = Format::new(Class::Elf64, Encoding::Msb);
+    /// The format of big-endian MIPS files, for example.
+    pub const ELF32_MSB: Format = Format::new(Class::Elf32, Encoding::Msb);
+
+    pub const fn new(class: Class, encoding: Encoding) -> Self {
+        Format { class, encoding }
+    }
+
+    /// The format given by `EI_CLASS` and `EI_DATA`, if valid.
+    pub fn from_ident(class: u8, data: u8) -> Option<Self> {
+        let class = match class {
+            1 => Class::Elf32,
+            2 => Class::Elf64,
+            _ => return None,
+        };
+        let encoding = match data {
+            1 => Encoding::Lsb,
+            2 => Encoding::Msb,
+            _ => return None,
+        };
+        Some(Format::new(class, encoding))
+    }
+
+    /// Size of the ELF header.
+    pub fn ehsize(&self) -> usize {
+        match self.class {
+            Class::Elf32 => elf32_hdr::SIZE.unwrap(),
+            Class::Elf64 => elf64_hdr::SIZE.unwrap(),
+        }
+    }
+
+    /// Size of a program header.
+    pub fn phentsize(&self) -> usize {
+        match self.class {
+            Class::Elf32 => elf32_phdr::SIZE.unwrap(),
+            Class::Elf64 => elf64_phdr::SIZE.unwrap(),
+        }
+    }
+
+    /// Write `header` to the start of `buf`, taking `EI_CLASS` and `EI_DATA`
+    /// from this format rather than the header.
+    ///
+    /// Fails if an address or offset does not fit in an ELF-32 file.
+    pub fn write_header(&self, buf: &mut [u8], header: &Header) -> Result<(), FormatError> {
+        set_ident(elf64_ident::View::new(&mut buf[..]), *self);
+        let mut ident = elf64_ident::View::new(&mut buf[..]);
+        ident.os_abi_mut().write(header.os_abi);
+        ident.abi_version_mut().write(header.abi_version);
+        let mut c = Cursor {
+            format: *self,
+            buf,
+            pos: elf64_ident::SIZE.unwrap(),
+        };
+        c.half(header._type);
+        c.half(header.machine);
+        c.word(header.version);
+        c.sized("e_entry", header.entry)?;
+        c.sized("e_phoff", header.phoff)?;
+        c.sized("e_shoff", header.shoff)?;
+        c.word(header.flags);
+        c.half(header.ehsize);
+        c.half(header.phentsize);
+        c.half(header.phnum);
+        c.half(header.shentsize);
+        c.half(header.shnum);
+        c.half(header.shstrndx);
+        Ok(())
+    }
+
+    /// Write `phdr` to the start of `buf`.
+    ///
+    /// Fails if an address or size does not fit in an ELF-32 file.
+    pub fn write_phdr(&self, buf: &mut [u8], phdr: &Phdr) -> Result<(), FormatError> {
+        let mut c = Cursor {
+            format: *self,
+            buf,
+            pos: 0,
+        };
+        c.word(phdr._type);
+        // ELF-64 moves p_flags up to keep the double words aligned
+        if self.class == Class::Elf64 {
+            c.word(phdr.flags);
+        }
+        c.sized("p_offset", phdr.offset)?;
+        c.sized("p_vaddr", phdr.vaddr)?;
+        c.sized("p_paddr", phdr.paddr)?;
+        c.sized("p_filesz", phdr.filesz)?;
+        c.sized("p_memsz", phdr.memsz)?;
+        if self.class == Class::Elf32 {
+            c.word(phdr.flags);
+        }
+        c.sized("p_align", phdr.align)?;
+        Ok(())
+    }
+
+    // Read the ELF header from `buf`, which must be at least `ehsize` long.
+    pub(crate) fn read_header(&self, buf: &[u8]) -> Header {
+        let ident = elf64_ident::View::new(buf);
+        let mut c = Cursor {
+            format: *self,
+            buf,
+            pos: elf64_ident::SIZE.unwrap(),
+        };
+        Header {
+            class: ident.class().read(),
+            data: ident.data().read(),
+            os_abi: ident.os_abi().read(),
+            abi_version: ident.abi_version().read(),
+            _type: c.read_half(),
+            machine: c.read_half(),
+            version: c.read_word(),
+            entry: c.read_sized(),
+            phoff: c.read_sized(),
+            shoff: c.read_sized(),
+            flags: c.read_word(),
+            ehsize: c.read_half(),
+            phentsize: c.read_half(),
+            phnum: c.read_half(),
+            shentsize: c.read_half(),
+            shnum: c.read_half(),
+            shstrndx: c.read_half(),
+        }
+    }
+
+    // Read a program header from `buf`, which must be at least `phentsize`
+    // long.
+    pub(crate) fn read_phdr(&self, buf: &[u8]) -> Phdr {
+        let mut c = Cursor {
+            format: *self,
+            buf,
+            pos: 0,
+        };
+        let mut phdr = Phdr {
+            _type: c.read_word(),
+            ..Phdr::default()
+        };
+        if self.class == Class::Elf64 {
+            phdr.flags = c.read_word();
+        }
+        phdr.offset = c.read_sized();
+        phdr.vaddr = c.read_sized();
+        phdr.paddr = c.read_sized();
+        phdr.filesz = c.read_sized();
+        phdr.memsz = c.read_sized();
+        if self.class == Class::Elf32 {
+            phdr.flags = c.read_word();
+        }
+        phdr.align = c.read_sized();
+        phdr
+    }
+
+    /// Wrap `program` in a minimal executable for `machine` in this format,
+    /// loaded at `vaddr` plus the size of the headers.
+    ///
+    /// This is the layout of [`elf_bytes`](crate::elf_bytes) and
+    /// [`elf32_bytes`](crate::elf32_bytes), but for any target. Fails if the
+    /// program doesn't fit below 4 GiB in an ELF-32 file.
+    pub fn elf_bytes(
+        &self,
+        machine: u16,
+        vaddr: u64,
+        program: &[u8],
+    ) -> Result<Vec<u8>, FormatError> {
+        let offset = self.ehsize() + self.phentsize();
+        let mut buf = vec![0u8; offset + program.len()];
+        let header = Header {
+            _type: 2, // ET_EXEC
+            machine,
+            version: 1, // EV_CURRENT
+            entry: vaddr + offset as u64,
+            phoff: self.ehsize() as u64,
+            ehsize: self.ehsize() as u16,
+            phentsize: self.phentsize() as u16,
+            phnum: 1,
+            ..Header::default()
+        };
+        self.write_header(&mut buf, &header)?;
+        let phdr = Phdr {
+            _type: PT_LOAD,
+            flags: PF_X | PF_W | PF_R,
+            offset: offset as u64,
+            vaddr: vaddr + offset as u64,
+            paddr: 0,
+            filesz: program.len() as u64,
+            memsz: program.len() as u64,
+            align: 4096,
+        };
+        self.write_phdr(&mut buf[self.ehsize()..], &phdr)?;
+        buf[offset..].copy_from_slice(program);
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Class, Encoding, Format, FormatError};
+    use crate::test_util::{exit_code, run};
+    use crate::{create_program, elf32_bytes, elf_bytes, parse_headers, Header, ParseError, Phdr};
+    use crate::{PF_R, PF_X, PT_LOAD, VADDR, VADDR32};
+
+    const FORMATS: [(Format, u16); 4] = [
+        (Format::ELF64_LSB, 62), // EM_X86_64
+        (Format::ELF32_LSB, 3),  // EM_386
+        (Format::ELF64_MSB, 22), // EM_S390
+        (Format::ELF32_MSB, 8),  // EM_MIPS
+    ];
+
+    #[test]
+    fn test_round_trip() {
+        for (format, machine) in FORMATS {
+            let header = Header {
+                class: format.class as u8,
+                data: format.encoding as u8,
+                os_abi: 3,
+                abi_version: 1,
+                _type: 2,
+                machine,
+                version: 1,
+                entry: 0x1234_5678,
+                phoff: 0x40,
+                shoff: 0x1_0000,
+                flags: 0x8000_0001,
+                ehsize: format.ehsize() as u16,
+                phentsize: format.phentsize() as u16,
+                phnum: 2,
+                shentsize: 0,
+                shnum: 0,
+                shstrndx: 0,
+            };
+            let phdrs = [
+                Phdr {
+                    _type: PT_LOAD,
+                    flags: PF_R | PF_X,
+                    offset: 0,
+                    vaddr: 0x10000,
+                    paddr: 0x20000,
+                    filesz: 0x123,
+                    memsz: 0x456,
+                    align: 0x1000,
+                },
+                Phdr {
+                    _type: 0x6474e551, // PT_GNU_STACK
+                    flags: PF_R,
+                    ..Phdr::default()
+                },
+            ];
+            let mut buf = vec![0u8; 0x40 + 2 * format.phentsize()];
+            format.write_header(&mut buf, &header).unwrap();
+            for (i, phdr) in phdrs.iter().enumerate() {
+                format
+                    .write_phdr(&mut buf[0x40 + i * format.phentsize()..], phdr)
+                    .unwrap();
+            }
+            assert_eq!(
+                Ok((header, phdrs.to_vec())),
+                parse_headers(&buf),
+                "{format:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_byte_order() {
+        let program = [0u8; 4];
+        let lsb = Format::ELF32_LSB
+            .elf_bytes(3, VADDR32 as u64, &program)
+            .unwrap();
+        let msb = Format::ELF32_MSB
+            .elf_bytes(8, VADDR32 as u64, &program)
+            .unwrap();
+        assert_eq!((1, 1), (lsb[4], lsb[5]));
+        assert_eq!((1, 2), (msb[4], msb[5]));
+        // e_machine
+        assert_eq!(&[3, 0], &lsb[18..20]);
+        assert_eq!(&[0, 8], &msb[18..20]);
+        // e_entry
+        assert_eq!(&0x08048054u32.to_le_bytes(), &lsb[24..28]);
+        assert_eq!(&0x08048054u32.to_be_bytes(), &msb[24..28]);
+    }
+
+    #[test]
+    fn test_matches_fixed_layouts() {
+        let program = create_program();
+        assert_eq!(
+            elf_bytes(&program),
+            Format::ELF64_LSB.elf_bytes(62, VADDR, &program).unwrap()
+        );
+        let program32 = [0x31, 0xc0, 0x40, 0x31, 0xdb, 0xcd, 0x80];
+        assert_eq!(
+            elf32_bytes(&program32),
+            Format::ELF32_LSB
+                .elf_bytes(3, VADDR32 as u64, &program32)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_run_generic() {
+        let bytes = Format::ELF64_LSB
+            .elf_bytes(62, VADDR, &create_program())
+            .unwrap();
+        assert_eq!(0, exit_code(&run("generic64", &bytes)));
+        // xor eax, eax; inc eax; mov bl, 5; int 0x80
+        let program32 = [0x31, 0xc0, 0x40, 0xb3, 0x05, 0xcd, 0x80];
+        let bytes = Format::ELF32_LSB
+            .elf_bytes(3, VADDR32 as u64, &program32)
+            .unwrap();
+        assert_eq!(5, exit_code(&run("generic32", &bytes)));
+    }
+
+    #[test]
+    fn test_from_ident() {
+        assert_eq!(
+            Some(Format::new(Class::Elf64, Encoding::Msb)),
+            Format::from_ident(2, 2)
+        );
+        assert_eq!(None, Format::from_ident(3, 1));
+        assert_eq!(None, Format::from_ident(1, 0));
+        let mut bytes = Format::ELF64_MSB.elf_bytes(22, VADDR, &[0; 4]).unwrap();
+        bytes[5] = 3;
+        assert_eq!(Err(ParseError::BadDataEncoding(3)), parse_headers(&bytes));
+    }
+
+    #[test]
+    fn test_elf32_overflow() {
+        assert_eq!(
+            Err(FormatError::TooLarge {
+                field: "e_entry",
+                value: (1 << 32) + 52 + 32
+            }),
+            Format::ELF32_LSB.elf_bytes(3, 1 << 32, &[])
+        );
+        assert!(Format::ELF64_LSB.elf_bytes(62, 1 << 32, &[]).is_ok());
+    }
+}
@@ -0,0 +1,539 @@
+//! A golfed layout that overlaps the ELF structures wherever the Linux loader
+//! tolerates it.
+//!
+//! Unlike [`elf_bytes`](crate::elf_bytes), the whole file (headers included)
+//! is mapped at [`VADDR`], in the style of the teensy ELF tutorials. The file
+//! is not valid according to the specification, so it is written directly
+//! rather than through the `elf64_file` layout.
+
+use crate::{elf64_hdr, elf64_header, elf64_phdr, set_elf64_hdr, set_elf64_phdr, Header, Phdr};
+use crate::{PF_R, PF_W, PF_X};
+use crate::{PT_LOAD, VADDR};
+use binary_layout::Field;
+use iced_x86::code_asm::CodeAssembler;
+use iced_x86::{BlockEncoder, BlockEncoderOptions, Code, IcedError, Instruction, InstructionBlock};
+use std::fmt;
+use std::ops::Range;
+
+/// The tricks used by [`golf_bytes`], each of which can be enabled separately.
+///
+/// The default enables none of them, which gives a file the same size as
+/// [`elf_bytes`](crate::elf_bytes).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Golf {
+    phdr_in_ehdr: bool,
+    code_in_align: bool,
+}
+
+impl Golf {
+    /// Every trick.
+    pub fn all() -> Self {
+        Golf {
+            phdr_in_ehdr: true,
+            code_in_align: true,
+        }
+    }
+
+    /// Start the program header 8 bytes before the end of the ELF header.
+    ///
+    /// `p_type` then overlaps `e_phnum` and `e_shentsize`, which works since
+    /// `PT_LOAD` is 1 and there is one program header. `p_flags` overlaps
+    /// `e_shnum` and `e_shstrndx`, which the loader ignores.
+    pub fn phdr_in_ehdr(mut self, enable: bool) -> Self {
+        self.phdr_in_ehdr = enable;
+        self
+    }
+
+    /// Start the code at the program header's `p_align` field.
+    ///
+    /// The loader only uses `p_align` for `ET_DYN` files, so the first 8 bytes
+    /// of code can double as the alignment.
+    pub fn code_in_align(mut self, enable: bool) -> Self {
+        self.code_in_align = enable;
+        self
+    }
+
+    /// Offset of the program header in the file.
+    pub fn phoff(&self) -> u64 {
+        let hdr_sz = elf64_hdr::SIZE.unwrap() as u64;
+        if self.phdr_in_ehdr {
+            hdr_sz - 8
+        } else {
+            hdr_sz
+        }
+    }
+
+    /// Offset of the code in the file.
+    pub fn program_offset(&self) -> u64 {
+        let phdr_sz = elf64_phdr::SIZE.unwrap() as u64;
+        if self.code_in_align {
+            self.phoff() + phdr_sz - 8
+        } else {
+            self.phoff() + phdr_sz
+        }
+    }
+
+    /// Address of the code, which is also the entry point.
+    pub fn program_vaddr(&self) -> u64 {
+        VADDR + self.program_offset()
+    }
+
+    /// The header fields that the loader ignores and [`hide_in_slack`] fills
+    /// by default, as ranges of file offsets.
+    ///
+    /// These are `EI_ABIVERSION` and the padding in `e_ident`, the adjacent
+    /// `e_shoff`, `e_flags` and `e_ehsize`, and `p_paddr`.
+    pub fn slack(&self) -> Vec<Range<u64>> {
+        let paddr = self.phoff() + elf64_phdr::paddr::OFFSET as u64;
+        vec![8..16, 40..54, paddr..paddr + 8]
+    }
+
+    // The header fields that binfmt_elf reads, which code must not overwrite.
+    fn loader_fields(&self) -> Vec<(&'static str, Range<u64>)> {
+        let phdr = |field: &'static str, offset: usize, size: usize| {
+            let start = self.phoff() + offset as u64;
+            (field, start..start + size as u64)
+        };
+        vec![
+            ("e_ident[EI_MAG]", 0..4),
+            ("e_type", 16..18),
+            ("e_machine", 18..20),
+            ("e_entry", 24..32),
+            ("e_phoff", 32..40),
+            ("e_phentsize", 54..56),
+            ("e_phnum", 56..58),
+            phdr("p_type", elf64_phdr::_type::OFFSET, 4),
+            phdr("p_flags", elf64_phdr::flags::OFFSET, 4),
+            phdr("p_offset", elf64_phdr::offset::OFFSET, 8),
+            phdr("p_vaddr", elf64_phdr::vaddr::OFFSET, 8),
+            phdr("p_filesz", elf64_phdr::filesz::OFFSET, 8),
+            phdr("p_memsz", elf64_phdr::memsz::OFFSET, 8),
+        ]
+    }
+}
+
+/// Wrap `program` in an ELF executable using the tricks in `golf`.
+///
+/// The code should be assembled at [`Golf::program_vaddr`].
+pub fn golf_bytes(program: &[u8], golf: Golf) -> Vec<u8> {
+    golf_file(program, golf, golf.program_vaddr())
+}
+
+fn golf_file(program: &[u8], golf: Golf, entry: u64) -> Vec<u8> {
+    let program_offset = golf.program_offset() as usize;
+    let len = program_offset + program.len();
+    // the program header reads the alignment from the code, so pad short
+    // programs out to the end of the program header
+    let phdr_end = golf.phoff() as usize + elf64_phdr::SIZE.unwrap();
+    let mut buf = vec![0u8; len.max(phdr_end)];
+    let header = Header {
+        phoff: golf.phoff(),
+        ..elf64_header(entry, 1)
+    };
+    set_elf64_hdr(&mut buf, &header);
+    let phdr = Phdr {
+        _type: PT_LOAD,
+        flags: PF_X | PF_W | PF_R,
+        offset: 0,
+        vaddr: VADDR,
+        filesz: buf.len() as u64,
+        memsz: buf.len() as u64,
+        align: 4096,
+        ..Phdr::default()
+    };
+    // written after the ELF header, so the overlapping fields take their
+    // values from the program header
+    set_elf64_phdr(&mut buf[golf.phoff() as usize..], &phdr);
+    buf[program_offset..len].copy_from_slice(program);
+    buf
+}
+
+/// Assemble `a` at [`Golf::program_vaddr`] and wrap the result with
+/// [`golf_bytes`].
+pub fn golf_from_asm(a: &mut CodeAssembler, golf: Golf) -> Result<Vec<u8>, IcedError> {
+    Ok(golf_bytes(&a.assemble(golf.program_vaddr())?, golf))
+}
+
+/// An error from [`hide_in_slack`].
+#[derive(Debug, Clone)]
+pub enum GolfError {
+    /// The code could not be assembled.
+    Asm(IcedError),
+    /// A slot overlaps a header field that the kernel's loader reads.
+    LoaderField {
+        slot: Range<u64>,
+        field: &'static str,
+    },
+    /// A slot is empty, overlaps another slot, or extends into the code.
+    BadSlot(Range<u64>),
+    /// The instruction sizes kept changing, so no placement fit the slots.
+    DidNotConverge,
+}
+
+impl fmt::Display for GolfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GolfError::Asm(e) => write!(f, "assembly failed: {e}"),
+            GolfError::LoaderField { slot, field } => {
+                write!(f, "slot {slot:?} overlaps {field}, which the loader reads")
+            }
+            GolfError::BadSlot(slot) => write!(f, "bad slot {slot:?}"),
+            GolfError::DidNotConverge => {
+                write!(f, "instruction sizes did not settle in {MAX_PASSES} passes")
+            }
+        }
+    }
+}
+
+impl std::error::Error for GolfError {}
+
+impl From<IcedError> for GolfError {
+    fn from(e: IcedError) -> Self {
+        GolfError::Asm(e)
+    }
+}
+
+/// The result of [`hide_in_slack`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlackPlan {
+    pub bytes: Vec<u8>,
+    pub entry: u64,
+    /// How many instructions went into each slot, in the order the slots
+    /// were given.
+    pub placed: Vec<usize>,
+    /// How much smaller the file is than with [`golf_from_asm`].
+    pub saved: usize,
+}
+
+// A short jump, which links one slot to the next
+const JMP_SIZE: usize = 2;
+
+// Passes before giving up on the instruction sizes settling.
+const MAX_PASSES: usize = 16;
+
+/// Lay out the code in `a` with [`golf_bytes`], moving as many instructions
+/// as possible into `slots` (ranges of file offsets, such as
+/// [`Golf::slack`]).
+///
+/// Instructions are placed in order, filling each slot before moving to the
+/// next and linking the pieces with short jumps; the rest go after the
+/// headers as usual. The entry point is the first slot used. Slots that
+/// would overwrite a field the kernel reads are rejected.
+pub fn hide_in_slack(
+    a: &mut CodeAssembler,
+    golf: Golf,
+    slots: &[Range<u64>],
+) -> Result<SlackPlan, GolfError> {
+    let fields = golf.loader_fields();
+    for (i, slot) in slots.iter().enumerate() {
+        if slot.is_empty()
+            || slot.end > golf.program_offset()
+            || slots[..i]
+                .iter()
+                .any(|s| s.start < slot.end && slot.start < s.end)
+        {
+            return Err(GolfError::BadSlot(slot.clone()));
+        }
+        if let Some((field, _)) = fields
+            .iter()
+            .find(|(_, f)| f.start < slot.end && slot.start < f.end)
+        {
+            return Err(GolfError::LoaderField {
+                slot: slot.clone(),
+                field,
+            });
+        }
+    }
+    let unhidden = golf_from_asm(a, golf)?.len();
+
+    // Give every instruction an id for the jumps between pieces to refer
+    // to: labelled instructions keep the label's, and the rest get ids above
+    // any label the code uses.
+    let mut instrs = a.instructions().to_vec();
+    let next_id = 1 + instrs
+        .iter()
+        .map(|instr| {
+            let memory = instr.is_ip_rel_memory_operand();
+            let target = if memory {
+                instr.memory_displacement64()
+            } else {
+                instr.near_branch_target()
+            };
+            instr.ip().max(target)
+        })
+        .max()
+        .unwrap_or(0);
+    for (i, instr) in instrs.iter_mut().enumerate() {
+        if instr.ip() == 0 {
+            instr.set_ip(next_id + i as u64);
+        }
+    }
+    // start optimistic, and grow the sizes to those actually encoded until
+    // every piece fits its slot
+    let mut sizes = vec![0; instrs.len()];
+    let mut jmp_size = JMP_SIZE;
+    for _ in 0..MAX_PASSES {
+        let placed = place(&sizes, jmp_size, slots);
+        // one piece per slot, then the rest of the code; each piece but the
+        // last may end in a jump to the next one
+        let mut pieces = vec![];
+        let mut start = 0;
+        for &n in &placed {
+            let mut piece = instrs[start..start + n].to_vec();
+            start += n;
+            if n > 0 && start < instrs.len() {
+                piece.push(Instruction::with_branch(
+                    Code::Jmp_rel8_64,
+                    instrs[start].ip(),
+                )?);
+            }
+            pieces.push(piece);
+        }
+        pieces.push(instrs[start..].to_vec());
+        let addrs = slots
+            .iter()
+            .map(|slot| VADDR + slot.start)
+            .chain([golf.program_vaddr()]);
+        let blocks: Vec<InstructionBlock> = pieces
+            .iter()
+            .zip(addrs)
+            .map(|(piece, addr)| InstructionBlock::new(piece, addr))
+            .collect();
+        let results = BlockEncoder::encode_slice(
+            64,
+            &blocks,
+            BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS,
+        )?;
+
+        let counts = placed.iter().copied().chain([instrs.len() - start]);
+        let mut index = 0;
+        for ((result, piece), count) in results.iter().zip(&pieces).zip(counts) {
+            let offsets = &result.new_instruction_offsets;
+            for i in 0..piece.len() {
+                let end = offsets
+                    .get(i + 1)
+                    .map_or(result.code_buffer.len(), |&o| o as usize);
+                let size = end - offsets[i] as usize;
+                if i >= count {
+                    jmp_size = jmp_size.max(size);
+                } else {
+                    sizes[index] = sizes[index].max(size);
+                    index += 1;
+                }
+            }
+        }
+        let fits = results
+            .iter()
+            .zip(slots)
+            .all(|(result, slot)| result.code_buffer.len() as u64 <= slot.end - slot.start);
+        if !fits {
+            continue;
+        }
+
+        let tail_code = &results.last().unwrap().code_buffer;
+        let entry = match slots.iter().zip(&placed).find(|(_, &n)| n > 0) {
+            Some((slot, _)) => VADDR + slot.start,
+            None => golf.program_vaddr(),
+        };
+        let mut bytes = golf_file(tail_code, golf, entry);
+        for (result, slot) in results.iter().zip(slots) {
+            let start = slot.start as usize;
+            bytes[start..start + result.code_buffer.len()].copy_from_slice(&result.code_buffer);
+        }
+        return Ok(SlackPlan {
+            saved: unhidden.saturating_sub(bytes.len()),
+            bytes,
+            entry,
+            placed,
+        });
+    }
+    Err(GolfError::DidNotConverge)
+}
+
+// Greedily fill each slot with whole instructions, leaving room for a jump
+// to the next piece unless the rest of the code fits.
+fn place(sizes: &[usize], jmp_size: usize, slots: &[Range<u64>]) -> Vec<usize> {
+    let mut start = 0;
+    slots
+        .iter()
+        .map(|slot| {
+            let room = (slot.end - slot.start) as usize;
+            let mut used = 0;
+            let mut n = 0;
+            while start + n < sizes.len() {
+                let rest = if start + n + 1 < sizes.len() {
+                    jmp_size
+                } else {
+                    0
+                };
+                if used + sizes[start + n] + rest > room {
+                    break;
+                }
+                used += sizes[start + n];
+                n += 1;
+            }
+            start += n;
+            n
+        })
+        .collect()
+}
+
+#[cfg(test)]
+// single slots are slices of one range, not ranges to collect
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use super::{golf_from_asm, hide_in_slack, Golf, GolfError};
+    use crate::test_util::{exit_code, run};
+    use crate::VADDR;
+    use crate::{lint, parse};
+    use iced_x86::code_asm::*;
+
+    fn exit_with(golf: Golf, code: i8) -> Vec<u8> {
+        let mut a = CodeAssembler::new(64).unwrap();
+        a.push(60).unwrap();
+        a.pop(rax).unwrap();
+        a.push(code as i32).unwrap();
+        a.pop(rdi).unwrap();
+        a.syscall().unwrap();
+        golf_from_asm(&mut a, golf).unwrap()
+    }
+
+    #[test]
+    fn test_no_tricks() {
+        let bytes = exit_with(Golf::default(), 3);
+        assert_eq!(64 + 56 + 8, bytes.len());
+        assert_eq!(3, exit_code(&run("golf_none", &bytes)));
+    }
+
+    #[test]
+    fn test_phdr_in_ehdr() {
+        let golf = Golf::default().phdr_in_ehdr(true);
+        let bytes = exit_with(golf, 4);
+        assert_eq!(56 + 56 + 8, bytes.len());
+        assert_eq!(&56u64.to_le_bytes(), &bytes[32..40]); // e_phoff
+        assert_eq!(&[1, 0], &bytes[56..58]); // e_phnum
+
+        // p_flags ends up as e_shnum without any section headers
+        let diagnostics: Vec<_> = lint(&bytes).iter().map(|d| d.field).collect();
+        assert_eq!(vec!["e_shentsize"], diagnostics);
+        assert_eq!(4, exit_code(&run("golf_phdr_in_ehdr", &bytes)));
+    }
+
+    #[test]
+    fn test_code_in_align() {
+        let golf = Golf::default().code_in_align(true);
+        let bytes = exit_with(golf, 5);
+        assert_eq!(64 + 48 + 8, bytes.len());
+        let elf = parse(&bytes).unwrap();
+        assert_eq!(&bytes[112..120], &elf.segments[0].align.to_le_bytes());
+        assert_eq!(5, exit_code(&run("golf_code_in_align", &bytes)));
+    }
+
+    #[test]
+    fn test_all_tricks() {
+        let bytes = exit_with(Golf::all(), 6);
+        assert_eq!(56 + 48 + 8, bytes.len());
+        assert_eq!(6, exit_code(&run("golf_all", &bytes)));
+    }
+
+    #[test]
+    fn test_short_program_is_padded() {
+        // a program shorter than p_align still gets a complete program header
+        let golf = Golf::all();
+        let mut a = CodeAssembler::new(64).unwrap();
+        a.mov(al, 60).unwrap();
+        a.syscall().unwrap();
+        let bytes = golf_from_asm(&mut a, golf).unwrap();
+        assert_eq!(56 + 56, bytes.len());
+        assert_eq!(0, exit_code(&run("golf_short", &bytes)));
+    }
+
+    #[test]
+    fn test_hide_exit_stub() {
+        let golf = Golf::default().phdr_in_ehdr(true);
+        let mut a = CodeAssembler::new(64).unwrap();
+        a.push(60).unwrap();
+        a.pop(rax).unwrap();
+        a.push(42).unwrap();
+        a.pop(rdi).unwrap();
+        a.syscall().unwrap();
+        let plan = hide_in_slack(&mut a, golf, &golf.slack()).unwrap();
+        // the whole stub fits in the ident padding
+        assert_eq!(vec![5, 0, 0], plan.placed);
+        assert_eq!(VADDR + 8, plan.entry);
+        assert_eq!(8, plan.saved);
+        assert_eq!(56 + 56, plan.bytes.len());
+        assert_eq!(42, exit_code(&run("golf_hide_exit", &plan.bytes)));
+    }
+
+    #[test]
+    fn test_hide_loop() {
+        // branches between pieces are re-encoded for their new addresses
+        let golf = Golf::all();
+        let mut a = CodeAssembler::new(64).unwrap();
+        let mut top = a.create_label();
+        a.mov(ecx, 5).unwrap();
+        a.xor(edi, edi).unwrap();
+        a.set_label(&mut top).unwrap();
+        a.add(edi, 2).unwrap();
+        a.dec(ecx).unwrap();
+        a.jnz(top).unwrap();
+        a.mov(eax, 60).unwrap();
+        a.syscall().unwrap();
+        let plan = hide_in_slack(&mut a, golf, &golf.slack()).unwrap();
+        // mov ecx; then the loop; then mov eax and syscall, each piece but
+        // the last ending in a 2-byte jump
+        assert_eq!(vec![1, 4, 2], plan.placed);
+        assert_eq!(104 + 21 - (56 + 56), plan.saved);
+        assert_eq!(10, exit_code(&run("golf_hide_loop", &plan.bytes)));
+    }
+
+    #[test]
+    fn test_hide_in_custom_slot() {
+        // the kernel doesn't check EI_CLASS through EI_OSABI either
+        let golf = Golf::all();
+        let mut a = CodeAssembler::new(64).unwrap();
+        a.mov(al, 60).unwrap();
+        a.mov(dil, 7).unwrap();
+        a.syscall().unwrap();
+        let plan = hide_in_slack(&mut a, golf, &[4..16]).unwrap();
+        assert_eq!(vec![3], plan.placed);
+        assert_eq!(VADDR + 4, plan.entry);
+        assert_eq!(7, exit_code(&run("golf_hide_custom", &plan.bytes)));
+    }
+
+    #[test]
+    fn test_hide_nothing() {
+        let golf = Golf::default();
+        let mut a = CodeAssembler::new(64).unwrap();
+        a.mov(rax, 60u64 << 32).unwrap();
+        a.syscall().unwrap();
+        let plan = hide_in_slack(&mut a, golf, &[8..16]).unwrap();
+        assert_eq!(vec![0], plan.placed);
+        assert_eq!(golf.program_vaddr(), plan.entry);
+        assert_eq!(0, plan.saved);
+    }
+
+    #[test]
+    fn test_rejected_slots() {
+        let golf = Golf::all();
+        let rejected = |slots: &[std::ops::Range<u64>]| {
+            let mut a = CodeAssembler::new(64).unwrap();
+            a.syscall().unwrap();
+            match hide_in_slack(&mut a, golf, slots) {
+                Err(GolfError::LoaderField { field, .. }) => field,
+                Err(GolfError::BadSlot(_)) => "bad slot",
+                r => panic!("unexpected result {:?}", r.map(|p| p.placed)),
+            }
+        };
+        assert_eq!("e_ident[EI_MAG]", rejected(&[2..6]));
+        assert_eq!("e_type", rejected(&[8..17]));
+        assert_eq!("e_phentsize", rejected(&[40..56]));
+        // with phdr_in_ehdr, p_type is at 56 and p_offset at 64
+        assert_eq!("p_type", rejected(&[58..60]));
+        assert_eq!("p_offset", rejected(&[64..66]));
+        assert_eq!("bad slot", rejected(&[8..12, 10..16]));
+        assert_eq!("bad slot", rejected(&[8..8]));
+        assert_eq!("bad slot", rejected(&[100..110]));
+    }
+}
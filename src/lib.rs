@@ -12,6 +12,45 @@
 
 #![allow(non_camel_case_types)]
 
+mod aarch64;
+mod builder;
+mod dynamic;
+mod elf32;
+mod format;
+mod golf;
+mod lint;
+mod load;
+mod object;
+mod parse;
+mod riscv;
+mod sections;
+mod sha1;
+mod superopt;
+mod syscalls;
+
+pub use aarch64::{
+    elf_aarch64_bytes, elf_from_a64, write_elf_aarch64, A64Assembler, A64Error, A64Label, Cond,
+    Reg, EM_AARCH64,
+};
+pub use builder::{BuildError, ElfBuilder, Image, LoadBase, Segment, BSS_ALIGN, INTERPRETER};
+pub use elf32::{
+    elf32_bytes, elf32_file, elf32_from_asm, elf32_hdr, elf32_phdr, write_elf32, PROGRAM_VADDR32,
+    VADDR32,
+};
+pub use format::{Class, Encoding, Format, FormatError};
+pub use golf::{golf_bytes, golf_from_asm, hide_in_slack, Golf, GolfError, SlackPlan};
+pub use lint::{lint, Diagnostic};
+pub use load::{ConstLoad, KnownRegisters, LoadConst};
+pub use object::ObjectBuilder;
+pub use parse::{parse, parse_headers, Elf, Header, ParseError, Section, Symbol};
+pub use riscv::{
+    elf_from_rv, elf_riscv_bytes, write_elf_riscv, RvAssembler, RvError, RvLabel, RvReg,
+    EF_RISCV_FLOAT_ABI, EF_RISCV_FLOAT_ABI_DOUBLE, EF_RISCV_FLOAT_ABI_QUAD,
+    EF_RISCV_FLOAT_ABI_SINGLE, EF_RISCV_FLOAT_ABI_SOFT, EF_RISCV_RVC, EM_RISCV,
+};
+pub use superopt::{superoptimize, Spec};
+pub use syscalls::{Syscall, SyscallAbi, SyscallArg, Syscalls};
+
 use binary_layout::prelude::*;
 use std::io::prelude::*;
 use std::path::Path;
@@ -23,7 +62,7 @@ type Elf64_Half = u16;
 type Elf64_Word = u32;
 // type Elf64_Sword = i32;
 type Elf64_Xword = u64;
-// type Elf64_Sxword = i64;
+type Elf64_Sxword = i64;
 
 define_layout!(elf64_ident, LittleEndian, {
     mag: [u8; 4],
@@ -47,11 +86,10 @@ mod tests {
     }
 }
 
-fn set_ident<S: AsRef<[u8]> + AsMut<[u8]>>(mut view: elf64_ident::View<S>) {
-    view.mag_mut()
-        .copy_from_slice(&[0x7f, 'E' as u8, 'L' as u8, 'F' as u8]);
-    view.class_mut().write(2); // class: ELFCLASS64
-    view.data_mut().write(1); // data encoding: ELFDATA2LSB
+fn set_ident<S: AsRef<[u8]> + AsMut<[u8]>>(mut view: elf64_ident::View<S>, format: Format) {
+    view.mag_mut().copy_from_slice(&[0x7f, b'E', b'L', b'F']);
+    view.class_mut().write(format.class as u8); // class: ELFCLASS32 or ELFCLASS64
+    view.data_mut().write(format.encoding as u8); // data encoding: ELFDATA2LSB or ELFDATA2MSB
     view.version_mut().write(1); // file version: EV_CURRENT
     view.os_abi_mut().write(0); // OS/ABI identification: System V
     view.abi_version_mut().write(0); // ABI version: System V third edition
@@ -88,19 +126,44 @@ const PROGRAM_OFFSET: u64 = {
     (sz1 + sz2) as u64
 };
 
+/// The `e_machine` value for x86-64.
+pub const EM_X86_64: u16 = 62;
+
 pub const VADDR: u64 = 0x400000;
 
-fn set_elf64_hdr<S: AsRef<[u8]> + AsMut<[u8]>>(mut view: elf64_hdr::View<S>) {
-    set_ident(view.ident_mut());
-    view._type_mut().write(2); // ET_EXEC
-    view.machine_mut().write(62); // EM_X86_64
-    view.version_mut().write(1); // EV_CURRENT
-    view.entry_mut().write(VADDR + PROGRAM_OFFSET);
-    view.phoff_mut().write(elf64_hdr::SIZE.unwrap() as u64);
-    view.flags_mut().write(0); // no processor-specific flags
-    view.ehsize_mut().write(elf64_hdr::SIZE.unwrap() as u16);
-    view.phentsize_mut().write(elf64_phdr::SIZE.unwrap() as u16);
-    view.phnum_mut().write(1);
+fn align_up(x: u64, align: u64) -> u64 {
+    (x + align - 1) & !(align - 1)
+}
+
+/// Virtual address where the program is loaded, which is also the entry point.
+///
+/// Code should be assembled at this address (see [`elf_from_asm`]) so that
+/// absolute and RIP-relative references resolve correctly.
+pub const PROGRAM_VADDR: u64 = VADDR + PROGRAM_OFFSET;
+
+// The header of an x86-64 executable with `phnum` program headers.
+fn elf64_header(entry: u64, phnum: u16) -> Header {
+    let format = Format::ELF64_LSB;
+    Header {
+        _type: 2, // ET_EXEC
+        machine: EM_X86_64,
+        version: 1, // EV_CURRENT
+        entry,
+        // program headers immediately follow the ELF header
+        phoff: format.ehsize() as u64,
+        flags: 0, // no processor-specific flags
+        ehsize: format.ehsize() as u16,
+        phentsize: format.phentsize() as u16,
+        phnum,
+        ..Header::default()
+    }
+}
+
+// Write `header` to the start of `buf` as ELF-64 little-endian.
+fn set_elf64_hdr(buf: &mut [u8], header: &Header) {
+    Format::ELF64_LSB
+        .write_header(buf, header)
+        .expect("ELF-64 fields hold any value")
 }
 
 define_layout!(elf64_phdr, LittleEndian, {
@@ -114,22 +177,290 @@ define_layout!(elf64_phdr, LittleEndian, {
     align: Elf64_Xword,
 });
 
-fn set_elf64_phdr<S>(mut view: elf64_phdr::View<S>, program_size: u64)
+/// Loadable segment
+pub const PT_LOAD: u32 = 1;
+/// Dynamic linking information, read by the dynamic linker
+pub const PT_DYNAMIC: u32 = 2;
+/// Path of the dynamic linker (the "interpreter") that the kernel loads to
+/// run the program
+pub const PT_INTERP: u32 = 3;
+/// Notes, such as the build ID
+pub const PT_NOTE: u32 = 4;
+/// The program headers themselves, from which the dynamic linker works out
+/// the load base of a PIE
+pub const PT_PHDR: u32 = 6;
+/// The initialization image of thread-local storage
+pub const PT_TLS: u32 = 7;
+/// Stack permissions; without one, the dynamic linker assumes the stack
+/// must be executable
+pub const PT_GNU_STACK: u32 = 0x6474e551;
+
+/// Segment is executable
+pub const PF_X: u32 = 0x1;
+/// Segment is writable
+pub const PF_W: u32 = 0x2;
+/// Segment is readable
+pub const PF_R: u32 = 0x4;
+
+/// The fields of a program header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Phdr {
+    pub _type: u32,
+    pub flags: u32,
+    pub offset: u64, // location of segment in file
+    pub vaddr: u64,  // virtual address of segment
+    pub paddr: u64,  // physical address, ignored by Linux
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+// Write `phdr` to the start of `buf` as ELF-64 little-endian.
+fn set_elf64_phdr(buf: &mut [u8], phdr: &Phdr) {
+    Format::ELF64_LSB
+        .write_phdr(buf, phdr)
+        .expect("ELF-64 fields hold any value")
+}
+
+// The layout of `elf_bytes`, for another 64-bit little-endian machine.
+fn elf64_bytes(machine: u16, program: &[u8]) -> Vec<u8> {
+    Format::ELF64_LSB
+        .elf_bytes(machine, VADDR, program)
+        .expect("ELF-64 fields hold any value")
+}
+
+define_layout!(elf64_shdr, LittleEndian, {
+    name: Elf64_Word, // offset of the name in the section name string table
+    _type: Elf64_Word,
+    flags: Elf64_Xword,
+    addr: Elf64_Addr, // virtual address in memory, if loaded
+    offset: Elf64_Off, // location of contents in file
+    size: Elf64_Xword,
+    link: Elf64_Word, // index of an associated section
+    info: Elf64_Word,
+    addralign: Elf64_Xword,
+    entsize: Elf64_Xword, // size of each entry, for tables
+});
+
+/// Section with contents defined by the program
+pub const SHT_PROGBITS: u32 = 1;
+/// Symbol table
+pub const SHT_SYMTAB: u32 = 2;
+/// String table
+pub const SHT_STRTAB: u32 = 3;
+/// Section that occupies no space in the file, like `.bss`
+pub const SHT_NOBITS: u32 = 8;
+/// Relocation entries with explicit addends
+pub const SHT_RELA: u32 = 4;
+/// Symbol hash table for the dynamic linker
+pub const SHT_HASH: u32 = 5;
+/// Dynamic linking information
+pub const SHT_DYNAMIC: u32 = 6;
+/// Notes
+pub const SHT_NOTE: u32 = 7;
+/// Dynamic linker symbol table
+pub const SHT_DYNSYM: u32 = 11;
+
+/// Section is writable
+pub const SHF_WRITE: u64 = 0x1;
+/// Section is loaded into memory
+pub const SHF_ALLOC: u64 = 0x2;
+/// Section is executable
+pub const SHF_EXECINSTR: u64 = 0x4;
+/// `sh_info` holds a section index, as for the section a relocation
+/// section applies to
+pub const SHF_INFO_LINK: u64 = 0x40;
+/// Section holds the template for thread-local storage
+pub const SHF_TLS: u64 = 0x400;
+
+/// The fields of a section header, other than its name.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Shdr {
+    pub _type: u32,
+    pub flags: u64,
+    pub addr: u64,
+    pub offset: u64,
+    pub size: u64,
+    pub link: u32,
+    pub info: u32,
+    pub addralign: u64,
+    pub entsize: u64,
+}
+
+fn set_elf64_shdr<S>(mut view: elf64_shdr::View<S>, name: u32, shdr: &Shdr)
 where
     S: AsRef<[u8]> + AsMut<[u8]>,
 {
-    view._type_mut().write(1); // PT_LOAD
-    view.flags_mut().write(0x1 | 0x2 | 0x4); // PF_X | PF_W | PF_R
+    view.name_mut().write(name);
+    view._type_mut().write(shdr._type);
+    view.flags_mut().write(shdr.flags);
+    view.addr_mut().write(shdr.addr);
+    view.offset_mut().write(shdr.offset);
+    view.size_mut().write(shdr.size);
+    view.link_mut().write(shdr.link);
+    view.info_mut().write(shdr.info);
+    view.addralign_mut().write(shdr.addralign);
+    view.entsize_mut().write(shdr.entsize);
+}
+
+define_layout!(elf64_sym, LittleEndian, {
+    name: Elf64_Word, // offset of the name in the string table
+    info: u8, // binding and type
+    other: u8, // visibility
+    shndx: Elf64_Half, // index of the section the symbol is defined in
+    value: Elf64_Addr,
+    size: Elf64_Xword,
+});
+
+/// Symbol is not visible outside the object file
+pub const STB_LOCAL: u8 = 0;
+/// Symbol is visible to all object files
+pub const STB_GLOBAL: u8 = 1;
+
+/// Symbol has no type
+pub const STT_NOTYPE: u8 = 0;
+/// Symbol is a data object
+pub const STT_OBJECT: u8 = 1;
+/// Symbol is a function or other code
+pub const STT_FUNC: u8 = 2;
+
+/// Section index for undefined symbols
+pub const SHN_UNDEF: u16 = 0;
+/// Section index for symbols with absolute values
+pub const SHN_ABS: u16 = 0xfff1;
 
-    // location of segment in file
-    let offset = (elf64_hdr::SIZE.unwrap() + elf64_phdr::SIZE.unwrap()) as u64;
-    view.offset_mut().write(offset);
-    // virtual address of segment
-    view.vaddr_mut().write(VADDR + PROGRAM_OFFSET);
+/// Relocation type for an 8-byte absolute address (`S + A`).
+pub const R_X86_64_64: u32 = 1;
+/// Relocation type for a 4-byte PC-relative offset (`S + A - P`).
+pub const R_X86_64_PC32: u32 = 2;
+/// Relocation type for a 4-byte PC-relative offset to a function's PLT
+/// entry (`L + A - P`), as used by `call`.
+pub const R_X86_64_PLT32: u32 = 4;
 
-    view.filesz_mut().write(program_size);
-    view.memsz_mut().write(program_size);
-    view.align_mut().write(4096);
+/// Relocation type for a GOT entry holding the address of a data symbol
+/// (`S`).
+pub const R_X86_64_GLOB_DAT: u32 = 6;
+/// Relocation type for a GOT entry holding the address of a function
+/// (`S`), which may be bound lazily.
+pub const R_X86_64_JUMP_SLOT: u32 = 7;
+/// Relocation type for an 8-byte address relative to the load base
+/// (`B + A` in the x86-64 psABI).
+pub const R_X86_64_RELATIVE: u32 = 8;
+
+/// The fields of a symbol table entry, other than its name.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Sym {
+    pub bind: u8,
+    pub _type: u8,
+    pub other: u8,
+    pub shndx: u16,
+    pub value: u64,
+    pub size: u64,
+}
+
+fn set_elf64_sym<S>(mut view: elf64_sym::View<S>, name: u32, sym: &Sym)
+where
+    S: AsRef<[u8]> + AsMut<[u8]>,
+{
+    view.name_mut().write(name);
+    view.info_mut().write((sym.bind << 4) | sym._type);
+    view.other_mut().write(sym.other);
+    view.shndx_mut().write(sym.shndx);
+    view.value_mut().write(sym.value);
+    view.size_mut().write(sym.size);
+}
+
+define_layout!(elf64_rela, LittleEndian, {
+    offset: Elf64_Addr, // location to apply the relocation
+    info: Elf64_Xword, // symbol index and relocation type
+    addend: Elf64_Sxword,
+});
+
+/// A relocation entry with an explicit addend.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rela {
+    pub offset: u64,
+    /// Index of the symbol in the associated symbol table.
+    pub symbol: u32,
+    pub _type: u32,
+    pub addend: i64,
+}
+
+fn set_elf64_rela<S>(mut view: elf64_rela::View<S>, rela: &Rela)
+where
+    S: AsRef<[u8]> + AsMut<[u8]>,
+{
+    view.offset_mut().write(rela.offset);
+    view.info_mut()
+        .write(((rela.symbol as u64) << 32) | rela._type as u64);
+    view.addend_mut().write(rela.addend);
+}
+
+define_layout!(elf64_dyn, LittleEndian, {
+    tag: Elf64_Sxword, // one of the DT_ constants
+    val: Elf64_Xword, // a value or an address, depending on the tag
+});
+
+/// Marks the end of the dynamic section
+pub const DT_NULL: i64 = 0;
+/// String table offset of the name of a needed library
+pub const DT_NEEDED: i64 = 1;
+/// Size in bytes of the `DT_JMPREL` table
+pub const DT_PLTRELSZ: i64 = 2;
+/// Address of the symbol hash table
+pub const DT_HASH: i64 = 4;
+/// Address of the dynamic string table
+pub const DT_STRTAB: i64 = 5;
+/// Address of the dynamic symbol table
+pub const DT_SYMTAB: i64 = 6;
+/// Address of the `Elf64_Rela` relocation table
+pub const DT_RELA: i64 = 7;
+/// Size in bytes of the `DT_RELA` table
+pub const DT_RELASZ: i64 = 8;
+/// Size of a `DT_RELA` entry
+pub const DT_RELAENT: i64 = 9;
+/// Size in bytes of the dynamic string table
+pub const DT_STRSZ: i64 = 10;
+/// Size of a dynamic symbol table entry
+pub const DT_SYMENT: i64 = 11;
+/// Type of the `DT_JMPREL` entries, `DT_REL` or `DT_RELA`
+pub const DT_PLTREL: i64 = 20;
+/// Address of the relocations for jump slots
+pub const DT_JMPREL: i64 = 23;
+/// Bind all symbols when loading, rather than lazily on the first call
+pub const DT_BIND_NOW: i64 = 24;
+
+fn set_elf64_dyn<S>(mut view: elf64_dyn::View<S>, tag: i64, val: u64)
+where
+    S: AsRef<[u8]> + AsMut<[u8]>,
+{
+    view.tag_mut().write(tag);
+    view.val_mut().write(val);
+}
+
+define_layout!(elf64_nhdr, LittleEndian, {
+    namesz: Elf64_Word, // including the terminating 0
+    descsz: Elf64_Word,
+    _type: Elf64_Word, // meaning depends on the name
+});
+
+/// Note type for the GNU build ID, a unique identifier for the file
+pub const NT_GNU_BUILD_ID: u32 = 3;
+
+/// Encode a note: the header, then the name and descriptor, each padded to
+/// 4 bytes.
+fn note_bytes(name: &str, _type: u32, desc: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![0u8; elf64_nhdr::SIZE.unwrap()];
+    let mut view = elf64_nhdr::View::new(&mut bytes[..]);
+    view.namesz_mut().write(name.len() as u32 + 1);
+    view.descsz_mut().write(desc.len() as u32);
+    view._type_mut().write(_type);
+    bytes.extend_from_slice(name.as_bytes());
+    bytes.push(0);
+    bytes.resize(align_up(bytes.len() as u64, 4) as usize, 0);
+    bytes.extend_from_slice(desc);
+    bytes.resize(align_up(bytes.len() as u64, 4) as usize, 0);
+    bytes
 }
 
 define_layout!(elf64_file, LittleEndian, {
@@ -138,14 +469,26 @@ define_layout!(elf64_file, LittleEndian, {
     program: [u8],
 });
 
-fn create_elf(program: &[u8]) -> Vec<u8> {
+/// Wrap `program` (raw x86-64 machine code) in a minimal ELF executable.
+///
+/// The code is loaded right after the headers and is also the entry point.
+pub fn elf_bytes(program: &[u8]) -> Vec<u8> {
     let hdr_sz = elf64_hdr::SIZE.unwrap();
     let phdr_sz = elf64_phdr::SIZE.unwrap();
     let mut buf = vec![0u8; hdr_sz + phdr_sz + program.len()];
-    let mut file = elf64_file::View::new(&mut buf);
-    set_elf64_hdr(file.hdr_mut());
-    set_elf64_phdr(file.phdr_mut(), program.len() as u64);
-    file.program_mut().copy_from_slice(program);
+    set_elf64_hdr(&mut buf, &elf64_header(PROGRAM_VADDR, 1));
+    let phdr = Phdr {
+        _type: PT_LOAD,
+        flags: PF_X | PF_W | PF_R,
+        offset: PROGRAM_OFFSET,
+        vaddr: PROGRAM_VADDR,
+        filesz: program.len() as u64,
+        memsz: program.len() as u64,
+        align: 4096,
+        ..Phdr::default()
+    };
+    set_elf64_phdr(&mut buf[hdr_sz..], &phdr);
+    buf[hdr_sz + phdr_sz..].copy_from_slice(program);
     buf
 }
 
@@ -153,19 +496,23 @@ fn create_program() -> Vec<u8> {
     use iced_x86::code_asm::*;
     let f = || -> Result<_, IcedError> {
         let mut a = CodeAssembler::new(64)?;
-        // push + pop is 2+1 bytes, which is slightly shorter than even mov(eax, 60)
-        a.push(60)?;
-        a.pop(rax)?;
-        // a.mov(eax, 60)?;
-        // zero edi in two bytes
-        a.xor(edi, edi)?;
-        a.syscall()?;
-        let bytes = a.assemble(VADDR)?;
+        // push 60; pop rax (3 bytes, shorter than even mov eax, 60), then
+        // xor edi, edi
+        a.exit(0, &mut KnownRegisters::new())?;
+        let bytes = a.assemble(PROGRAM_VADDR)?;
         Ok(bytes)
     };
     f().unwrap()
 }
 
+/// Assemble `a` at [`PROGRAM_VADDR`] and wrap the result in a minimal ELF
+/// executable.
+pub fn elf_from_asm(
+    a: &mut iced_x86::code_asm::CodeAssembler,
+) -> Result<Vec<u8>, iced_x86::IcedError> {
+    Ok(elf_bytes(&a.assemble(PROGRAM_VADDR)?))
+}
+
 #[cfg(test)]
 mod test_program {
     use super::create_program;
@@ -177,11 +524,134 @@ mod test_program {
     }
 }
 
-pub fn write_elf<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
-    let buf = create_elf(&create_program());
+/// Write an executable ELF file to `path` that runs `program`.
+///
+/// See [`elf_bytes`] for the layout of the file.
+pub fn write_elf_with_program<P: AsRef<Path>>(path: P, program: &[u8]) -> std::io::Result<()> {
+    write_executable(path, &elf_bytes(program))
+}
+
+fn write_executable<P: AsRef<Path>>(path: P, buf: &[u8]) -> std::io::Result<()> {
     let mut options = OpenOptions::new();
-    options.write(true).create(true).mode(0o755);
+    options.write(true).create(true).truncate(true).mode(0o755);
     let mut file = options.open(path)?;
-    file.write_all(&buf)?;
+    file.write_all(buf)?;
     Ok(())
 }
+
+/// Write the minimal ELF file, which immediately calls `exit(0)`.
+pub fn write_elf<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
+    write_elf_with_program(path, &create_program())
+}
+
+#[cfg(test)]
+mod test_util;
+
+#[cfg(test)]
+mod test_elf {
+    use super::{create_program, elf_bytes, elf_from_asm, write_elf_with_program, PROGRAM_VADDR};
+    use crate::test_util::{exit_code, run};
+    use iced_x86::code_asm::*;
+    use iced_x86::BlockEncoderOptions;
+
+    fn exit_with(code: u32) -> Vec<u8> {
+        let mut a = CodeAssembler::new(64).unwrap();
+        a.mov(edi, code).unwrap();
+        a.push(60).unwrap();
+        a.pop(rax).unwrap();
+        a.syscall().unwrap();
+        a.assemble(0).unwrap()
+    }
+
+    #[test]
+    fn test_tiny_size() {
+        assert_eq!(127, elf_bytes(&create_program()).len());
+    }
+
+    #[test]
+    fn test_run_tiny() {
+        assert_eq!(0, exit_code(&run("tiny", &elf_bytes(&create_program()))));
+    }
+
+    #[test]
+    fn test_run_exit_codes() {
+        for code in [1, 42, 255] {
+            let status = run(&format!("exit{code}"), &elf_bytes(&exit_with(code)));
+            assert_eq!(code as i32, exit_code(&status));
+        }
+    }
+
+    #[test]
+    fn test_write_elf_with_program() {
+        let path = crate::test_util::path("write_elf_with_program");
+        // write a longer file first to check that the file is truncated
+        write_elf_with_program(&path, &[0x90; 100]).unwrap();
+        write_elf_with_program(&path, &exit_with(7)).unwrap();
+        let contents = std::fs::read(&path).unwrap();
+        assert_eq!(elf_bytes(&exit_with(7)), contents);
+        assert_eq!(7, exit_code(&crate::test_util::run_path(&path)));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn exit_42(a: &mut CodeAssembler) {
+        a.push(42).unwrap();
+        a.pop(rdi).unwrap();
+        a.push(60).unwrap();
+        a.pop(rax).unwrap();
+        a.syscall().unwrap();
+    }
+
+    #[test]
+    fn test_absolute_data_load() {
+        let mut a = CodeAssembler::new(64).unwrap();
+        // 7 bytes, followed by 5 bytes for the syscall
+        a.mov(edi, dword_ptr(PROGRAM_VADDR + 12)).unwrap();
+        a.push(60).unwrap();
+        a.pop(rax).unwrap();
+        a.syscall().unwrap();
+        a.dd(&[42]).unwrap();
+        let status = run("absolute_data_load", &elf_from_asm(&mut a).unwrap());
+        assert_eq!(42, exit_code(&status));
+    }
+
+    #[test]
+    fn test_label_relative_jump_table() {
+        let mut a = CodeAssembler::new(64).unwrap();
+        let mut table = a.create_label();
+        let mut target = a.create_label();
+        // 6 bytes, followed by a 2-byte ud2
+        a.jmp(qword_ptr(table)).unwrap();
+        a.ud2().unwrap();
+        a.set_label(&mut target).unwrap();
+        exit_42(&mut a);
+        a.set_label(&mut table).unwrap();
+        a.dq(&[PROGRAM_VADDR + 8]).unwrap();
+        let result = a
+            .assemble_options(
+                PROGRAM_VADDR,
+                BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS,
+            )
+            .unwrap();
+        assert_eq!(PROGRAM_VADDR + 8, result.label_ip(&target).unwrap());
+        let status = run(
+            "label_relative_jump_table",
+            &elf_bytes(&result.inner.code_buffer),
+        );
+        assert_eq!(42, exit_code(&status));
+    }
+
+    #[test]
+    fn test_absolute_jumps() {
+        let mut a = CodeAssembler::new(64).unwrap();
+        // jump through a register: 5 + 2 bytes, followed by a 2-byte ud2
+        a.mov(eax, (PROGRAM_VADDR + 9) as u32).unwrap();
+        a.jmp(rax).unwrap();
+        a.ud2().unwrap();
+        // far enough away that a near jump is needed
+        a.jmp(PROGRAM_VADDR + 0x100).unwrap();
+        a.db(&[0xcc; 0x100 - 9 - 5]).unwrap();
+        exit_42(&mut a);
+        let status = run("absolute_jumps", &elf_from_asm(&mut a).unwrap());
+        assert_eq!(42, exit_code(&status));
+    }
+}
@@ -0,0 +1,332 @@
+//! Check ELF files against the parts of the specification the kernel's loader
+//! relies on.
+
+use crate::parse::{parse_header, parse_section_headers, parse_segments, Header, ParseError};
+use crate::{elf64_hdr, elf64_phdr, elf64_shdr, Phdr, PF_X, PT_LOAD, SHT_NOBITS};
+use binary_layout::Field;
+use std::fmt;
+
+/// A problem with one field of an ELF file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// The name of the field in the ELF specification, such as `p_align`.
+    pub field: &'static str,
+    /// The offset of the field in the file.
+    pub offset: u64,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {:#x}: {}", self.field, self.offset, self.message)
+    }
+}
+
+struct Linter {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Linter {
+    fn report(&mut self, field: &'static str, offset: usize, message: String) {
+        self.diagnostics.push(Diagnostic {
+            field,
+            offset: offset as u64,
+            message,
+        });
+    }
+
+    fn header(&mut self, header: &Header) {
+        if header.ehsize as usize != elf64_hdr::SIZE.unwrap() {
+            self.report(
+                "e_ehsize",
+                elf64_hdr::ehsize::OFFSET,
+                format!(
+                    "is {}, expected {}",
+                    header.ehsize,
+                    elf64_hdr::SIZE.unwrap()
+                ),
+            );
+        }
+    }
+
+    fn segments(&mut self, bytes: &[u8], header: &Header) {
+        let segments = match parse_segments(bytes, header) {
+            Ok(segments) => segments,
+            Err(ParseError::BadEntrySize { size, .. }) => {
+                let expected = elf64_phdr::SIZE.unwrap();
+                let offset = elf64_hdr::phentsize::OFFSET;
+                return self.report(
+                    "e_phentsize",
+                    offset,
+                    format!("is {size}, expected {expected}"),
+                );
+            }
+            Err(e) => return self.report("e_phoff", elf64_hdr::phoff::OFFSET, e.to_string()),
+        };
+        // offset of a field in the program header for segment i
+        let field = |i: usize, offset: usize| {
+            header.phoff as usize + i * elf64_phdr::SIZE.unwrap() + offset
+        };
+        for (i, p) in segments.iter().enumerate() {
+            if p.filesz > p.memsz {
+                self.report(
+                    "p_filesz",
+                    field(i, elf64_phdr::filesz::OFFSET),
+                    format!(
+                        "segment {i} has filesz {:#x} > memsz {:#x}",
+                        p.filesz, p.memsz
+                    ),
+                );
+            }
+            if p._type != PT_LOAD {
+                continue;
+            }
+            if p.offset.saturating_add(p.filesz) > bytes.len() as u64 {
+                self.report(
+                    "p_offset",
+                    field(i, elf64_phdr::offset::OFFSET),
+                    format!("segment {i} extends past the end of the file"),
+                );
+            }
+            if !p.align.is_power_of_two() && p.align != 0 {
+                self.report(
+                    "p_align",
+                    field(i, elf64_phdr::align::OFFSET),
+                    format!("segment {i} alignment {:#x} is not a power of two", p.align),
+                );
+            } else if p.align > 1 && p.offset % p.align != p.vaddr % p.align {
+                self.report(
+                    "p_vaddr",
+                    field(i, elf64_phdr::vaddr::OFFSET),
+                    format!(
+                        "segment {i} has vaddr {:#x} and offset {:#x}, \
+                        which differ modulo align {:#x}",
+                        p.vaddr, p.offset, p.align
+                    ),
+                );
+            }
+            let overlaps = |q: &Phdr| {
+                q._type == PT_LOAD
+                    && p.vaddr < q.vaddr.saturating_add(q.memsz)
+                    && q.vaddr < p.vaddr.saturating_add(p.memsz)
+            };
+            if let Some(j) = segments[..i].iter().position(overlaps) {
+                self.report(
+                    "p_vaddr",
+                    field(i, elf64_phdr::vaddr::OFFSET),
+                    format!("segment {i} overlaps segment {j} in memory"),
+                );
+            }
+        }
+
+        // relocatable files have no entry point, and shared libraries may
+        // leave it zero
+        let has_entry = header._type != 1 /* ET_REL */ && (header.entry != 0 || header._type == 2);
+        let executable = |p: &&Phdr| {
+            p._type == PT_LOAD
+                && p.flags & PF_X != 0
+                && p.vaddr <= header.entry
+                && header.entry < p.vaddr.saturating_add(p.memsz)
+        };
+        if has_entry && !segments.iter().any(|p| executable(&p)) {
+            self.report(
+                "e_entry",
+                elf64_hdr::entry::OFFSET,
+                format!(
+                    "entry point {:#x} is not in an executable segment",
+                    header.entry
+                ),
+            );
+        }
+    }
+
+    fn sections(&mut self, bytes: &[u8], header: &Header) {
+        let sections = match parse_section_headers(bytes, header) {
+            Ok(sections) => sections,
+            Err(ParseError::BadEntrySize { size, .. }) => {
+                let expected = elf64_shdr::SIZE.unwrap();
+                let offset = elf64_hdr::shentsize::OFFSET;
+                return self.report(
+                    "e_shentsize",
+                    offset,
+                    format!("is {size}, expected {expected}"),
+                );
+            }
+            Err(e) => return self.report("e_shoff", elf64_hdr::shoff::OFFSET, e.to_string()),
+        };
+        if sections.is_empty() {
+            return;
+        }
+        if header.shstrndx as usize >= sections.len() {
+            self.report(
+                "e_shstrndx",
+                elf64_hdr::shstrndx::OFFSET,
+                format!("section {} does not exist", header.shstrndx),
+            );
+        }
+        for (i, (_, shdr)) in sections.iter().enumerate() {
+            if shdr._type != SHT_NOBITS
+                && shdr.offset.saturating_add(shdr.size) > bytes.len() as u64
+            {
+                let offset = header.shoff as usize + i * elf64_shdr::SIZE.unwrap();
+                self.report(
+                    "sh_size",
+                    offset + elf64_shdr::size::OFFSET,
+                    format!("section {i} extends past the end of the file"),
+                );
+            }
+        }
+    }
+}
+
+/// Check an ELF-64 file for violations of the specification.
+///
+/// Returns an empty list if no problems were found.
+pub fn lint(bytes: &[u8]) -> Vec<Diagnostic> {
+    let mut linter = Linter {
+        diagnostics: vec![],
+    };
+    match parse_header(bytes) {
+        Ok(header) => {
+            linter.header(&header);
+            linter.segments(bytes, &header);
+            linter.sections(bytes, &header);
+        }
+        Err(e) => {
+            let (field, offset) = match e {
+                ParseError::BadClass(_) => ("EI_CLASS", 4),
+                ParseError::BadDataEncoding(_) => ("EI_DATA", 5),
+                _ => ("e_ident", 0),
+            };
+            linter.report(field, offset, e.to_string());
+        }
+    }
+    linter.diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lint, Diagnostic};
+    use crate::{create_program, elf_bytes, ElfBuilder, Segment, PF_R, PF_W, PF_X};
+    use iced_x86::code_asm::*;
+
+    fn fields(bytes: &[u8]) -> Vec<(&'static str, u64)> {
+        lint(bytes).iter().map(|d| (d.field, d.offset)).collect()
+    }
+
+    fn hello() -> Vec<u8> {
+        let mut b = ElfBuilder::new();
+        b.symbol_table(true);
+        b.segment(Segment::new(PF_R | PF_X));
+        b.label("_start").unwrap();
+        b.asm().push(60).unwrap();
+        b.asm().pop(rax).unwrap();
+        b.asm().syscall().unwrap();
+        b.segment(Segment::new(PF_R | PF_W));
+        b.rodata("msg", b"hello").unwrap();
+        b.bss("buf", 100).unwrap();
+        b.build().unwrap()
+    }
+
+    #[test]
+    fn test_generated_files_are_clean() {
+        assert_eq!(
+            Vec::<Diagnostic>::new(),
+            lint(&elf_bytes(&create_program()))
+        );
+        assert_eq!(Vec::<Diagnostic>::new(), lint(&hello()));
+    }
+
+    #[test]
+    fn test_foreign_files_are_clean() {
+        let exe = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        assert_eq!(Vec::<Diagnostic>::new(), lint(&exe));
+        if let Ok(sh) = std::fs::read("/bin/sh") {
+            assert_eq!(Vec::<Diagnostic>::new(), lint(&sh));
+        }
+    }
+
+    #[test]
+    fn test_bad_header() {
+        let tiny = elf_bytes(&create_program());
+        assert_eq!(vec![("e_ident", 0)], fields(b"MZ"));
+        let mut bytes = tiny.clone();
+        bytes[4] = 1;
+        assert_eq!(vec![("EI_CLASS", 4)], fields(&bytes));
+
+        let mut bytes = tiny.clone();
+        bytes[54] = 32; // e_phentsize
+        let diagnostics = lint(&bytes);
+        assert_eq!(1, diagnostics.len());
+        assert_eq!(
+            "e_phentsize at 0x36: is 32, expected 56",
+            diagnostics[0].to_string()
+        );
+
+        let mut bytes = tiny.clone();
+        bytes[24] = 0; // e_entry, now below the segment
+        assert_eq!(vec![("e_entry", 24)], fields(&bytes));
+
+        assert_eq!(vec![("e_phoff", 32)], fields(&tiny[..80]));
+    }
+
+    #[test]
+    fn test_bad_segments() {
+        let tiny = elf_bytes(&create_program());
+        let phdr = 64;
+
+        let mut bytes = tiny.clone();
+        bytes[phdr + 32] = 8; // p_filesz
+        assert_eq!(
+            vec![
+                ("p_filesz", phdr as u64 + 32),
+                ("p_offset", phdr as u64 + 8)
+            ],
+            fields(&bytes)
+        );
+
+        let mut bytes = tiny.clone();
+        bytes[phdr + 16] += 1; // p_vaddr, moving the entry point out as well
+        bytes[24] += 1;
+        assert_eq!(vec![("p_vaddr", phdr as u64 + 16)], fields(&bytes));
+
+        let mut bytes = tiny.clone();
+        bytes[phdr + 48] = 3; // p_align
+        assert_eq!(vec![("p_align", phdr as u64 + 48)], fields(&bytes));
+
+        let mut bytes = tiny.clone();
+        bytes[phdr + 4] = (PF_R | PF_W) as u8; // p_flags
+        assert_eq!(vec![("e_entry", 24)], fields(&bytes));
+    }
+
+    #[test]
+    fn test_overlapping_segments() {
+        let mut bytes = hello();
+        // grow the code segment in memory until it covers the data segment
+        let memsz = 64 + 40;
+        bytes[memsz + 1] = 0x20;
+        let phdr = 64 + 56;
+        let diagnostics = lint(&bytes);
+        assert_eq!(1, diagnostics.len());
+        assert_eq!(
+            ("p_vaddr", phdr as u64 + 16),
+            (diagnostics[0].field, diagnostics[0].offset)
+        );
+        assert!(diagnostics[0].message.contains("overlaps segment 0"));
+    }
+
+    #[test]
+    fn test_bad_sections() {
+        let bytes = hello();
+        let shoff = u64::from_le_bytes(bytes[40..48].try_into().unwrap()) as usize;
+        let mut bad = bytes.clone();
+        bad[62] = 100; // e_shstrndx
+        assert_eq!(vec![("e_shstrndx", 62)], fields(&bad));
+
+        let mut bad = bytes.clone();
+        bad[shoff + 64 + 32 + 7] = 1; // sh_size of section 1
+        assert_eq!(vec![("sh_size", shoff as u64 + 64 + 32)], fields(&bad));
+
+        assert_eq!(vec![("e_shoff", 40)], fields(&bytes[..bytes.len() - 1]));
+    }
+}
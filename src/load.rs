@@ -0,0 +1,458 @@
+//! Load constants into registers with the shortest encoding available.
+//!
+//! `push 60; pop rax` is a byte shorter than `mov eax, 60`, and
+//! `xor edi, edi` is the shortest way to zero a register. [`LoadConst`]
+//! makes that choice for any constant, and can also take advantage of
+//! registers whose values are already known; [`Syscalls`](crate::Syscalls)
+//! uses it for system call arguments.
+
+use iced_x86::code_asm::{AsmRegister64, CodeAssembler};
+use iced_x86::{
+    Code, Encoder, IcedError, Instruction, InstructionInfoFactory, MemoryOperand, OpAccess,
+    Register,
+};
+use std::collections::BTreeMap;
+
+pub(crate) const GPR64: [Register; 16] = [
+    Register::RAX,
+    Register::RCX,
+    Register::RDX,
+    Register::RBX,
+    Register::RSP,
+    Register::RBP,
+    Register::RSI,
+    Register::RDI,
+    Register::R8,
+    Register::R9,
+    Register::R10,
+    Register::R11,
+    Register::R12,
+    Register::R13,
+    Register::R14,
+    Register::R15,
+];
+
+const GPR16: [Register; 16] = [
+    Register::AX,
+    Register::CX,
+    Register::DX,
+    Register::BX,
+    Register::SP,
+    Register::BP,
+    Register::SI,
+    Register::DI,
+    Register::R8W,
+    Register::R9W,
+    Register::R10W,
+    Register::R11W,
+    Register::R12W,
+    Register::R13W,
+    Register::R14W,
+    Register::R15W,
+];
+
+pub(crate) const GPR8: [Register; 16] = [
+    Register::AL,
+    Register::CL,
+    Register::DL,
+    Register::BL,
+    Register::SPL,
+    Register::BPL,
+    Register::SIL,
+    Register::DIL,
+    Register::R8L,
+    Register::R9L,
+    Register::R10L,
+    Register::R11L,
+    Register::R12L,
+    Register::R13L,
+    Register::R14L,
+    Register::R15L,
+];
+
+/// The values of general-purpose registers at some point in a program.
+///
+/// Registers are tracked by their full 64-bit value; anything not recorded is
+/// unknown.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KnownRegisters {
+    values: BTreeMap<Register, u64>,
+}
+
+impl KnownRegisters {
+    /// No known registers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The registers at the entry point of a static Linux executable, where
+    /// the kernel zeroes every general-purpose register except `rsp`.
+    pub fn linux_entry() -> Self {
+        let mut known = Self::new();
+        for reg in GPR64 {
+            if reg != Register::RSP {
+                known.set(reg, 0);
+            }
+        }
+        known
+    }
+
+    /// Record that `value` was written to `reg`, which may be a 64-, 32-, 16-
+    /// or 8-bit register.
+    ///
+    /// `value` is truncated to the size of `reg`. Writing a 32-bit register
+    /// zero-extends it, as on x86-64, but writing an 8- or 16-bit register
+    /// keeps the other bits, so the full register is only known afterwards
+    /// if it was known before.
+    pub fn set<R: Into<Register>>(&mut self, reg: R, value: u64) {
+        let reg = reg.into();
+        let full = reg.full_register();
+        let value = match reg.size() {
+            8 => value,
+            4 => value & 0xffff_ffff,
+            size => {
+                // ah, ch, dh and bh are bits 8 to 15
+                let shift = if (Register::AH..=Register::BH).contains(&reg) {
+                    8
+                } else {
+                    0
+                };
+                let mask = ((1u64 << (8 * size)) - 1) << shift;
+                match self.get(full) {
+                    Some(old) => old & !mask | (value << shift) & mask,
+                    // the other bits are unknown, and so is the register
+                    None => return,
+                }
+            }
+        };
+        self.values.insert(full, value);
+    }
+
+    /// The value of the 64-bit register `reg`, if known.
+    pub fn get<R: Into<Register>>(&self, reg: R) -> Option<u64> {
+        self.values.get(&reg.into().full_register()).copied()
+    }
+
+    /// Forget the value of `reg`, for example after code that changes it.
+    pub fn forget<R: Into<Register>>(&mut self, reg: R) {
+        self.values.remove(&reg.into().full_register());
+    }
+}
+
+/// The instructions chosen by [`LoadConst::load_const`] and their side
+/// effects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConstLoad {
+    pub instructions: Vec<Instruction>,
+    /// Size of the encoded instructions in bytes.
+    pub len: usize,
+    /// Flags the instructions modify, as [`RflagsBits`](iced_x86::RflagsBits).
+    pub flags: u32,
+    /// 64-bit registers the instructions write, including the destination.
+    ///
+    /// `push`/`pop` pairs include `rsp`: it ends up unchanged, but the value
+    /// is written to the stack below it.
+    pub registers: Vec<Register>,
+}
+
+impl ConstLoad {
+    fn new(instructions: Vec<Instruction>) -> Self {
+        let mut encoder = Encoder::new(64);
+        let mut factory = InstructionInfoFactory::new();
+        let mut len = 0;
+        let mut flags = 0;
+        let mut registers = vec![];
+        for instr in &instructions {
+            len += encoder
+                .encode(instr, 0)
+                .expect("constant loads are valid 64-bit instructions");
+            flags |= instr.rflags_modified();
+            for used in factory.info(instr).used_registers() {
+                let written = !matches!(
+                    used.access(),
+                    OpAccess::Read | OpAccess::CondRead | OpAccess::NoMemAccess | OpAccess::None
+                );
+                let reg = used.register().full_register();
+                if written && !registers.contains(&reg) {
+                    registers.push(reg);
+                }
+            }
+        }
+        ConstLoad {
+            instructions,
+            len,
+            flags,
+            registers,
+        }
+    }
+
+    // Shorter first, then without side effects, then fewer instructions.
+    fn cost(&self) -> (usize, bool, usize, usize) {
+        (
+            self.len,
+            self.flags != 0,
+            self.registers.len(),
+            self.instructions.len(),
+        )
+    }
+}
+
+// Every sequence we know of that sets `dest` to `value`.
+fn candidates(
+    dest: Register,
+    value: u64,
+    known: &KnownRegisters,
+) -> Result<Vec<Vec<Instruction>>, IcedError> {
+    let n = GPR64.iter().position(|&r| r == dest).unwrap();
+    let dest32 = dest.full_register32();
+    let fits_u32 = value <= u32::MAX as u64;
+    let imm32 = i32::try_from(value as i64).ok();
+    let mut candidates = vec![];
+
+    if known.get(dest) == Some(value) {
+        candidates.push(vec![]);
+    }
+    if value == 0 {
+        candidates.push(vec![Instruction::with2(
+            Code::Xor_rm32_r32,
+            dest32,
+            dest32,
+        )?]);
+    }
+    if fits_u32 {
+        candidates.push(vec![Instruction::with2(
+            Code::Mov_r32_imm32,
+            dest32,
+            value as u32,
+        )?]);
+    }
+    if let Some(imm) = imm32 {
+        candidates.push(vec![Instruction::with2(Code::Mov_rm64_imm32, dest, imm)?]);
+        let push = if i8::try_from(imm).is_ok() {
+            Instruction::with1(Code::Pushq_imm8, imm)?
+        } else {
+            Instruction::with1(Code::Pushq_imm32, imm)?
+        };
+        candidates.push(vec![push, Instruction::with1(Code::Pop_r64, dest)?]);
+    }
+    candidates.push(vec![Instruction::with2(Code::Mov_r64_imm64, dest, value)?]);
+
+    if let Some(current) = known.get(dest) {
+        // overwrite only the low bits, if the rest already match
+        if current & !0xff == value & !0xff {
+            candidates.push(vec![Instruction::with2(
+                Code::Mov_r8_imm8,
+                GPR8[n],
+                value as u32 & 0xff,
+            )?]);
+        }
+        if current & !0xffff == value & !0xffff {
+            candidates.push(vec![Instruction::with2(
+                Code::Mov_r16_imm16,
+                GPR16[n],
+                value as u32 & 0xffff,
+            )?]);
+        }
+        // inc and dec of a 32-bit register zero-extend, like mov
+        let small = current <= u32::MAX as u64 && fits_u32;
+        let (inc, dec) = if small {
+            (Code::Inc_rm32, Code::Dec_rm32)
+        } else {
+            (Code::Inc_rm64, Code::Dec_rm64)
+        };
+        let reg = if small { dest32 } else { dest };
+        if current.wrapping_add(1) == value {
+            candidates.push(vec![Instruction::with1(inc, reg)?]);
+        }
+        if current.wrapping_sub(1) == value {
+            candidates.push(vec![Instruction::with1(dec, reg)?]);
+        }
+    }
+
+    for (&base, &base_value) in &known.values {
+        if base_value == value && base != dest {
+            candidates.push(vec![Instruction::with2(Code::Mov_rm64_r64, dest, base)?]);
+            if fits_u32 {
+                candidates.push(vec![Instruction::with2(
+                    Code::Mov_rm32_r32,
+                    dest32,
+                    base.full_register32(),
+                )?]);
+            }
+        }
+        let Ok(displ) = i32::try_from(value.wrapping_sub(base_value) as i64) else {
+            continue;
+        };
+        if displ == 0 {
+            continue;
+        }
+        let mem = MemoryOperand::with_base_displ(base, displ as i64);
+        candidates.push(vec![Instruction::with2(Code::Lea_r64_m, dest, mem)?]);
+        // the 64-bit address is truncated to 32 bits and zero-extended
+        if fits_u32 {
+            candidates.push(vec![Instruction::with2(Code::Lea_r32_m, dest32, mem)?]);
+        }
+    }
+    Ok(candidates)
+}
+
+/// Load constants into registers with the shortest encoding.
+pub trait LoadConst {
+    /// Set `dest` to `value` using the shortest sequence of instructions,
+    /// given the registers in `known`, and record the new value of `dest` in
+    /// `known`.
+    ///
+    /// The candidates are `xor` for zero, `mov` of a 32-bit, sign-extended
+    /// or 64-bit immediate, `push`/`pop`, `mov` into the low 8 or 16 bits,
+    /// `inc`/`dec`, and `mov` or `lea` from another known register. Ties go
+    /// to sequences that clobber less; see [`ConstLoad`] for what was
+    /// clobbered.
+    fn load_const(
+        &mut self,
+        dest: AsmRegister64,
+        value: u64,
+        known: &mut KnownRegisters,
+    ) -> Result<ConstLoad, IcedError>;
+}
+
+impl LoadConst for CodeAssembler {
+    fn load_const(
+        &mut self,
+        dest: AsmRegister64,
+        value: u64,
+        known: &mut KnownRegisters,
+    ) -> Result<ConstLoad, IcedError> {
+        let dest: Register = dest.into();
+        let best = candidates(dest, value, known)?
+            .into_iter()
+            .map(ConstLoad::new)
+            .min_by_key(ConstLoad::cost)
+            .unwrap();
+        for instr in &best.instructions {
+            self.add_instruction(*instr)?;
+        }
+        known.set(dest, value);
+        Ok(best)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KnownRegisters, LoadConst};
+    use crate::elf_from_asm;
+    use crate::test_util::{exit_code, run};
+    use iced_x86::code_asm::*;
+    use iced_x86::{Register, RflagsBits};
+
+    // The bytes emitted to load `value` into `dest`, and the load itself.
+    fn load(
+        dest: AsmRegister64,
+        value: u64,
+        known: &mut KnownRegisters,
+    ) -> (Vec<u8>, super::ConstLoad) {
+        let mut a = CodeAssembler::new(64).unwrap();
+        let load = a.load_const(dest, value, known).unwrap();
+        let bytes = a.assemble(0).unwrap();
+        assert_eq!(load.len, bytes.len());
+        assert_eq!(Some(value), known.get(dest));
+        (bytes, load)
+    }
+
+    #[test]
+    fn test_matches_create_program() {
+        let mut known = KnownRegisters::new();
+        let (bytes, l) = load(rax, 60, &mut known);
+        assert_eq!(vec![0x6a, 60, 0x58], bytes); // push 60; pop rax
+        assert_eq!(0, l.flags);
+        assert_eq!(vec![Register::RSP, Register::RAX], l.registers);
+        let (bytes, l) = load(rdi, 0, &mut known);
+        assert_eq!(vec![0x31, 0xff], bytes); // xor edi, edi
+        let written = RflagsBits::OF | RflagsBits::SF | RflagsBits::ZF | RflagsBits::PF;
+        assert_eq!(written, l.flags & written);
+        assert_eq!(vec![Register::RDI], l.registers);
+    }
+
+    #[test]
+    fn test_immediates() {
+        let mut known = KnownRegisters::new();
+        assert_eq!(
+            vec![0xbe, 0x78, 0x56, 0x34, 0x12],
+            load(rsi, 0x12345678, &mut known).0
+        );
+        // sign-extended push is shorter than mov of a 32-bit immediate
+        assert_eq!(vec![0x6a, 0xff, 0x5e], load(rsi, u64::MAX, &mut known).0);
+        assert_eq!(10, load(rsi, 1 << 40, &mut known).1.len);
+        // an extended register needs a REX prefix for the pop
+        assert_eq!(vec![0x6a, 0x01, 0x41, 0x5a], load(r10, 1, &mut known).0);
+    }
+
+    #[test]
+    fn test_known_registers() {
+        let mut known = KnownRegisters::linux_entry();
+        // already zero
+        assert_eq!(0, load(rdi, 0, &mut known).1.len);
+        // only the low byte needs changing
+        let (bytes, l) = load(rax, 60, &mut known);
+        assert_eq!(vec![0xb0, 60], bytes); // mov al, 60
+        assert_eq!(0, l.flags);
+        // inc is shorter than rewriting the low 16 bits
+        known.set(rdx, 0x1ff);
+        let (bytes, l) = load(rdx, 0x200, &mut known);
+        assert_eq!(vec![0xff, 0xc2], bytes); // inc edx
+        assert_ne!(0, l.flags & RflagsBits::ZF);
+        assert_eq!(vec![0xff, 0xca], load(rdx, 0x1ff, &mut known).0); // dec edx
+
+        // lea from another register
+        known.set(rbx, 0x400000);
+        assert_eq!(vec![0x8d, 0x73, 0x10], load(rsi, 0x400010, &mut known).0); // lea esi, [rbx+16]
+        known.set(rbx, 0xffff_ffff);
+        assert_eq!(
+            vec![0x48, 0x8d, 0x73, 0x01], // lea rsi, [rbx+1]
+            load(rsi, 1 << 32, &mut known).0
+        );
+        // copy another register
+        assert_eq!(vec![0x89, 0xde], load(rsi, 0xffff_ffff, &mut known).0); // mov esi, ebx
+    }
+
+    #[test]
+    fn test_set_zero_extends() {
+        let mut known = KnownRegisters::new();
+        known.set(ecx, u64::MAX);
+        assert_eq!(Some(0xffff_ffff), known.get(rcx));
+        known.forget(rcx);
+        assert_eq!(None, known.get(rcx));
+    }
+
+    #[test]
+    fn test_set_partial() {
+        let mut known = KnownRegisters::new();
+        // the upper bits of rax are unknown
+        known.set(al, 60);
+        assert_eq!(None, known.get(rax));
+        known.set(rax, 0x1122_3344_5566_7788);
+        known.set(al, 0x1ff);
+        assert_eq!(Some(0x1122_3344_5566_77ff), known.get(rax));
+        known.set(ah, 0);
+        assert_eq!(Some(0x1122_3344_5566_00ff), known.get(rax));
+        known.set(ax, 0x12345);
+        assert_eq!(Some(0x1122_3344_5566_2345), known.get(rax));
+        known.set(r8, u64::MAX);
+        known.set(r8b, 0);
+        assert_eq!(Some(0xffff_ffff_ffff_ff00), known.get(r8));
+    }
+
+    #[test]
+    fn test_run_exit() {
+        let mut known = KnownRegisters::linux_entry();
+        let mut a = CodeAssembler::new(64).unwrap();
+        a.load_const(rax, 60, &mut known).unwrap();
+        a.load_const(rdi, 300, &mut known).unwrap();
+        a.load_const(rdi, 299, &mut known).unwrap();
+        a.syscall().unwrap();
+        // exit codes are truncated to 8 bits
+        assert_eq!(
+            299 & 0xff,
+            exit_code(&run("load_exit", &elf_from_asm(&mut a).unwrap()))
+        );
+    }
+}
@@ -0,0 +1,475 @@
+//! Write relocatable object files (`ET_REL`) for the system linker.
+//!
+//! Code goes through a [`CodeAssembler`] as with [`ElfBuilder`](crate::ElfBuilder),
+//! but references to named symbols are left to the linker as relocations in
+//! `.rela.text`, so the symbols can be defined in `.data` or in another
+//! object file.
+
+use crate::sections::{SectionTable, SymbolTable};
+use crate::{
+    elf64_hdr, elf64_header, elf64_rela, lint, set_elf64_hdr, set_elf64_rela, BuildError, Header,
+    Rela, Shdr, Sym, R_X86_64_64, R_X86_64_PC32, R_X86_64_PLT32, SHF_ALLOC, SHF_EXECINSTR,
+    SHF_INFO_LINK, SHF_WRITE, SHN_UNDEF, SHT_PROGBITS, SHT_RELA, STB_GLOBAL, STB_LOCAL, STT_FUNC,
+    STT_NOTYPE, STT_OBJECT,
+};
+use iced_x86::code_asm::{CodeAssembler, CodeLabel};
+use iced_x86::{
+    BlockEncoder, BlockEncoderOptions, Code, Encoder, IcedError, Instruction, InstructionBlock,
+    MemoryOperand, Register,
+};
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+
+// A field in the code for the linker to fill in with the address of a symbol.
+struct Reference {
+    // index of the instruction holding the field
+    index: usize,
+    // offset of the field in the instruction
+    offset: usize,
+    _type: u32,
+    symbol: String,
+    addend: i64,
+}
+
+/// Build a relocatable object file with `.text`, `.data` and `.symtab`
+/// sections, for linking with `ld`.
+pub struct ObjectBuilder {
+    asm: CodeAssembler,
+    labels: HashMap<String, CodeLabel>,
+    // (name, instruction index) of each label in .text
+    text_symbols: Vec<(String, usize)>,
+    data: Vec<u8>,
+    // (name, offset, size) of each symbol in .data
+    data_symbols: Vec<(String, u64, u64)>,
+    globals: BTreeSet<String>,
+    references: Vec<Reference>,
+    lint: bool,
+}
+
+impl Default for ObjectBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ObjectBuilder {
+    pub fn new() -> Self {
+        ObjectBuilder {
+            asm: CodeAssembler::new(64).unwrap(),
+            labels: HashMap::new(),
+            text_symbols: vec![],
+            data: vec![],
+            data_symbols: vec![],
+            globals: BTreeSet::new(),
+            references: vec![],
+            lint: cfg!(debug_assertions),
+        }
+    }
+
+    /// Check the object file with [`lint`](crate::lint), failing with
+    /// [`BuildError::Lint`] if it finds problems (default on in debug builds
+    /// only).
+    pub fn lint(&mut self, enable: bool) -> &mut Self {
+        self.lint = enable;
+        self
+    }
+
+    /// The assembler for adding code to `.text`.
+    pub fn asm(&mut self) -> &mut CodeAssembler {
+        &mut self.asm
+    }
+
+    /// The label for the `.text` symbol `name`, for branches within the
+    /// object. It must be placed with [`ObjectBuilder::label`].
+    pub fn symbol(&mut self, name: &str) -> CodeLabel {
+        if let Some(&label) = self.labels.get(name) {
+            return label;
+        }
+        let label = self.asm.create_label();
+        self.labels.insert(name.to_string(), label);
+        label
+    }
+
+    /// Place the symbol `name` at the current position in `.text`.
+    pub fn label(&mut self, name: &str) -> Result<CodeLabel, IcedError> {
+        let mut label = self.symbol(name);
+        if self.text_symbols.iter().any(|(placed, _)| placed == name) {
+            // reported as a duplicate when building
+            label = self.asm.create_label();
+        }
+        self.asm.set_label(&mut label)?;
+        self.text_symbols
+            .push((name.to_string(), self.asm.instructions().len()));
+        self.asm.zero_bytes()?;
+        Ok(label)
+    }
+
+    /// Add `bytes` to `.data` as the symbol `name`.
+    pub fn data(&mut self, name: &str, bytes: &[u8]) -> &mut Self {
+        let offset = self.data.len() as u64;
+        self.data_symbols
+            .push((name.to_string(), offset, bytes.len() as u64));
+        self.data.extend_from_slice(bytes);
+        self
+    }
+
+    /// Make the symbol `name` global, so that other objects can refer to it.
+    ///
+    /// Symbols that are referenced but not defined are always global.
+    pub fn global(&mut self, name: &str) -> &mut Self {
+        self.globals.insert(name.to_string());
+        self
+    }
+
+    /// Call the function `name`, with an `R_X86_64_PLT32` relocation.
+    pub fn call(&mut self, name: &str) -> Result<(), IcedError> {
+        let instr = Instruction::with_branch(Code::Call_rel32_64, 0)?;
+        self.reference(instr, R_X86_64_PLT32, name)
+    }
+
+    /// Jump to the function `name`, as in a tail call, with an
+    /// `R_X86_64_PLT32` relocation.
+    pub fn jmp(&mut self, name: &str) -> Result<(), IcedError> {
+        let instr = Instruction::with_branch(Code::Jmp_rel32_64, 0)?;
+        self.reference(instr, R_X86_64_PLT32, name)
+    }
+
+    /// Load the address of the symbol `name` into `reg` with a RIP-relative
+    /// `lea`, with an `R_X86_64_PC32` relocation.
+    pub fn lea<R: Into<Register>>(&mut self, reg: R, name: &str) -> Result<(), IcedError> {
+        let instr = Instruction::with2(
+            Code::Lea_r64_m,
+            reg.into(),
+            MemoryOperand::with_base(Register::RIP),
+        )?;
+        self.rip_relative(instr, name)
+    }
+
+    /// Add `instr`, whose `[rip]` memory operand refers to the symbol `name`,
+    /// with an `R_X86_64_PC32` relocation.
+    pub fn rip_relative(&mut self, instr: Instruction, name: &str) -> Result<(), IcedError> {
+        self.reference(instr, R_X86_64_PC32, name)
+    }
+
+    /// Load the absolute address of the symbol `name` into the 64-bit
+    /// register `reg`, with an `R_X86_64_64` relocation.
+    pub fn mov_addr<R: Into<Register>>(&mut self, reg: R, name: &str) -> Result<(), IcedError> {
+        let instr = Instruction::with2(Code::Mov_r64_imm64, reg.into(), 0u64)?;
+        self.reference(instr, R_X86_64_64, name)
+    }
+
+    // Add `instr` as bytes, so the block encoder can neither resize it nor
+    // resolve its target, with a relocation for its displacement or
+    // immediate.
+    fn reference(&mut self, instr: Instruction, _type: u32, name: &str) -> Result<(), IcedError> {
+        let mut encoder = Encoder::new(64);
+        let len = encoder.encode(&instr, 0)?;
+        let offsets = encoder.get_constant_offsets();
+        let (offset, size) = if _type == R_X86_64_64 {
+            (offsets.immediate_offset(), offsets.immediate_size())
+        } else if offsets.has_displacement() {
+            (offsets.displacement_offset(), offsets.displacement_size())
+        } else {
+            // a branch displacement counts as an immediate
+            (offsets.immediate_offset(), offsets.immediate_size())
+        };
+        let mut bytes = encoder.take_buffer();
+        bytes[offset..offset + size].fill(0);
+        let addend = match _type {
+            // the CPU adds the displacement to the address of the next
+            // instruction, not of the field
+            R_X86_64_64 => 0,
+            _ => offset as i64 - len as i64,
+        };
+        self.references.push(Reference {
+            index: self.asm.instructions().len(),
+            offset,
+            _type,
+            symbol: name.to_string(),
+            addend,
+        });
+        self.asm.db(&bytes)
+    }
+
+    /// Build the object file.
+    pub fn build(&self) -> Result<Vec<u8>, BuildError> {
+        let instructions = self.asm.instructions();
+        let result = BlockEncoder::encode(
+            64,
+            InstructionBlock::new(instructions, 0),
+            BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS,
+        )?;
+        let offsets = &result.new_instruction_offsets;
+        let text = result.code_buffer;
+
+        // (name, section, value, size) of each defined symbol
+        let mut defined: Vec<(&str, u16, u64, u64)> = vec![];
+        for (name, index) in &self.text_symbols {
+            defined.push((name, TEXT, offsets[*index] as u64, 0));
+        }
+        for (name, offset, size) in &self.data_symbols {
+            defined.push((name, DATA, *offset, *size));
+        }
+        let mut symtab = SymbolTable::new();
+        for (i, &(name, shndx, value, size)) in defined.iter().enumerate() {
+            if defined[..i].iter().any(|d| d.0 == name) {
+                return Err(BuildError::DuplicateSymbol(name.to_string()));
+            }
+            let global = self.globals.contains(name);
+            let sym = Sym {
+                bind: if global { STB_GLOBAL } else { STB_LOCAL },
+                _type: if shndx == TEXT { STT_FUNC } else { STT_OBJECT },
+                shndx,
+                value,
+                size,
+                ..Sym::default()
+            };
+            symtab.add(name, sym);
+        }
+        let undefined = (self.globals.iter())
+            .chain(self.references.iter().map(|r| &r.symbol))
+            .filter(|name| !defined.iter().any(|d| d.0 == name.as_str()))
+            .collect::<BTreeSet<_>>();
+        for name in undefined {
+            let sym = Sym {
+                bind: STB_GLOBAL,
+                _type: STT_NOTYPE,
+                shndx: SHN_UNDEF,
+                ..Sym::default()
+            };
+            symtab.add(name, sym);
+        }
+
+        let relas: Vec<Rela> = self
+            .references
+            .iter()
+            .map(|r| Rela {
+                offset: offsets[r.index] as u64 + r.offset as u64,
+                symbol: symtab.index(&r.symbol).unwrap(),
+                _type: r._type,
+                addend: r.addend,
+            })
+            .collect();
+
+        let mut bytes = vec![0u8; elf64_hdr::SIZE.unwrap()];
+        let header = Header {
+            _type: 1, // ET_REL
+            // no program headers
+            phoff: 0,
+            phentsize: 0,
+            ..elf64_header(0, 0)
+        };
+        set_elf64_hdr(&mut bytes, &header);
+
+        let mut sections = SectionTable::new();
+        let progbits = |flags, addralign| Shdr {
+            _type: SHT_PROGBITS,
+            flags,
+            addralign,
+            ..Shdr::default()
+        };
+        let text_shdr = progbits(SHF_ALLOC | SHF_EXECINSTR, 16);
+        let text_index = sections.add_data(&mut bytes, ".text", text_shdr, &text);
+        let data_shdr = progbits(SHF_ALLOC | SHF_WRITE, 8);
+        sections.add_data(&mut bytes, ".data", data_shdr, &self.data);
+        // without this, ld assumes the object needs an executable stack
+        sections.add_data(&mut bytes, ".note.GNU-stack", progbits(0, 1), &[]);
+        let symtab_index = symtab.finish(&mut bytes, &mut sections);
+        if !relas.is_empty() {
+            let entsize = elf64_rela::SIZE.unwrap();
+            let mut data = vec![0u8; relas.len() * entsize];
+            for (i, rela) in relas.iter().enumerate() {
+                set_elf64_rela(elf64_rela::View::new(&mut data[i * entsize..]), rela);
+            }
+            let rela_shdr = Shdr {
+                _type: SHT_RELA,
+                flags: SHF_INFO_LINK,
+                link: symtab_index as u32,
+                info: text_index as u32,
+                addralign: 8,
+                entsize: entsize as u64,
+                ..Shdr::default()
+            };
+            sections.add_data(&mut bytes, ".rela.text", rela_shdr, &data);
+        }
+        sections.finish(&mut bytes);
+
+        if self.lint {
+            let diagnostics = lint(&bytes);
+            if !diagnostics.is_empty() {
+                return Err(BuildError::Lint(diagnostics));
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Build the object file and write it to `path`.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), BuildError> {
+        std::fs::write(path, self.build()?)?;
+        Ok(())
+    }
+}
+
+// section indexes, in the order they are added
+const TEXT: u16 = 1;
+const DATA: u16 = 2;
+
+#[cfg(test)]
+mod tests {
+    use super::ObjectBuilder;
+    use crate::test_util::{exit_code, output_path, path};
+    use crate::{elf64_rela, parse, BuildError, R_X86_64_64, R_X86_64_PC32, R_X86_64_PLT32};
+    use crate::{SHN_UNDEF, SHT_RELA, STB_GLOBAL, STB_LOCAL, STT_FUNC, STT_NOTYPE, STT_OBJECT};
+    use iced_x86::code_asm::*;
+    use std::process::Command;
+
+    // calls `greet` and exits with the value it returns
+    fn main_object() -> ObjectBuilder {
+        let mut b = ObjectBuilder::new();
+        b.global("_start").global("answer");
+        b.label("_start").unwrap();
+        b.call("greet").unwrap();
+        b.asm().mov(edi, eax).unwrap();
+        b.asm().mov(eax, 60).unwrap();
+        b.asm().syscall().unwrap();
+        b.data("answer", &42u64.to_le_bytes());
+        b
+    }
+
+    // writes a message and returns the value of `answer`
+    fn greet_object() -> ObjectBuilder {
+        let mut b = ObjectBuilder::new();
+        b.global("greet");
+        b.label("greet").unwrap();
+        b.lea(rsi, "msg").unwrap();
+        b.asm().mov(edx, 6).unwrap();
+        b.asm().mov(edi, 1).unwrap();
+        b.asm().mov(eax, 1).unwrap();
+        b.asm().syscall().unwrap();
+        b.mov_addr(rax, "answer").unwrap();
+        b.asm().mov(eax, dword_ptr(rax)).unwrap();
+        b.asm().ret().unwrap();
+        b.data("msg", b"hello\n");
+        b
+    }
+
+    // (offset, symbol, type, addend) of each entry in .rela.text
+    fn relocations(bytes: &[u8]) -> Vec<(u64, u32, u32, i64)> {
+        let elf = parse(bytes).unwrap();
+        let rela = elf.section(".rela.text").unwrap().header;
+        assert_eq!(SHT_RELA, rela._type);
+        let entsize = elf64_rela::SIZE.unwrap();
+        (0..rela.size as usize / entsize)
+            .map(|i| {
+                let view = elf64_rela::View::new(&bytes[rela.offset as usize + i * entsize..]);
+                let info = view.info().read();
+                let addend = view.addend().read();
+                (
+                    view.offset().read(),
+                    (info >> 32) as u32,
+                    info as u32,
+                    addend,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_object_sections() {
+        let bytes = greet_object().build().unwrap();
+        let elf = parse(&bytes).unwrap();
+        assert_eq!(
+            (1, 0, 0),
+            (elf.header._type, elf.header.phoff, elf.header.phnum)
+        );
+        let names: Vec<&str> = elf.sections.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(
+            vec![
+                "",
+                ".text",
+                ".data",
+                ".note.GNU-stack",
+                ".strtab",
+                ".symtab",
+                ".rela.text",
+                ".shstrtab"
+            ],
+            names
+        );
+        let rela = elf.section(".rela.text").unwrap().header;
+        assert_eq!((5, 1), (rela.link, rela.info));
+
+        let symbols: Vec<_> = (elf.symbols.iter())
+            .map(|s| (s.name.as_str(), s.sym.bind, s.sym._type, s.sym.shndx))
+            .collect();
+        assert_eq!(
+            vec![
+                ("msg", STB_LOCAL, STT_OBJECT, 2),
+                ("greet", STB_GLOBAL, STT_FUNC, 1),
+                ("answer", STB_GLOBAL, STT_NOTYPE, SHN_UNDEF),
+            ],
+            symbols
+        );
+
+        // the displacement of lea rsi, [rip] is its last 4 bytes, and
+        // mov_addr starts after 3 more movs and a syscall, at 24, so its
+        // imm64 is at 26
+        assert_eq!(
+            vec![(3, 1, R_X86_64_PC32, -4), (26, 3, R_X86_64_64, 0)],
+            relocations(&bytes)
+        );
+        let text = elf.section(".text").unwrap().header;
+        let code = &bytes[text.offset as usize..][..text.size as usize];
+        assert_eq!([0; 4], code[3..7]);
+        assert_eq!([0; 8], code[26..34]);
+    }
+
+    #[test]
+    fn test_call_relocation() {
+        let bytes = main_object().build().unwrap();
+        // the undefined symbol comes after _start and answer
+        assert_eq!(vec![(1, 3, R_X86_64_PLT32, -4)], relocations(&bytes));
+        let elf = parse(&bytes).unwrap();
+        assert_eq!("greet", elf.symbols[2].name);
+    }
+
+    #[test]
+    fn test_duplicate_symbol() {
+        let mut b = ObjectBuilder::new();
+        b.label("x").unwrap();
+        b.asm().ret().unwrap();
+        b.data("x", &[0]);
+        match b.build() {
+            Err(BuildError::DuplicateSymbol(name)) => assert_eq!("x", name),
+            r => panic!("expected a duplicate symbol, got {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_ld() {
+        let main = path("ld_main.o");
+        let greet = path("ld_greet.o");
+        let exe = path("ld_hello");
+        main_object().write(&main).unwrap();
+        greet_object().write(&greet).unwrap();
+        let out = Command::new("ld")
+            .arg("-o")
+            .arg(&exe)
+            .arg(&main)
+            .arg(&greet)
+            .output();
+        std::fs::remove_file(&main).unwrap();
+        std::fs::remove_file(&greet).unwrap();
+        let Ok(out) = out else {
+            // ld is not installed
+            return;
+        };
+        assert!(out.status.success(), "{out:?}");
+        // any warning, such as for an executable stack, is a bug
+        assert!(out.stderr.is_empty(), "{out:?}");
+        let run = output_path(&exe);
+        std::fs::remove_file(&exe).unwrap();
+        assert_eq!(b"hello\n", &run.stdout[..]);
+        assert_eq!(42, exit_code(&run.status));
+    }
+}
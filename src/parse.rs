@@ -0,0 +1,557 @@
+//! Parse ELF files back into the header structs used to write them.
+
+use crate::{
+    elf64_shdr, elf64_sym, Class, Encoding, Format, Phdr, Shdr, Sym, SHT_DYNSYM, SHT_NOBITS,
+    SHT_SYMTAB,
+};
+use std::fmt;
+
+/// The fields of the ELF header, including its identification bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Header {
+    pub class: u8,
+    pub data: u8, // data encoding
+    pub os_abi: u8,
+    pub abi_version: u8,
+    pub _type: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub phoff: u64,
+    pub shoff: u64,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub name: String,
+    pub header: Shdr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub sym: Sym,
+}
+
+/// A parsed ELF file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Elf {
+    pub header: Header,
+    pub segments: Vec<Phdr>,
+    /// Empty if the file has no section headers.
+    pub sections: Vec<Section>,
+    /// Contents of the `SHT_SYMTAB` section, without the null symbol.
+    pub symbols: Vec<Symbol>,
+    /// Contents of the `SHT_DYNSYM` section, without the null symbol.
+    pub dynamic_symbols: Vec<Symbol>,
+}
+
+impl Elf {
+    /// The first section called `name`.
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+
+    /// The first symbol called `name` in the symbol table.
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The file does not start with `\x7fELF`.
+    BadMagic,
+    /// `EI_CLASS` is not `ELFCLASS64` (or `ELFCLASS32`, for
+    /// [`parse_headers`]).
+    BadClass(u8),
+    /// `EI_DATA` is not `ELFDATA2LSB` (or `ELFDATA2MSB`, for
+    /// [`parse_headers`]).
+    BadDataEncoding(u8),
+    /// A header or table extends past the end of the file.
+    Truncated {
+        what: &'static str,
+        offset: u64,
+        size: u64,
+    },
+    /// The entry size of a table does not match its layout.
+    BadEntrySize { what: &'static str, size: u64 },
+    /// A section index (such as `e_shstrndx` or `sh_link`) is out of range.
+    BadSectionIndex { what: &'static str, index: u32 },
+    /// A name lies outside its string table or is not NUL-terminated.
+    BadName { offset: u32 },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::BadMagic => write!(f, "not an ELF file"),
+            ParseError::BadClass(class) => write!(f, "unsupported class {class}"),
+            ParseError::BadDataEncoding(data) => write!(f, "unsupported data encoding {data}"),
+            ParseError::Truncated { what, offset, size } => {
+                write!(
+                    f,
+                    "{what} at {offset:#x} ({size:#x} bytes) is past the end of the file"
+                )
+            }
+            ParseError::BadEntrySize { what, size } => {
+                write!(f, "{what} has bad entry size {size}")
+            }
+            ParseError::BadSectionIndex { what, index } => {
+                write!(f, "{what} refers to nonexistent section {index}")
+            }
+            ParseError::BadName { offset } => write!(f, "bad name at string offset {offset}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// The `num` entries of size `entsize` at `offset`.
+fn table<'a>(
+    bytes: &'a [u8],
+    what: &'static str,
+    offset: u64,
+    num: u64,
+    entsize: usize,
+) -> Result<&'a [u8], ParseError> {
+    let size = num.saturating_mul(entsize as u64);
+    let truncated = ParseError::Truncated { what, offset, size };
+    let end = offset.checked_add(size).ok_or(truncated.clone())?;
+    if end > bytes.len() as u64 {
+        return Err(truncated);
+    }
+    Ok(&bytes[offset as usize..end as usize])
+}
+
+fn name(strtab: &[u8], offset: u32) -> Result<String, ParseError> {
+    let bad = ParseError::BadName { offset };
+    let s = strtab.get(offset as usize..).ok_or(bad.clone())?;
+    let len = s.iter().position(|&b| b == 0).ok_or(bad)?;
+    Ok(String::from_utf8_lossy(&s[..len]).into_owned())
+}
+
+// The ELF header of a file in any format.
+fn read_header(bytes: &[u8]) -> Result<Header, ParseError> {
+    if bytes.len() < 4 || bytes[..4] != [0x7f, b'E', b'L', b'F'] {
+        return Err(ParseError::BadMagic);
+    }
+    // the class and data encoding determine the header layout, so check them
+    // before the header size
+    let class = bytes.get(4).copied().unwrap_or(0);
+    if class != Class::Elf32 as u8 && class != Class::Elf64 as u8 {
+        return Err(ParseError::BadClass(class));
+    }
+    let data = bytes.get(5).copied().unwrap_or(0);
+    let format = Format::from_ident(class, data).ok_or(ParseError::BadDataEncoding(data))?;
+    let hdr = table(bytes, "ELF header", 0, 1, format.ehsize())?;
+    Ok(format.read_header(hdr))
+}
+
+// The ELF header of an ELF-64 little-endian file.
+pub(crate) fn parse_header(bytes: &[u8]) -> Result<Header, ParseError> {
+    let header = read_header(bytes)?;
+    match format(&header) {
+        Format {
+            class: Class::Elf32,
+            ..
+        } => Err(ParseError::BadClass(header.class)),
+        Format {
+            encoding: Encoding::Msb,
+            ..
+        } => Err(ParseError::BadDataEncoding(header.data)),
+        _ => Ok(header),
+    }
+}
+
+// The format of a header returned by read_header.
+fn format(header: &Header) -> Format {
+    Format::from_ident(header.class, header.data).unwrap()
+}
+
+pub(crate) fn parse_segments(bytes: &[u8], header: &Header) -> Result<Vec<Phdr>, ParseError> {
+    if header.phnum == 0 {
+        return Ok(vec![]);
+    }
+    let format = format(header);
+    let entsize = format.phentsize();
+    if header.phentsize as usize != entsize {
+        return Err(ParseError::BadEntrySize {
+            what: "program header table",
+            size: header.phentsize as u64,
+        });
+    }
+    let phdrs = table(
+        bytes,
+        "program header table",
+        header.phoff,
+        header.phnum as u64,
+        entsize,
+    )?;
+    Ok(phdrs
+        .chunks(entsize)
+        .map(|entry| format.read_phdr(entry))
+        .collect())
+}
+
+// The section headers with the offsets of their names, without checking that
+// the sections themselves are in bounds.
+pub(crate) fn parse_section_headers(
+    bytes: &[u8],
+    header: &Header,
+) -> Result<Vec<(u32, Shdr)>, ParseError> {
+    if header.shnum == 0 {
+        return Ok(vec![]);
+    }
+    let entsize = elf64_shdr::SIZE.unwrap();
+    if header.shentsize as usize != entsize {
+        return Err(ParseError::BadEntrySize {
+            what: "section header table",
+            size: header.shentsize as u64,
+        });
+    }
+    let shdrs = table(
+        bytes,
+        "section header table",
+        header.shoff,
+        header.shnum as u64,
+        entsize,
+    )?;
+    Ok(shdrs
+        .chunks(entsize)
+        .map(|entry| {
+            let view = elf64_shdr::View::new(entry);
+            let shdr = Shdr {
+                _type: view._type().read(),
+                flags: view.flags().read(),
+                addr: view.addr().read(),
+                offset: view.offset().read(),
+                size: view.size().read(),
+                link: view.link().read(),
+                info: view.info().read(),
+                addralign: view.addralign().read(),
+                entsize: view.entsize().read(),
+            };
+            (view.name().read(), shdr)
+        })
+        .collect())
+}
+
+fn parse_sections(bytes: &[u8], header: &Header) -> Result<Vec<Section>, ParseError> {
+    let headers = parse_section_headers(bytes, header)?;
+    if headers.is_empty() {
+        return Ok(vec![]);
+    }
+    for (_, shdr) in &headers {
+        if shdr._type != SHT_NOBITS {
+            section_data(bytes, shdr)?;
+        }
+    }
+    let bad_index = ParseError::BadSectionIndex {
+        what: "e_shstrndx",
+        index: header.shstrndx as u32,
+    };
+    let (_, shstrtab) = headers
+        .get(header.shstrndx as usize)
+        .filter(|(_, shdr)| shdr._type != SHT_NOBITS)
+        .ok_or(bad_index)?;
+    let names = section_data(bytes, shstrtab)?;
+    headers
+        .into_iter()
+        .map(|(offset, header)| {
+            Ok(Section {
+                name: name(names, offset)?,
+                header,
+            })
+        })
+        .collect()
+}
+
+// The contents of a section in the file (not SHT_NOBITS).
+fn section_data<'a>(bytes: &'a [u8], shdr: &Shdr) -> Result<&'a [u8], ParseError> {
+    table(bytes, "section", shdr.offset, shdr.size, 1)
+}
+
+// The symbols in the first section of type `_type`, if any.
+fn parse_symbols(
+    bytes: &[u8],
+    sections: &[Section],
+    _type: u32,
+) -> Result<Vec<Symbol>, ParseError> {
+    let Some(symtab) = sections.iter().find(|s| s.header._type == _type) else {
+        return Ok(vec![]);
+    };
+    let entsize = elf64_sym::SIZE.unwrap();
+    if symtab.header.entsize as usize != entsize {
+        return Err(ParseError::BadEntrySize {
+            what: "symbol table",
+            size: symtab.header.entsize,
+        });
+    }
+    let strtab = sections
+        .get(symtab.header.link as usize)
+        .filter(|s| s.header._type != SHT_NOBITS)
+        .ok_or(ParseError::BadSectionIndex {
+            what: "symbol table sh_link",
+            index: symtab.header.link,
+        })?;
+    let names = section_data(bytes, &strtab.header)?;
+    section_data(bytes, &symtab.header)?
+        .chunks_exact(entsize)
+        .skip(1)
+        .map(|entry| {
+            let view = elf64_sym::View::new(entry);
+            let info = view.info().read();
+            Ok(Symbol {
+                name: name(names, view.name().read())?,
+                sym: Sym {
+                    bind: info >> 4,
+                    _type: info & 0xf,
+                    other: view.other().read(),
+                    shndx: view.shndx().read(),
+                    value: view.value().read(),
+                    size: view.size().read(),
+                },
+            })
+        })
+        .collect()
+}
+
+/// Parse and validate the ELF header and program headers of a file of any
+/// class and data encoding (see [`Format`]).
+pub fn parse_headers(bytes: &[u8]) -> Result<(Header, Vec<Phdr>), ParseError> {
+    let header = read_header(bytes)?;
+    let segments = parse_segments(bytes, &header)?;
+    Ok((header, segments))
+}
+
+/// Parse and validate an ELF-64 little-endian file.
+pub fn parse(bytes: &[u8]) -> Result<Elf, ParseError> {
+    let header = parse_header(bytes)?;
+    let segments = parse_segments(bytes, &header)?;
+    let sections = parse_sections(bytes, &header)?;
+    let symbols = parse_symbols(bytes, &sections, SHT_SYMTAB)?;
+    let dynamic_symbols = parse_symbols(bytes, &sections, SHT_DYNSYM)?;
+    Ok(Elf {
+        header,
+        segments,
+        sections,
+        symbols,
+        dynamic_symbols,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, ParseError};
+    use crate::{create_program, elf64_shdr, elf_bytes, ElfBuilder, Segment, Sym};
+    use crate::{PF_R, PF_W, PF_X};
+    use crate::{PROGRAM_VADDR, PT_LOAD, SHT_NOBITS, STB_GLOBAL, STT_FUNC, STT_OBJECT};
+    use binary_layout::Field;
+    use iced_x86::code_asm::*;
+
+    #[test]
+    fn test_parse_tiny() {
+        let elf = parse(&elf_bytes(&create_program())).unwrap();
+        let h = elf.header;
+        assert_eq!(
+            (2, 1, 2, 62, 1),
+            (h.class, h.data, h._type, h.machine, h.version)
+        );
+        assert_eq!((PROGRAM_VADDR, 64, 0), (h.entry, h.phoff, h.shoff));
+        assert_eq!((64, 56, 1), (h.ehsize, h.phentsize, h.phnum));
+        assert_eq!(1, elf.segments.len());
+        let p = elf.segments[0];
+        assert_eq!((PT_LOAD, PF_R | PF_W | PF_X), (p._type, p.flags));
+        assert_eq!(
+            (120, PROGRAM_VADDR, 7, 7),
+            (p.offset, p.vaddr, p.filesz, p.memsz)
+        );
+        assert!(elf.sections.is_empty());
+        assert!(elf.symbols.is_empty());
+    }
+
+    #[test]
+    fn test_parse_builder_output() {
+        let mut b = ElfBuilder::new();
+        b.symbol_table(true);
+        b.segment(Segment::new(PF_R | PF_X));
+        b.label("_start").unwrap();
+        b.asm().xor(edi, edi).unwrap();
+        b.asm().push(60).unwrap();
+        b.asm().pop(rax).unwrap();
+        b.asm().syscall().unwrap();
+        b.segment(Segment::new(PF_R | PF_W));
+        b.rodata("table", &[1, 2, 3]).unwrap();
+        b.bss("buf", 64).unwrap();
+        let image = b.link().unwrap();
+        let elf = parse(&image.bytes).unwrap();
+        assert_eq!(image.entry, elf.header.entry);
+        assert_eq!(2, elf.segments.len());
+        assert_eq!(PF_R | PF_W, elf.segments[1].flags);
+        assert_eq!(3, elf.segments[1].filesz);
+        let names: Vec<&str> = elf.sections.iter().map(|s| &s.name[..]).collect();
+        assert_eq!(
+            vec![
+                "",
+                ".text",
+                ".rodata",
+                ".bss",
+                ".strtab",
+                ".symtab",
+                ".shstrtab"
+            ],
+            names
+        );
+        assert_eq!(SHT_NOBITS, elf.section(".bss").unwrap().header._type);
+        let start = elf.symbol("_start").unwrap().sym;
+        let expected = Sym {
+            bind: STB_GLOBAL,
+            _type: STT_FUNC,
+            shndx: 1,
+            value: image.entry,
+            ..Sym::default()
+        };
+        assert_eq!(expected, start);
+        let table = elf.symbol("table").unwrap().sym;
+        assert_eq!((STT_OBJECT, 3), (table._type, table.size));
+        assert_eq!(image.symbols["buf"], elf.symbol("buf").unwrap().sym.value);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let tiny = elf_bytes(&create_program());
+        assert_eq!(Err(ParseError::BadMagic), parse(b"\x7fEL"));
+        assert_eq!(Err(ParseError::BadMagic), parse(&tiny[1..]));
+
+        let mut bytes = tiny.clone();
+        bytes[4] = 1;
+        assert_eq!(Err(ParseError::BadClass(1)), parse(&bytes));
+        let mut bytes = tiny.clone();
+        bytes[5] = 2;
+        assert_eq!(Err(ParseError::BadDataEncoding(2)), parse(&bytes));
+
+        assert!(matches!(
+            parse(&tiny[..40]),
+            Err(ParseError::Truncated {
+                what: "ELF header",
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse(&tiny[..100]),
+            Err(ParseError::Truncated {
+                what: "program header table",
+                ..
+            })
+        ));
+
+        let mut bytes = tiny.clone();
+        bytes[54] = 32; // e_phentsize
+        assert_eq!(
+            Err(ParseError::BadEntrySize {
+                what: "program header table",
+                size: 32
+            }),
+            parse(&bytes)
+        );
+
+        let mut b = ElfBuilder::new();
+        b.section_headers(true);
+        b.asm().nop().unwrap();
+        let bytes = b.build().unwrap();
+        assert!(matches!(
+            parse(&bytes[..bytes.len() - 1]),
+            Err(ParseError::Truncated {
+                what: "section header table",
+                ..
+            })
+        ));
+        let mut bad = bytes.clone();
+        bad[62] = 9; // e_shstrndx
+        assert!(matches!(
+            parse(&bad),
+            Err(ParseError::BadSectionIndex { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_bad_nobits() {
+        // sections that are never read can be anywhere, but names and
+        // symbols cannot come from a NOBITS section
+        let mut b = ElfBuilder::new();
+        b.symbol_table(true);
+        b.asm().nop().unwrap();
+        b.bss("buf", 64).unwrap();
+        let bytes = b.build().unwrap();
+        let elf = parse(&bytes).unwrap();
+        let bss = elf.sections.iter().position(|s| s.name == ".bss").unwrap();
+        let symtab = elf
+            .sections
+            .iter()
+            .position(|s| s.name == ".symtab")
+            .unwrap();
+        let shdr = |i: usize| elf.header.shoff as usize + i * elf64_shdr::SIZE.unwrap();
+        let mut bytes = bytes.clone();
+        // an offset and size that overflow when added
+        bytes[shdr(bss) + elf64_shdr::offset::OFFSET..][..8].fill(0xff);
+        bytes[shdr(bss) + elf64_shdr::size::OFFSET..][..8].fill(0xff);
+        assert!(parse(&bytes).is_ok());
+
+        let mut bad = bytes.clone();
+        bad[62..64].copy_from_slice(&(bss as u16).to_le_bytes()); // e_shstrndx
+        assert_eq!(
+            Err(ParseError::BadSectionIndex {
+                what: "e_shstrndx",
+                index: bss as u32
+            }),
+            parse(&bad)
+        );
+        let mut bad = bytes.clone();
+        let link = shdr(symtab) + elf64_shdr::link::OFFSET;
+        bad[link..][..4].copy_from_slice(&(bss as u32).to_le_bytes());
+        assert_eq!(
+            Err(ParseError::BadSectionIndex {
+                what: "symbol table sh_link",
+                index: bss as u32
+            }),
+            parse(&bad)
+        );
+
+        // the same offset in a PROGBITS section is truncated, not a panic
+        let mut bad = bytes.clone();
+        let _type = shdr(bss) + elf64_shdr::_type::OFFSET;
+        bad[_type..][..4].copy_from_slice(&1u32.to_le_bytes());
+        assert!(matches!(
+            parse(&bad),
+            Err(ParseError::Truncated {
+                what: "section",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_parse_foreign_binaries() {
+        // this test binary is linked by the system toolchain
+        let exe = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let elf = parse(&exe).unwrap();
+        assert_eq!(62, elf.header.machine);
+        assert!(elf.segments.iter().any(|p| p._type == PT_LOAD));
+        let text = elf.section(".text").unwrap();
+        assert!(text.header.size > 0);
+        assert!(!elf.dynamic_symbols.is_empty() || !elf.symbols.is_empty());
+
+        if let Ok(sh) = std::fs::read("/bin/sh") {
+            let elf = parse(&sh).unwrap();
+            assert!(elf.dynamic_symbols.iter().any(|s| !s.name.is_empty()));
+        }
+    }
+}
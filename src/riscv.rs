@@ -0,0 +1,1360 @@
+//! Minimal RISC-V (RV64) executables, with an encoder for the RV64I base
+//! instructions and the compressed (RVC) extension.
+//!
+//! Base instructions are 32-bit words and compressed ones are 16 bits, with
+//! code only needing 2-byte alignment when the C extension is used. With
+//! compression enabled, [`RvAssembler`] picks the compressed form of a base
+//! instruction whenever its operands fit, as assemblers do; branches to
+//! labels are always the full size, since their offsets aren't known yet. On
+//! Linux the syscall number goes in `a7` and the arguments in `a0` to `a5`.
+//!
+//! The ELF header flags record whether the code uses compressed instructions
+//! and which registers pass floating-point arguments (see the [RISC-V ELF
+//! psABI](https://github.com/riscv-non-isa/riscv-elf-psabi-doc)).
+
+use crate::{elf64_bytes, elf64_hdr, write_executable};
+use std::fmt;
+use std::path::Path;
+
+/// The `e_machine` value for RISC-V.
+pub const EM_RISCV: u16 = 243;
+
+/// `e_flags` bit for code that uses compressed instructions.
+pub const EF_RISCV_RVC: u32 = 0x1;
+/// `e_flags` float ABI: floating-point arguments are passed in integer
+/// registers.
+pub const EF_RISCV_FLOAT_ABI_SOFT: u32 = 0x0;
+/// `e_flags` float ABI: single-precision floating-point registers.
+pub const EF_RISCV_FLOAT_ABI_SINGLE: u32 = 0x2;
+/// `e_flags` float ABI: double-precision floating-point registers (`lp64d`,
+/// the usual Linux ABI).
+pub const EF_RISCV_FLOAT_ABI_DOUBLE: u32 = 0x4;
+/// `e_flags` float ABI: quad-precision floating-point registers.
+pub const EF_RISCV_FLOAT_ABI_QUAD: u32 = 0x6;
+/// Mask for the float ABI in `e_flags`.
+pub const EF_RISCV_FLOAT_ABI: u32 = 0x6;
+
+/// An integer register `x0` to `x31`, with constants for the ABI names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RvReg(u8);
+
+impl RvReg {
+    pub const ZERO: RvReg = RvReg(0);
+    pub const RA: RvReg = RvReg(1);
+    pub const SP: RvReg = RvReg(2);
+    pub const GP: RvReg = RvReg(3);
+    pub const TP: RvReg = RvReg(4);
+    pub const T0: RvReg = RvReg(5);
+    pub const T1: RvReg = RvReg(6);
+    pub const T2: RvReg = RvReg(7);
+    pub const S0: RvReg = RvReg(8);
+    pub const S1: RvReg = RvReg(9);
+    pub const A0: RvReg = RvReg(10);
+    pub const A1: RvReg = RvReg(11);
+    pub const A2: RvReg = RvReg(12);
+    pub const A3: RvReg = RvReg(13);
+    pub const A4: RvReg = RvReg(14);
+    pub const A5: RvReg = RvReg(15);
+    pub const A6: RvReg = RvReg(16);
+    pub const A7: RvReg = RvReg(17);
+
+    /// The register `xn`.
+    pub const fn x(n: u8) -> RvReg {
+        assert!(n < 32, "no such register");
+        RvReg(n)
+    }
+
+    fn bits(self) -> u32 {
+        self.0 as u32
+    }
+
+    // The 3-bit field for x8 to x15, the registers most compressed
+    // instructions can name.
+    fn prime(self) -> Option<u32> {
+        (8..16).contains(&self.0).then(|| self.0 as u32 - 8)
+    }
+}
+
+/// A branch target, created with [`RvAssembler::create_label`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RvLabel(usize);
+
+/// An error from [`RvAssembler`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RvError {
+    /// `value` doesn't fit the immediate field of `instruction`.
+    Immediate {
+        instruction: &'static str,
+        value: i64,
+    },
+    /// The operands of the compressed `instruction` have no compressed
+    /// encoding.
+    Operands { instruction: &'static str },
+    /// A compressed instruction was used without enabling compression.
+    NoCompressed,
+    /// A label was used but never set.
+    UnboundLabel,
+    /// A label was set twice.
+    LabelRedefined,
+    /// The branch at byte `offset` can't reach its label.
+    OutOfRange { offset: usize },
+}
+
+impl fmt::Display for RvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RvError::Immediate { instruction, value } => {
+                write!(f, "immediate {value:#x} does not fit in {instruction}")
+            }
+            RvError::Operands { instruction } => {
+                write!(f, "operands are not encodable with {instruction}")
+            }
+            RvError::NoCompressed => write!(f, "compressed instructions are not enabled"),
+            RvError::UnboundLabel => write!(f, "label was never set"),
+            RvError::LabelRedefined => write!(f, "label was set twice"),
+            RvError::OutOfRange { offset } => {
+                write!(f, "branch at {offset:#x} is out of range")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RvError {}
+
+// How a label's byte offset is placed in the instruction.
+#[derive(Debug, Clone, Copy)]
+enum Fixup {
+    // conditional branches (B-type)
+    Branch,
+    // jal (J-type)
+    Jal,
+    // an auipc followed by an I-type instruction
+    Pcrel,
+    // c.j
+    CJump,
+    // c.beqz and c.bnez
+    CBranch,
+}
+
+// `value` as a signed field of `bits` bits, or None if it doesn't fit.
+fn fits(value: i64, bits: u32) -> Option<u32> {
+    let half = 1i64 << (bits - 1);
+    (-half..half)
+        .contains(&value)
+        .then(|| value as u32 & ((1 << bits) - 1))
+}
+
+fn simm(instruction: &'static str, value: i64, bits: u32) -> Result<u32, RvError> {
+    fits(value, bits).ok_or(RvError::Immediate { instruction, value })
+}
+
+fn bit(value: u32, i: u32) -> u32 {
+    (value >> i) & 1
+}
+
+fn r_type(funct7: u32, rs2: RvReg, rs1: RvReg, funct3: u32, rd: RvReg, opcode: u32) -> u32 {
+    funct7 << 25 | rs2.bits() << 20 | rs1.bits() << 15 | funct3 << 12 | rd.bits() << 7 | opcode
+}
+
+fn i_type(imm: u32, rs1: RvReg, funct3: u32, rd: RvReg, opcode: u32) -> u32 {
+    imm << 20 | rs1.bits() << 15 | funct3 << 12 | rd.bits() << 7 | opcode
+}
+
+fn s_type(imm: u32, rs2: RvReg, rs1: RvReg, funct3: u32) -> u32 {
+    (imm >> 5) << 25 | rs2.bits() << 20 | rs1.bits() << 15 | funct3 << 12 | (imm & 0x1f) << 7 | 0x23
+}
+
+// The immediate bits of a B-type branch to byte offset `imm`.
+fn b_imm(imm: u32) -> u32 {
+    bit(imm, 12) << 31 | (imm >> 5 & 0x3f) << 25 | (imm >> 1 & 0xf) << 8 | bit(imm, 11) << 7
+}
+
+// The immediate bits of a jal to byte offset `imm`.
+fn j_imm(imm: u32) -> u32 {
+    bit(imm, 20) << 31 | (imm >> 1 & 0x3ff) << 21 | bit(imm, 11) << 20 | (imm >> 12 & 0xff) << 12
+}
+
+// The immediate bits of a c.j to byte offset `imm`.
+fn cj_imm(imm: u32) -> u32 {
+    bit(imm, 11) << 12
+        | bit(imm, 4) << 11
+        | (imm >> 8 & 3) << 9
+        | bit(imm, 10) << 8
+        | bit(imm, 6) << 7
+        | bit(imm, 7) << 6
+        | (imm >> 1 & 7) << 3
+        | bit(imm, 5) << 2
+}
+
+// The immediate bits of a c.beqz or c.bnez to byte offset `imm`.
+fn cb_imm(imm: u32) -> u32 {
+    bit(imm, 8) << 12
+        | (imm >> 3 & 3) << 10
+        | (imm >> 6 & 3) << 5
+        | (imm >> 1 & 3) << 3
+        | bit(imm, 5) << 2
+}
+
+// The CI format, with a 6-bit immediate split around rd.
+fn ci(funct3: u32, rd: RvReg, imm: u32, quadrant: u32) -> u16 {
+    (funct3 << 13 | bit(imm, 5) << 12 | rd.bits() << 7 | (imm & 0x1f) << 2 | quadrant) as u16
+}
+
+// The CR format, with two full registers.
+fn cr(funct4: u32, rd: RvReg, rs2: RvReg) -> u16 {
+    (funct4 << 12 | rd.bits() << 7 | rs2.bits() << 2 | 2) as u16
+}
+
+// The compressed encodings, or None if the operands don't fit.
+mod c {
+    use super::{ci, cr, fits, RvReg};
+
+    pub fn nop() -> u16 {
+        0x0001
+    }
+
+    pub fn li(rd: RvReg, imm: i64) -> Option<u16> {
+        (rd.0 != 0).then_some(())?;
+        Some(ci(2, rd, fits(imm, 6)?, 1))
+    }
+
+    pub fn addi(rd: RvReg, imm: i64) -> Option<u16> {
+        (rd.0 != 0 && imm != 0).then_some(())?;
+        Some(ci(0, rd, fits(imm, 6)?, 1))
+    }
+
+    pub fn addiw(rd: RvReg, imm: i64) -> Option<u16> {
+        (rd.0 != 0).then_some(())?;
+        Some(ci(1, rd, fits(imm, 6)?, 1))
+    }
+
+    // `imm` is the 20-bit field of lui, which must be the sign extension of
+    // its low 6 bits
+    pub fn lui(rd: RvReg, imm: u32) -> Option<u16> {
+        (rd.0 != 0 && rd.0 != 2 && imm != 0).then_some(())?;
+        let imm = fits(((imm << 12) as i32 >> 12) as i64, 6)?;
+        Some(ci(3, rd, imm, 1))
+    }
+
+    pub fn slli(rd: RvReg, shamt: u32) -> Option<u16> {
+        (rd.0 != 0 && shamt != 0 && shamt < 64).then_some(())?;
+        Some(ci(0, rd, shamt, 2))
+    }
+
+    pub fn mv(rd: RvReg, rs2: RvReg) -> Option<u16> {
+        (rd.0 != 0 && rs2.0 != 0).then_some(cr(8, rd, rs2))
+    }
+
+    pub fn add(rd: RvReg, rs2: RvReg) -> Option<u16> {
+        (rd.0 != 0 && rs2.0 != 0).then_some(cr(9, rd, rs2))
+    }
+
+    pub fn jr(rs1: RvReg) -> Option<u16> {
+        (rs1.0 != 0).then_some(cr(8, rs1, RvReg::ZERO))
+    }
+
+    pub fn jalr(rs1: RvReg) -> Option<u16> {
+        (rs1.0 != 0).then_some(cr(9, rs1, RvReg::ZERO))
+    }
+
+    pub fn ebreak() -> u16 {
+        cr(9, RvReg::ZERO, RvReg::ZERO)
+    }
+
+    // c.sub, c.xor, c.or and c.and
+    pub fn arith(funct2: u32, rd: RvReg, rs2: RvReg) -> Option<u16> {
+        Some((0x8c01 | rd.prime()? << 7 | funct2 << 5 | rs2.prime()? << 2) as u16)
+    }
+
+    // c.ld and c.sd, by their funct3
+    pub fn load_store(funct3: u32, rt: RvReg, rs1: RvReg, offset: i64) -> Option<u16> {
+        (0..256).contains(&offset).then_some(())?;
+        (offset % 8 == 0).then_some(())?;
+        let u = offset as u32;
+        let word = funct3 << 13 | (u >> 3 & 7) << 10 | rs1.prime()? << 7 | (u >> 6 & 3) << 5;
+        Some((word | rt.prime()? << 2) as u16)
+    }
+
+    pub fn ldsp(rd: RvReg, offset: i64) -> Option<u16> {
+        (rd.0 != 0 && (0..512).contains(&offset) && offset % 8 == 0).then_some(())?;
+        let u = offset as u32;
+        Some(ci(
+            3,
+            rd,
+            (u >> 5 & 1) << 5 | (u >> 3 & 3) << 3 | (u >> 6 & 7),
+            2,
+        ))
+    }
+
+    pub fn sdsp(rs2: RvReg, offset: i64) -> Option<u16> {
+        ((0..512).contains(&offset) && offset % 8 == 0).then_some(())?;
+        let u = offset as u32;
+        let imm = (u >> 3 & 7) << 3 | (u >> 6 & 7);
+        Some((0xe002 | imm << 7 | rs2.bits() << 2) as u16)
+    }
+}
+
+/// Assembles RV64I instructions, and optionally compressed ones, into
+/// position-independent machine code.
+///
+/// Each method appends one instruction (except [`li`](Self::li) and
+/// [`la`](Self::la)), and fails if an immediate doesn't fit. Branches to
+/// labels are resolved by [`assemble`](Self::assemble).
+#[derive(Debug, Clone, Default)]
+pub struct RvAssembler {
+    code: Vec<u8>,
+    rvc: bool,
+    labels: Vec<Option<usize>>,
+    fixups: Vec<(usize, RvLabel, Fixup)>,
+}
+
+impl RvAssembler {
+    /// An empty program, using compressed instructions if `rvc` is set.
+    pub fn new(rvc: bool) -> Self {
+        RvAssembler {
+            rvc,
+            ..Self::default()
+        }
+    }
+
+    /// Whether compressed instructions are enabled.
+    pub fn rvc(&self) -> bool {
+        self.rvc
+    }
+
+    /// The header flags for this code: [`EF_RISCV_RVC`] if compression is
+    /// enabled, and the soft-float ABI, since nothing here touches the
+    /// floating-point registers.
+    pub fn flags(&self) -> u32 {
+        let rvc = if self.rvc { EF_RISCV_RVC } else { 0 };
+        rvc | EF_RISCV_FLOAT_ABI_SOFT
+    }
+
+    /// Create a label, to be placed later with [`set_label`](Self::set_label).
+    pub fn create_label(&mut self) -> RvLabel {
+        self.labels.push(None);
+        RvLabel(self.labels.len() - 1)
+    }
+
+    /// Place `label` at the next instruction.
+    pub fn set_label(&mut self, label: RvLabel) -> Result<(), RvError> {
+        let slot = &mut self.labels[label.0];
+        if slot.is_some() {
+            return Err(RvError::LabelRedefined);
+        }
+        *slot = Some(self.code.len());
+        Ok(())
+    }
+
+    fn emit(&mut self, word: u32) -> Result<(), RvError> {
+        self.code.extend_from_slice(&word.to_le_bytes());
+        Ok(())
+    }
+
+    fn emit_fixup(&mut self, word: u32, label: RvLabel, fixup: Fixup) -> Result<(), RvError> {
+        self.fixups.push((self.code.len(), label, fixup));
+        self.emit(word)
+    }
+
+    // Emit `word`, or its compressed form if there is one and compression
+    // is enabled.
+    fn emit_or_compress(&mut self, word: u32, compressed: Option<u16>) -> Result<(), RvError> {
+        match compressed {
+            Some(half) if self.rvc => {
+                self.code.extend_from_slice(&half.to_le_bytes());
+                Ok(())
+            }
+            _ => self.emit(word),
+        }
+    }
+
+    // Emit an explicitly compressed instruction.
+    fn compressed(&mut self, instruction: &'static str, half: Option<u16>) -> Result<(), RvError> {
+        if !self.rvc {
+            return Err(RvError::NoCompressed);
+        }
+        let half = half.ok_or(RvError::Operands { instruction })?;
+        self.code.extend_from_slice(&half.to_le_bytes());
+        Ok(())
+    }
+
+    /// `lui rd, imm`: set `rd` to the sign extension of `imm << 12`, where
+    /// `imm` is 20 bits.
+    pub fn lui(&mut self, rd: RvReg, imm: u32) -> Result<(), RvError> {
+        if imm >> 20 != 0 {
+            return Err(RvError::Immediate {
+                instruction: "lui",
+                value: imm as i64,
+            });
+        }
+        self.emit_or_compress(imm << 12 | rd.bits() << 7 | 0x37, c::lui(rd, imm))
+    }
+
+    /// `auipc rd, imm`: set `rd` to the address of this instruction plus
+    /// `imm << 12`.
+    pub fn auipc(&mut self, rd: RvReg, imm: u32) -> Result<(), RvError> {
+        if imm >> 20 != 0 {
+            return Err(RvError::Immediate {
+                instruction: "auipc",
+                value: imm as i64,
+            });
+        }
+        self.emit(imm << 12 | rd.bits() << 7 | 0x17)
+    }
+
+    fn op_imm(
+        &mut self,
+        instruction: &'static str,
+        funct3: u32,
+        rd: RvReg,
+        rs1: RvReg,
+        imm: i64,
+    ) -> Result<(), RvError> {
+        let imm = simm(instruction, imm, 12)?;
+        self.emit(i_type(imm, rs1, funct3, rd, 0x13))
+    }
+
+    /// `addi rd, rs1, imm`, with a 12-bit signed immediate.
+    pub fn addi(&mut self, rd: RvReg, rs1: RvReg, imm: i64) -> Result<(), RvError> {
+        let word = i_type(simm("addi", imm, 12)?, rs1, 0, rd, 0x13);
+        let compressed = if rd == RvReg::ZERO && rs1 == RvReg::ZERO && imm == 0 {
+            Some(c::nop())
+        } else if rs1 == RvReg::ZERO {
+            c::li(rd, imm)
+        } else if imm == 0 {
+            c::mv(rd, rs1)
+        } else if rd == rs1 {
+            c::addi(rd, imm)
+        } else {
+            None
+        };
+        self.emit_or_compress(word, compressed)
+    }
+
+    /// `addiw rd, rs1, imm`: add and sign-extend the low 32 bits.
+    pub fn addiw(&mut self, rd: RvReg, rs1: RvReg, imm: i64) -> Result<(), RvError> {
+        let word = i_type(simm("addiw", imm, 12)?, rs1, 0, rd, 0x1b);
+        let compressed = (rd == rs1).then(|| c::addiw(rd, imm)).flatten();
+        self.emit_or_compress(word, compressed)
+    }
+
+    /// `slti rd, rs1, imm`: set `rd` to 1 if `rs1 < imm` (signed).
+    pub fn slti(&mut self, rd: RvReg, rs1: RvReg, imm: i64) -> Result<(), RvError> {
+        self.op_imm("slti", 2, rd, rs1, imm)
+    }
+
+    /// `sltiu rd, rs1, imm`: set `rd` to 1 if `rs1 < imm` (unsigned).
+    pub fn sltiu(&mut self, rd: RvReg, rs1: RvReg, imm: i64) -> Result<(), RvError> {
+        self.op_imm("sltiu", 3, rd, rs1, imm)
+    }
+
+    /// `xori rd, rs1, imm`.
+    pub fn xori(&mut self, rd: RvReg, rs1: RvReg, imm: i64) -> Result<(), RvError> {
+        self.op_imm("xori", 4, rd, rs1, imm)
+    }
+
+    /// `ori rd, rs1, imm`.
+    pub fn ori(&mut self, rd: RvReg, rs1: RvReg, imm: i64) -> Result<(), RvError> {
+        self.op_imm("ori", 6, rd, rs1, imm)
+    }
+
+    /// `andi rd, rs1, imm`.
+    pub fn andi(&mut self, rd: RvReg, rs1: RvReg, imm: i64) -> Result<(), RvError> {
+        self.op_imm("andi", 7, rd, rs1, imm)
+    }
+
+    // Shifts by an immediate; the word forms only have 5 bits of shift.
+    fn shift_imm(
+        &mut self,
+        instruction: &'static str,
+        funct: u32,
+        word: bool,
+        rd: RvReg,
+        rs1: RvReg,
+        shamt: u32,
+    ) -> Result<u32, RvError> {
+        let limit = if word { 32 } else { 64 };
+        if shamt >= limit {
+            return Err(RvError::Immediate {
+                instruction,
+                value: shamt as i64,
+            });
+        }
+        let (funct3, high) = (funct & 7, funct >> 3);
+        let opcode = if word { 0x1b } else { 0x13 };
+        Ok(i_type(high << 6 | shamt, rs1, funct3, rd, opcode))
+    }
+
+    /// `slli rd, rs1, shamt`.
+    pub fn slli(&mut self, rd: RvReg, rs1: RvReg, shamt: u32) -> Result<(), RvError> {
+        let word = self.shift_imm("slli", 1, false, rd, rs1, shamt)?;
+        let compressed = (rd == rs1).then(|| c::slli(rd, shamt)).flatten();
+        self.emit_or_compress(word, compressed)
+    }
+
+    /// `srli rd, rs1, shamt`.
+    pub fn srli(&mut self, rd: RvReg, rs1: RvReg, shamt: u32) -> Result<(), RvError> {
+        let word = self.shift_imm("srli", 5, false, rd, rs1, shamt)?;
+        self.emit(word)
+    }
+
+    /// `srai rd, rs1, shamt`.
+    pub fn srai(&mut self, rd: RvReg, rs1: RvReg, shamt: u32) -> Result<(), RvError> {
+        let word = self.shift_imm("srai", 0x10 << 3 | 5, false, rd, rs1, shamt)?;
+        self.emit(word)
+    }
+
+    /// `slliw rd, rs1, shamt`.
+    pub fn slliw(&mut self, rd: RvReg, rs1: RvReg, shamt: u32) -> Result<(), RvError> {
+        let word = self.shift_imm("slliw", 1, true, rd, rs1, shamt)?;
+        self.emit(word)
+    }
+
+    /// `srliw rd, rs1, shamt`.
+    pub fn srliw(&mut self, rd: RvReg, rs1: RvReg, shamt: u32) -> Result<(), RvError> {
+        let word = self.shift_imm("srliw", 5, true, rd, rs1, shamt)?;
+        self.emit(word)
+    }
+
+    /// `sraiw rd, rs1, shamt`.
+    pub fn sraiw(&mut self, rd: RvReg, rs1: RvReg, shamt: u32) -> Result<(), RvError> {
+        let word = self.shift_imm("sraiw", 0x10 << 3 | 5, true, rd, rs1, shamt)?;
+        self.emit(word)
+    }
+
+    fn op(
+        &mut self,
+        funct7: u32,
+        funct3: u32,
+        rd: RvReg,
+        rs1: RvReg,
+        rs2: RvReg,
+    ) -> Result<(), RvError> {
+        self.emit(r_type(funct7, rs2, rs1, funct3, rd, 0x33))
+    }
+
+    fn op32(
+        &mut self,
+        funct7: u32,
+        funct3: u32,
+        rd: RvReg,
+        rs1: RvReg,
+        rs2: RvReg,
+    ) -> Result<(), RvError> {
+        self.emit(r_type(funct7, rs2, rs1, funct3, rd, 0x3b))
+    }
+
+    // The register-register operations with a c.sub-style compressed form.
+    fn op_arith(
+        &mut self,
+        funct7: u32,
+        funct3: u32,
+        funct2: u32,
+        rd: RvReg,
+        rs1: RvReg,
+        rs2: RvReg,
+    ) -> Result<(), RvError> {
+        let word = r_type(funct7, rs2, rs1, funct3, rd, 0x33);
+        let compressed = (rd == rs1).then(|| c::arith(funct2, rd, rs2)).flatten();
+        self.emit_or_compress(word, compressed)
+    }
+
+    /// `add rd, rs1, rs2`.
+    pub fn add(&mut self, rd: RvReg, rs1: RvReg, rs2: RvReg) -> Result<(), RvError> {
+        let word = r_type(0, rs2, rs1, 0, rd, 0x33);
+        let compressed = if rs1 == RvReg::ZERO {
+            c::mv(rd, rs2)
+        } else if rd == rs1 {
+            c::add(rd, rs2)
+        } else {
+            None
+        };
+        self.emit_or_compress(word, compressed)
+    }
+
+    /// `sub rd, rs1, rs2`.
+    pub fn sub(&mut self, rd: RvReg, rs1: RvReg, rs2: RvReg) -> Result<(), RvError> {
+        self.op_arith(0x20, 0, 0, rd, rs1, rs2)
+    }
+
+    /// `sll rd, rs1, rs2`.
+    pub fn sll(&mut self, rd: RvReg, rs1: RvReg, rs2: RvReg) -> Result<(), RvError> {
+        self.op(0, 1, rd, rs1, rs2)
+    }
+
+    /// `slt rd, rs1, rs2`.
+    pub fn slt(&mut self, rd: RvReg, rs1: RvReg, rs2: RvReg) -> Result<(), RvError> {
+        self.op(0, 2, rd, rs1, rs2)
+    }
+
+    /// `sltu rd, rs1, rs2`.
+    pub fn sltu(&mut self, rd: RvReg, rs1: RvReg, rs2: RvReg) -> Result<(), RvError> {
+        self.op(0, 3, rd, rs1, rs2)
+    }
+
+    /// `xor rd, rs1, rs2`.
+    pub fn xor(&mut self, rd: RvReg, rs1: RvReg, rs2: RvReg) -> Result<(), RvError> {
+        self.op_arith(0, 4, 1, rd, rs1, rs2)
+    }
+
+    /// `srl rd, rs1, rs2`.
+    pub fn srl(&mut self, rd: RvReg, rs1: RvReg, rs2: RvReg) -> Result<(), RvError> {
+        self.op(0, 5, rd, rs1, rs2)
+    }
+
+    /// `sra rd, rs1, rs2`.
+    pub fn sra(&mut self, rd: RvReg, rs1: RvReg, rs2: RvReg) -> Result<(), RvError> {
+        self.op(0x20, 5, rd, rs1, rs2)
+    }
+
+    /// `or rd, rs1, rs2`.
+    pub fn or(&mut self, rd: RvReg, rs1: RvReg, rs2: RvReg) -> Result<(), RvError> {
+        self.op_arith(0, 6, 2, rd, rs1, rs2)
+    }
+
+    /// `and rd, rs1, rs2`.
+    pub fn and(&mut self, rd: RvReg, rs1: RvReg, rs2: RvReg) -> Result<(), RvError> {
+        self.op_arith(0, 7, 3, rd, rs1, rs2)
+    }
+
+    /// `addw rd, rs1, rs2`.
+    pub fn addw(&mut self, rd: RvReg, rs1: RvReg, rs2: RvReg) -> Result<(), RvError> {
+        self.op32(0, 0, rd, rs1, rs2)
+    }
+
+    /// `subw rd, rs1, rs2`.
+    pub fn subw(&mut self, rd: RvReg, rs1: RvReg, rs2: RvReg) -> Result<(), RvError> {
+        self.op32(0x20, 0, rd, rs1, rs2)
+    }
+
+    /// `sllw rd, rs1, rs2`.
+    pub fn sllw(&mut self, rd: RvReg, rs1: RvReg, rs2: RvReg) -> Result<(), RvError> {
+        self.op32(0, 1, rd, rs1, rs2)
+    }
+
+    /// `srlw rd, rs1, rs2`.
+    pub fn srlw(&mut self, rd: RvReg, rs1: RvReg, rs2: RvReg) -> Result<(), RvError> {
+        self.op32(0, 5, rd, rs1, rs2)
+    }
+
+    /// `sraw rd, rs1, rs2`.
+    pub fn sraw(&mut self, rd: RvReg, rs1: RvReg, rs2: RvReg) -> Result<(), RvError> {
+        self.op32(0x20, 5, rd, rs1, rs2)
+    }
+
+    fn load(
+        &mut self,
+        instruction: &'static str,
+        funct3: u32,
+        rd: RvReg,
+        rs1: RvReg,
+        offset: i64,
+    ) -> Result<u32, RvError> {
+        Ok(i_type(
+            simm(instruction, offset, 12)?,
+            rs1,
+            funct3,
+            rd,
+            0x03,
+        ))
+    }
+
+    /// `lb rd, offset(rs1)`.
+    pub fn lb(&mut self, rd: RvReg, rs1: RvReg, offset: i64) -> Result<(), RvError> {
+        let word = self.load("lb", 0, rd, rs1, offset)?;
+        self.emit(word)
+    }
+
+    /// `lh rd, offset(rs1)`.
+    pub fn lh(&mut self, rd: RvReg, rs1: RvReg, offset: i64) -> Result<(), RvError> {
+        let word = self.load("lh", 1, rd, rs1, offset)?;
+        self.emit(word)
+    }
+
+    /// `lw rd, offset(rs1)`.
+    pub fn lw(&mut self, rd: RvReg, rs1: RvReg, offset: i64) -> Result<(), RvError> {
+        let word = self.load("lw", 2, rd, rs1, offset)?;
+        self.emit(word)
+    }
+
+    /// `ld rd, offset(rs1)`.
+    pub fn ld(&mut self, rd: RvReg, rs1: RvReg, offset: i64) -> Result<(), RvError> {
+        let word = self.load("ld", 3, rd, rs1, offset)?;
+        let compressed = if rs1 == RvReg::SP {
+            c::ldsp(rd, offset)
+        } else {
+            c::load_store(3, rd, rs1, offset)
+        };
+        self.emit_or_compress(word, compressed)
+    }
+
+    /// `lbu rd, offset(rs1)`.
+    pub fn lbu(&mut self, rd: RvReg, rs1: RvReg, offset: i64) -> Result<(), RvError> {
+        let word = self.load("lbu", 4, rd, rs1, offset)?;
+        self.emit(word)
+    }
+
+    /// `lhu rd, offset(rs1)`.
+    pub fn lhu(&mut self, rd: RvReg, rs1: RvReg, offset: i64) -> Result<(), RvError> {
+        let word = self.load("lhu", 5, rd, rs1, offset)?;
+        self.emit(word)
+    }
+
+    /// `lwu rd, offset(rs1)`.
+    pub fn lwu(&mut self, rd: RvReg, rs1: RvReg, offset: i64) -> Result<(), RvError> {
+        let word = self.load("lwu", 6, rd, rs1, offset)?;
+        self.emit(word)
+    }
+
+    /// `sb rs2, offset(rs1)`.
+    pub fn sb(&mut self, rs2: RvReg, rs1: RvReg, offset: i64) -> Result<(), RvError> {
+        self.emit(s_type(simm("sb", offset, 12)?, rs2, rs1, 0))
+    }
+
+    /// `sh rs2, offset(rs1)`.
+    pub fn sh(&mut self, rs2: RvReg, rs1: RvReg, offset: i64) -> Result<(), RvError> {
+        self.emit(s_type(simm("sh", offset, 12)?, rs2, rs1, 1))
+    }
+
+    /// `sw rs2, offset(rs1)`.
+    pub fn sw(&mut self, rs2: RvReg, rs1: RvReg, offset: i64) -> Result<(), RvError> {
+        self.emit(s_type(simm("sw", offset, 12)?, rs2, rs1, 2))
+    }
+
+    /// `sd rs2, offset(rs1)`.
+    pub fn sd(&mut self, rs2: RvReg, rs1: RvReg, offset: i64) -> Result<(), RvError> {
+        let word = s_type(simm("sd", offset, 12)?, rs2, rs1, 3);
+        let compressed = if rs1 == RvReg::SP {
+            c::sdsp(rs2, offset)
+        } else {
+            c::load_store(7, rs2, rs1, offset)
+        };
+        self.emit_or_compress(word, compressed)
+    }
+
+    /// `jal rd, label`: jump within 1MiB, saving the return address in `rd`.
+    pub fn jal(&mut self, rd: RvReg, label: RvLabel) -> Result<(), RvError> {
+        self.emit_fixup(rd.bits() << 7 | 0x6f, label, Fixup::Jal)
+    }
+
+    /// `jalr rd, offset(rs1)`: jump to `rs1 + offset`, saving the return
+    /// address in `rd`.
+    pub fn jalr(&mut self, rd: RvReg, rs1: RvReg, offset: i64) -> Result<(), RvError> {
+        let word = i_type(simm("jalr", offset, 12)?, rs1, 0, rd, 0x67);
+        let compressed = match (rd, offset) {
+            (RvReg::ZERO, 0) => c::jr(rs1),
+            (RvReg::RA, 0) => c::jalr(rs1),
+            _ => None,
+        };
+        self.emit_or_compress(word, compressed)
+    }
+
+    fn branch(
+        &mut self,
+        funct3: u32,
+        rs1: RvReg,
+        rs2: RvReg,
+        label: RvLabel,
+    ) -> Result<(), RvError> {
+        let word = r_type(0, rs2, rs1, funct3, RvReg::ZERO, 0x63);
+        self.emit_fixup(word, label, Fixup::Branch)
+    }
+
+    /// `beq rs1, rs2, label`, within 4KiB.
+    pub fn beq(&mut self, rs1: RvReg, rs2: RvReg, label: RvLabel) -> Result<(), RvError> {
+        self.branch(0, rs1, rs2, label)
+    }
+
+    /// `bne rs1, rs2, label`.
+    pub fn bne(&mut self, rs1: RvReg, rs2: RvReg, label: RvLabel) -> Result<(), RvError> {
+        self.branch(1, rs1, rs2, label)
+    }
+
+    /// `blt rs1, rs2, label` (signed).
+    pub fn blt(&mut self, rs1: RvReg, rs2: RvReg, label: RvLabel) -> Result<(), RvError> {
+        self.branch(4, rs1, rs2, label)
+    }
+
+    /// `bge rs1, rs2, label` (signed).
+    pub fn bge(&mut self, rs1: RvReg, rs2: RvReg, label: RvLabel) -> Result<(), RvError> {
+        self.branch(5, rs1, rs2, label)
+    }
+
+    /// `bltu rs1, rs2, label` (unsigned).
+    pub fn bltu(&mut self, rs1: RvReg, rs2: RvReg, label: RvLabel) -> Result<(), RvError> {
+        self.branch(6, rs1, rs2, label)
+    }
+
+    /// `bgeu rs1, rs2, label` (unsigned).
+    pub fn bgeu(&mut self, rs1: RvReg, rs2: RvReg, label: RvLabel) -> Result<(), RvError> {
+        self.branch(7, rs1, rs2, label)
+    }
+
+    /// `ecall`, which makes a syscall.
+    pub fn ecall(&mut self) -> Result<(), RvError> {
+        self.emit(0x00000073)
+    }
+
+    /// `ebreak`.
+    pub fn ebreak(&mut self) -> Result<(), RvError> {
+        self.emit_or_compress(0x00100073, Some(c::ebreak()))
+    }
+
+    /// `nop`, encoded as `addi zero, zero, 0`.
+    pub fn nop(&mut self) -> Result<(), RvError> {
+        self.addi(RvReg::ZERO, RvReg::ZERO, 0)
+    }
+
+    /// `mv rd, rs`, encoded as `addi rd, rs, 0`.
+    pub fn mv(&mut self, rd: RvReg, rs: RvReg) -> Result<(), RvError> {
+        self.addi(rd, rs, 0)
+    }
+
+    /// `ret`, encoded as `jalr zero, 0(ra)`.
+    pub fn ret(&mut self) -> Result<(), RvError> {
+        self.jalr(RvReg::ZERO, RvReg::RA, 0)
+    }
+
+    /// `j label`, encoded as `jal zero, label`.
+    pub fn j(&mut self, label: RvLabel) -> Result<(), RvError> {
+        self.jal(RvReg::ZERO, label)
+    }
+
+    /// Load the constant `value` into `rd`, with the same sequence as the
+    /// GNU and LLVM assemblers' `li`: `lui` and `addiw` for 32-bit values,
+    /// and otherwise the upper bits (loaded recursively) shifted into place
+    /// and the low 12 bits added. Returns the number of instructions.
+    pub fn li(&mut self, rd: RvReg, value: i64) -> Result<usize, RvError> {
+        if let Ok(value) = i32::try_from(value) {
+            let hi20 = ((value as i64 + 0x800) >> 12) as u32 & 0xfffff;
+            let lo12 = (value << 20 >> 20) as i64;
+            let mut n = 0;
+            if hi20 != 0 {
+                self.lui(rd, hi20)?;
+                n += 1;
+            }
+            if lo12 != 0 || hi20 == 0 {
+                if hi20 != 0 {
+                    self.addiw(rd, rd, lo12)?;
+                } else {
+                    self.addi(rd, RvReg::ZERO, lo12)?;
+                }
+                n += 1;
+            }
+            return Ok(n);
+        }
+        let lo12 = value << 52 >> 52;
+        let hi52 = (value as u64).wrapping_add(0x800) >> 12;
+        let shift = 12 + hi52.trailing_zeros();
+        let hi = ((hi52 >> (shift - 12)) << shift) as i64 >> shift;
+        let mut n = self.li(rd, hi)?;
+        self.slli(rd, rd, shift)?;
+        n += 1;
+        if lo12 != 0 {
+            self.addi(rd, rd, lo12)?;
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    /// Load the address of `label` into `rd` with `auipc` and `addi`.
+    pub fn la(&mut self, rd: RvReg, label: RvLabel) -> Result<(), RvError> {
+        self.emit_fixup(rd.bits() << 7 | 0x17, label, Fixup::Pcrel)?;
+        self.emit(i_type(0, rd, 0, rd, 0x13))
+    }
+
+    /// `c.nop`.
+    pub fn c_nop(&mut self) -> Result<(), RvError> {
+        self.compressed("c.nop", Some(c::nop()))
+    }
+
+    /// `c.li rd, imm`, with a 6-bit signed immediate.
+    pub fn c_li(&mut self, rd: RvReg, imm: i64) -> Result<(), RvError> {
+        self.compressed("c.li", c::li(rd, imm))
+    }
+
+    /// `c.addi rd, imm`, with a nonzero 6-bit signed immediate.
+    pub fn c_addi(&mut self, rd: RvReg, imm: i64) -> Result<(), RvError> {
+        self.compressed("c.addi", c::addi(rd, imm))
+    }
+
+    /// `c.addiw rd, imm`.
+    pub fn c_addiw(&mut self, rd: RvReg, imm: i64) -> Result<(), RvError> {
+        self.compressed("c.addiw", c::addiw(rd, imm))
+    }
+
+    /// `c.lui rd, imm`, where `imm` is the 20-bit field of `lui` and must be
+    /// a nonzero 6-bit signed value.
+    pub fn c_lui(&mut self, rd: RvReg, imm: u32) -> Result<(), RvError> {
+        self.compressed("c.lui", c::lui(rd, imm))
+    }
+
+    /// `c.slli rd, shamt`.
+    pub fn c_slli(&mut self, rd: RvReg, shamt: u32) -> Result<(), RvError> {
+        self.compressed("c.slli", c::slli(rd, shamt))
+    }
+
+    /// `c.mv rd, rs2`.
+    pub fn c_mv(&mut self, rd: RvReg, rs2: RvReg) -> Result<(), RvError> {
+        self.compressed("c.mv", c::mv(rd, rs2))
+    }
+
+    /// `c.add rd, rs2`.
+    pub fn c_add(&mut self, rd: RvReg, rs2: RvReg) -> Result<(), RvError> {
+        self.compressed("c.add", c::add(rd, rs2))
+    }
+
+    /// `c.sub rd, rs2`, for registers `x8` to `x15`.
+    pub fn c_sub(&mut self, rd: RvReg, rs2: RvReg) -> Result<(), RvError> {
+        self.compressed("c.sub", c::arith(0, rd, rs2))
+    }
+
+    /// `c.ld rd, offset(rs1)`, for registers `x8` to `x15`.
+    pub fn c_ld(&mut self, rd: RvReg, rs1: RvReg, offset: i64) -> Result<(), RvError> {
+        self.compressed("c.ld", c::load_store(3, rd, rs1, offset))
+    }
+
+    /// `c.sd rs2, offset(rs1)`, for registers `x8` to `x15`.
+    pub fn c_sd(&mut self, rs2: RvReg, rs1: RvReg, offset: i64) -> Result<(), RvError> {
+        self.compressed("c.sd", c::load_store(7, rs2, rs1, offset))
+    }
+
+    /// `c.ldsp rd, offset(sp)`.
+    pub fn c_ldsp(&mut self, rd: RvReg, offset: i64) -> Result<(), RvError> {
+        self.compressed("c.ldsp", c::ldsp(rd, offset))
+    }
+
+    /// `c.sdsp rs2, offset(sp)`.
+    pub fn c_sdsp(&mut self, rs2: RvReg, offset: i64) -> Result<(), RvError> {
+        self.compressed("c.sdsp", c::sdsp(rs2, offset))
+    }
+
+    /// `c.jr rs1`.
+    pub fn c_jr(&mut self, rs1: RvReg) -> Result<(), RvError> {
+        self.compressed("c.jr", c::jr(rs1))
+    }
+
+    /// `c.ebreak`.
+    pub fn c_ebreak(&mut self) -> Result<(), RvError> {
+        self.compressed("c.ebreak", Some(c::ebreak()))
+    }
+
+    /// `c.j label`, within 2KiB.
+    pub fn c_j(&mut self, label: RvLabel) -> Result<(), RvError> {
+        self.compressed("c.j", Some(0xa001))?;
+        self.add_fixup(2, label, Fixup::CJump);
+        Ok(())
+    }
+
+    /// `c.beqz rs1, label`, within 256 bytes, for registers `x8` to `x15`.
+    pub fn c_beqz(&mut self, rs1: RvReg, label: RvLabel) -> Result<(), RvError> {
+        let half = rs1.prime().map(|r| (0xc001 | r << 7) as u16);
+        self.compressed("c.beqz", half)?;
+        self.add_fixup(2, label, Fixup::CBranch);
+        Ok(())
+    }
+
+    /// `c.bnez rs1, label`, within 256 bytes, for registers `x8` to `x15`.
+    pub fn c_bnez(&mut self, rs1: RvReg, label: RvLabel) -> Result<(), RvError> {
+        let half = rs1.prime().map(|r| (0xe001 | r << 7) as u16);
+        self.compressed("c.bnez", half)?;
+        self.add_fixup(2, label, Fixup::CBranch);
+        Ok(())
+    }
+
+    // Record a fixup for the instruction of `size` bytes just emitted.
+    fn add_fixup(&mut self, size: usize, label: RvLabel, fixup: Fixup) {
+        self.fixups.push((self.code.len() - size, label, fixup));
+    }
+
+    /// Resolve labels and return the machine code.
+    ///
+    /// All references to labels are relative, so the code runs at any
+    /// (2-byte aligned, or 4-byte without compression) address.
+    pub fn assemble(&self) -> Result<Vec<u8>, RvError> {
+        let mut code = self.code.clone();
+        for &(offset, label, fixup) in &self.fixups {
+            let target = self.labels[label.0].ok_or(RvError::UnboundLabel)?;
+            let delta = target as i64 - offset as i64;
+            let out_of_range = RvError::OutOfRange { offset };
+            let mut patch = |at: usize, bits: u32, size: usize| {
+                let mut word = [0u8; 4];
+                word[..size].copy_from_slice(&code[at..at + size]);
+                let patched = u32::from_le_bytes(word) | bits;
+                code[at..at + size].copy_from_slice(&patched.to_le_bytes()[..size]);
+            };
+            match fixup {
+                Fixup::Branch => patch(offset, b_imm(fits(delta, 13).ok_or(out_of_range)?), 4),
+                Fixup::Jal => patch(offset, j_imm(fits(delta, 21).ok_or(out_of_range)?), 4),
+                Fixup::CJump => patch(offset, cj_imm(fits(delta, 12).ok_or(out_of_range)?), 2),
+                Fixup::CBranch => patch(offset, cb_imm(fits(delta, 9).ok_or(out_of_range)?), 2),
+                Fixup::Pcrel => {
+                    let hi20 = fits((delta + 0x800) >> 12, 20).ok_or(out_of_range)?;
+                    let lo12 = (delta as u32) & 0xfff;
+                    patch(offset, hi20 << 12, 4);
+                    patch(offset + 4, lo12 << 20, 4);
+                }
+            }
+        }
+        Ok(code)
+    }
+}
+
+/// Wrap `program` (raw RV64 machine code) in a minimal RISC-V ELF executable
+/// with the header flags `flags`, with the same layout as
+/// [`elf_bytes`](crate::elf_bytes).
+pub fn elf_riscv_bytes(program: &[u8], flags: u32) -> Vec<u8> {
+    let mut bytes = elf64_bytes(EM_RISCV, program);
+    elf64_hdr::View::new(&mut bytes[..])
+        .flags_mut()
+        .write(flags);
+    bytes
+}
+
+/// Assemble `a` and wrap the result in a minimal RISC-V ELF executable, with
+/// the header flags from [`RvAssembler::flags`].
+pub fn elf_from_rv(a: &RvAssembler) -> Result<Vec<u8>, RvError> {
+    Ok(elf_riscv_bytes(&a.assemble()?, a.flags()))
+}
+
+fn create_program_riscv() -> RvAssembler {
+    let f = || -> Result<_, RvError> {
+        let mut a = RvAssembler::new(true);
+        // exit is syscall 93 in the generic syscall table; 93 is too big for
+        // c.li, but 0 isn't
+        a.li(RvReg::A7, 93)?;
+        a.li(RvReg::A0, 0)?;
+        a.ecall()?;
+        Ok(a)
+    };
+    f().unwrap()
+}
+
+/// Write the minimal RISC-V ELF file, which immediately calls `exit(0)`.
+pub fn write_elf_riscv<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
+    write_executable(path, &elf_from_rv(&create_program_riscv()).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create_program_riscv, elf_from_rv, elf_riscv_bytes, write_elf_riscv};
+    use super::{RvAssembler, RvError, RvReg, EF_RISCV_FLOAT_ABI, EF_RISCV_FLOAT_ABI_DOUBLE};
+    use super::{EF_RISCV_RVC, EM_RISCV};
+    use crate::{parse_headers, ElfBuilder, PROGRAM_VADDR};
+    use RvReg as R;
+
+    // one instruction, checked against llvm-mc's encoding
+    fn check(rvc: bool, f: impl FnOnce(&mut RvAssembler) -> Result<(), RvError>, want: &[u8]) {
+        let mut a = RvAssembler::new(rvc);
+        f(&mut a).unwrap();
+        assert_eq!(want, &a.assemble().unwrap()[..]);
+    }
+
+    #[test]
+    fn test_exit_stub() {
+        let code = create_program_riscv().assemble().unwrap();
+        assert_eq!(
+            vec![
+                0x93, 0x08, 0xd0, 0x05, // addi a7, zero, 93
+                0x01, 0x45, // c.li a0, 0
+                0x73, 0x00, 0x00, 0x00, // ecall
+            ],
+            code
+        );
+        // without compression every instruction is a word
+        let mut a = RvAssembler::new(false);
+        a.li(R::A7, 93).unwrap();
+        a.li(R::A0, 0).unwrap();
+        a.ecall().unwrap();
+        assert_eq!(12, a.assemble().unwrap().len());
+    }
+
+    #[test]
+    fn test_header_flags() {
+        let bytes = elf_from_rv(&create_program_riscv()).unwrap();
+        assert_eq!(120 + 10, bytes.len());
+        let (header, phdrs) = parse_headers(&bytes).unwrap();
+        assert_eq!(EM_RISCV, header.machine);
+        assert_eq!(EF_RISCV_RVC, header.flags);
+        assert_eq!(0, header.flags & EF_RISCV_FLOAT_ABI);
+        assert_eq!(PROGRAM_VADDR, header.entry);
+        assert_eq!(10, phdrs[0].filesz);
+        assert!(crate::lint(&bytes).is_empty());
+
+        let flags = EF_RISCV_FLOAT_ABI_DOUBLE | EF_RISCV_RVC;
+        let (header, _) = parse_headers(&elf_riscv_bytes(&[0; 4], flags)).unwrap();
+        assert_eq!(0x5, header.flags);
+        assert_eq!(0, RvAssembler::new(false).flags());
+    }
+
+    #[test]
+    fn test_base_encodings() {
+        check(false, |a| a.ecall(), &[0x73, 0x00, 0x00, 0x00]);
+        check(false, |a| a.ebreak(), &[0x73, 0x00, 0x10, 0x00]);
+        check(false, |a| a.lui(R::A0, 0x12345), &[0x37, 0x55, 0x34, 0x12]);
+        check(
+            false,
+            |a| a.auipc(R::A1, 0xfffff),
+            &[0x97, 0xf5, 0xff, 0xff],
+        );
+        check(
+            false,
+            |a| a.add(R::A0, R::A1, R::A2),
+            &[0x33, 0x85, 0xc5, 0x00],
+        );
+        check(
+            false,
+            |a| a.sub(R::A0, R::A1, R::A2),
+            &[0x33, 0x85, 0xc5, 0x40],
+        );
+        check(
+            false,
+            |a| a.sra(R::T0, R::T1, R::T2),
+            &[0xb3, 0x52, 0x73, 0x40],
+        );
+        check(
+            false,
+            |a| a.addw(R::A0, R::A0, R::A1),
+            &[0x3b, 0x05, 0xb5, 0x00],
+        );
+        check(
+            false,
+            |a| a.subw(R::A0, R::A0, R::A1),
+            &[0x3b, 0x05, 0xb5, 0x40],
+        );
+        check(
+            false,
+            |a| a.slli(R::A0, R::A0, 63),
+            &[0x13, 0x15, 0xf5, 0x03],
+        );
+        check(
+            false,
+            |a| a.srai(R::A0, R::A0, 1),
+            &[0x13, 0x55, 0x15, 0x40],
+        );
+        check(
+            false,
+            |a| a.sraiw(R::A0, R::A0, 31),
+            &[0x1b, 0x55, 0xf5, 0x41],
+        );
+        check(false, |a| a.ld(R::A0, R::SP, -8), &[0x03, 0x35, 0x81, 0xff]);
+        check(false, |a| a.lw(R::A0, R::A1, 4), &[0x03, 0xa5, 0x45, 0x00]);
+        check(false, |a| a.lbu(R::A0, R::A1, 1), &[0x03, 0xc5, 0x15, 0x00]);
+        check(false, |a| a.sd(R::A0, R::SP, -8), &[0x23, 0x3c, 0xa1, 0xfe]);
+        check(
+            false,
+            |a| a.sb(R::A0, R::A1, 2047),
+            &[0xa3, 0x8f, 0xa5, 0x7e],
+        );
+        check(
+            false,
+            |a| a.jalr(R::RA, R::T0, 0),
+            &[0xe7, 0x80, 0x02, 0x00],
+        );
+        check(
+            false,
+            |a| a.andi(R::A0, R::A1, -1),
+            &[0x13, 0xf5, 0xf5, 0xff],
+        );
+        check(
+            false,
+            |a| a.sltiu(R::A0, R::A1, 1),
+            &[0x13, 0xb5, 0x15, 0x00],
+        );
+    }
+
+    #[test]
+    fn test_compressed_encodings() {
+        check(true, |a| a.nop(), &[0x01, 0x00]);
+        check(true, |a| a.li(R::A0, 0).map(drop), &[0x01, 0x45]);
+        check(true, |a| a.c_li(R::A7, -32), &[0x81, 0x58]);
+        check(true, |a| a.addi(R::A0, R::A0, 31), &[0x7d, 0x05]);
+        check(true, |a| a.addiw(R::A0, R::A0, -1), &[0x7d, 0x35]);
+        check(true, |a| a.lui(R::A0, 31), &[0x7d, 0x65]);
+        check(true, |a| a.c_lui(R::A0, 0xfffe0), &[0x01, 0x75]);
+        check(true, |a| a.mv(R::A0, R::A1), &[0x2e, 0x85]);
+        check(true, |a| a.add(R::A0, R::A0, R::A1), &[0x2e, 0x95]);
+        check(true, |a| a.sub(R::S0, R::S0, R::A5), &[0x1d, 0x8c]);
+        check(true, |a| a.ret(), &[0x82, 0x80]);
+        check(true, |a| a.slli(R::A0, R::A0, 63), &[0x7e, 0x15]);
+        check(true, |a| a.ld(R::A0, R::S1, 8), &[0x88, 0x64]);
+        check(true, |a| a.c_sd(R::A0, R::S1, 248), &[0xe8, 0xfc]);
+        check(true, |a| a.ld(R::RA, R::SP, 504), &[0xfe, 0x70]);
+        check(true, |a| a.sd(R::RA, R::SP, 8), &[0x06, 0xe4]);
+        check(true, |a| a.ebreak(), &[0x02, 0x90]);
+        // operands that don't fit stay uncompressed
+        check(true, |a| a.addi(R::A0, R::A1, 1), &[0x13, 0x85, 0x15, 0x00]);
+    }
+
+    #[test]
+    fn test_li() {
+        let li = |rvc: bool, value: i64| {
+            let mut a = RvAssembler::new(rvc);
+            let n = a.li(R::A0, value).unwrap();
+            (n, a.assemble().unwrap())
+        };
+        // lui a0, 0x12345; addiw a0, a0, 0x678
+        let want = vec![0x37, 0x55, 0x34, 0x12, 0x1b, 0x05, 0x85, 0x67];
+        assert_eq!((2, want), li(false, 0x12345678));
+        // lui a0, 0x80000; addiw a0, a0, -2048
+        let want = vec![0x37, 0x05, 0x00, 0x80, 0x1b, 0x05, 0x05, 0x80];
+        assert_eq!((2, want), li(false, 0x7ffff800));
+        // li a0, 1; slli a0, a0, 31
+        let want = vec![0x13, 0x05, 0x10, 0x00, 0x13, 0x15, 0xf5, 0x01];
+        assert_eq!((2, want), li(false, 0x8000_0000));
+        // lui, addiw, then three rounds of slli and addi
+        assert_eq!(8, li(false, 0x1234_5678_9abc_def0).0);
+        // c.li a0, -1
+        assert_eq!((1, vec![0x7d, 0x55]), li(true, -1));
+        // c.lui a0, 1
+        assert_eq!((1, vec![0x05, 0x65]), li(true, 4096));
+        // c.li a0, 1; c.slli a0, 32
+        assert_eq!((2, vec![0x05, 0x45, 0x02, 0x15]), li(true, 1 << 32));
+    }
+
+    #[test]
+    fn test_li_matches_llvm_mc() {
+        use std::io::Write;
+        let values = [0x12345678, -1, 1 << 40, 0x1234_5678_9abc_def0, -0x7654_3210];
+        let source: String = values.iter().map(|v| format!("li a0, {v}\n")).collect();
+        let child = std::process::Command::new("llvm-mc")
+            .args(["-triple=riscv64", "-mattr=+c", "-filetype=obj", "-o", "-"])
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn();
+        let Ok(mut child) = child else {
+            // llvm-mc is not installed
+            return;
+        };
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(source.as_bytes()).unwrap();
+        drop(stdin);
+        let out = child.wait_with_output().unwrap();
+        let elf = crate::parse(&out.stdout).unwrap();
+        let text = elf.sections.iter().find(|s| s.name == ".text").unwrap();
+        let text = &out.stdout[text.header.offset as usize..][..text.header.size as usize];
+
+        let mut a = RvAssembler::new(true);
+        for &v in &values {
+            a.li(R::A0, v).unwrap();
+        }
+        assert_eq!(text, &a.assemble().unwrap()[..]);
+    }
+
+    #[test]
+    fn test_branches() {
+        // each branch jumps to a label at the offset llvm-mc was given
+        let branch = |f: &dyn Fn(&mut RvAssembler, super::RvLabel), offset: i64| {
+            let mut a = RvAssembler::new(true);
+            let label = a.create_label();
+            if offset < 0 {
+                a.set_label(label).unwrap();
+                for _ in 0..-offset / 2 {
+                    a.c_nop().unwrap();
+                }
+            }
+            let at = a.code.len();
+            f(&mut a, label);
+            let size = a.code.len() - at;
+            if offset > 0 {
+                for _ in 0..(offset as usize - size) / 2 {
+                    a.c_nop().unwrap();
+                }
+                a.set_label(label).unwrap();
+            }
+            let code = a.assemble().unwrap();
+            code[at..at + size].to_vec()
+        };
+        assert_eq!(vec![0xfd, 0xaf], branch(&|a, l| a.c_j(l).unwrap(), 2046));
+        assert_eq!(vec![0x01, 0xb0], branch(&|a, l| a.c_j(l).unwrap(), -2048));
+        assert_eq!(vec![0x6d, 0xa4], branch(&|a, l| a.c_j(l).unwrap(), 682));
+        let beqz = |r: RvReg| move |a: &mut RvAssembler, l| a.c_beqz(r, l).unwrap();
+        assert_eq!(vec![0x01, 0xd1], branch(&beqz(R::A0), -256));
+        assert_eq!(vec![0xcd, 0xc7], branch(&beqz(R::A5), 170));
+        let bnez = |a: &mut RvAssembler, l| a.c_bnez(R::S1, l).unwrap();
+        assert_eq!(vec![0xfd, 0xec], branch(&bnez, 254));
+        let beq = |a: &mut RvAssembler, l| a.beq(R::A0, R::A1, l).unwrap();
+        assert_eq!(vec![0x63, 0x00, 0xb5, 0x80], branch(&beq, -4096));
+        let bne = |a: &mut RvAssembler, l| a.bne(R::A0, R::ZERO, l).unwrap();
+        assert_eq!(vec![0xe3, 0x1f, 0x05, 0x7e], branch(&bne, 4094));
+        let bge = |a: &mut RvAssembler, l| a.bge(R::T0, R::T1, l).unwrap();
+        assert_eq!(vec![0x63, 0xdb, 0x62, 0x54], branch(&bge, 1366));
+        let jal = |a: &mut RvAssembler, l| a.jal(R::RA, l).unwrap();
+        assert_eq!(vec![0xef, 0x00, 0x00, 0x80], branch(&jal, -1048576));
+        assert_eq!(
+            vec![0x6f, 0xa0, 0xba, 0x2a],
+            branch(&|a, l| a.j(l).unwrap(), 0xaaaaa)
+        );
+    }
+
+    #[test]
+    fn test_la() {
+        // auipc a1, 0; addi a1, a1, 12, pointing just past the ecall
+        let mut a = RvAssembler::new(true);
+        let msg = a.create_label();
+        a.la(R::A1, msg).unwrap();
+        a.ecall().unwrap();
+        a.set_label(msg).unwrap();
+        let want = vec![0x97, 0x05, 0x00, 0x00, 0x93, 0x85, 0xc5, 0x00];
+        assert_eq!(want, a.assemble().unwrap()[..8]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut a = RvAssembler::new(false);
+        assert_eq!(Err(RvError::NoCompressed), a.c_nop());
+        assert_eq!(
+            Err(RvError::Immediate {
+                instruction: "addi",
+                value: 2048
+            }),
+            a.addi(R::A0, R::A0, 2048)
+        );
+        assert!(a.slli(R::A0, R::A0, 64).is_err());
+        assert!(a.slliw(R::A0, R::A0, 32).is_err());
+        assert!(a.lui(R::A0, 1 << 20).is_err());
+
+        let mut a = RvAssembler::new(true);
+        assert_eq!(
+            Err(RvError::Operands {
+                instruction: "c.sub"
+            }),
+            a.c_sub(R::A0, R::T0)
+        );
+        assert!(a.c_li(R::A0, 32).is_err());
+        assert!(a.c_lui(R::SP, 1).is_err());
+        let label = a.create_label();
+        a.c_beqz(R::A0, label).unwrap();
+        assert_eq!(Err(RvError::UnboundLabel), a.assemble());
+        for _ in 0..128 {
+            a.c_nop().unwrap();
+        }
+        a.set_label(label).unwrap();
+        assert_eq!(Err(RvError::OutOfRange { offset: 0 }), a.assemble());
+        assert_eq!(Err(RvError::LabelRedefined), a.set_label(label));
+    }
+
+    #[test]
+    fn test_builder_machine() {
+        // the builder lays out non-x86 code given as bytes
+        let code = create_program_riscv().assemble().unwrap();
+        let mut b = ElfBuilder::new();
+        b.machine(EM_RISCV, EF_RISCV_RVC);
+        b.asm().db(&code).unwrap();
+        let bytes = b.build().unwrap();
+        let (header, _) = parse_headers(&bytes).unwrap();
+        assert_eq!(EM_RISCV, header.machine);
+        assert_eq!(EF_RISCV_RVC, header.flags);
+        let entry = (header.entry - crate::VADDR) as usize;
+        assert_eq!(&code[..], &bytes[entry..entry + code.len()]);
+    }
+
+    #[test]
+    fn test_write() {
+        let path = crate::test_util::path("tiny_riscv");
+        write_elf_riscv(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(elf_from_rv(&create_program_riscv()).unwrap(), bytes);
+    }
+}
@@ -0,0 +1,175 @@
+//! Section header tables, which describe a file for tools like objdump and
+//! gdb but are ignored by the loader.
+
+use crate::{
+    align_up, elf64_hdr, elf64_shdr, elf64_sym, set_elf64_shdr, set_elf64_sym, Shdr, Sym,
+    SHF_ALLOC, SHF_TLS, SHT_STRTAB, SHT_SYMTAB, STB_LOCAL,
+};
+
+pub(crate) struct SectionTable {
+    // the section name string table, which starts with the empty name
+    names: Vec<u8>,
+    sections: Vec<(u32, Shdr)>,
+}
+
+impl SectionTable {
+    /// A table with only the null section at index 0.
+    pub fn new() -> Self {
+        SectionTable {
+            names: vec![0],
+            sections: vec![(0, Shdr::default())],
+        }
+    }
+
+    fn name(&mut self, name: &str) -> u32 {
+        let offset = self.names.len() as u32;
+        self.names.extend_from_slice(name.as_bytes());
+        self.names.push(0);
+        offset
+    }
+
+    /// Add a section describing existing contents of the file, returning its
+    /// index.
+    pub fn add(&mut self, name: &str, shdr: Shdr) -> u16 {
+        let name = self.name(name);
+        self.sections.push((name, shdr));
+        (self.sections.len() - 1) as u16
+    }
+
+    /// Append `data` to the file and add a section for it, returning its
+    /// index.
+    pub fn add_data(
+        &mut self,
+        bytes: &mut Vec<u8>,
+        name: &str,
+        mut shdr: Shdr,
+        data: &[u8],
+    ) -> u16 {
+        bytes.resize(
+            align_up(bytes.len() as u64, shdr.addralign.max(1)) as usize,
+            0,
+        );
+        shdr.offset = bytes.len() as u64;
+        shdr.size = data.len() as u64;
+        bytes.extend_from_slice(data);
+        self.add(name, shdr)
+    }
+
+    /// The index and header of the section loaded at `addr`, if any.
+    pub fn containing(&self, addr: u64) -> Option<(u16, &Shdr)> {
+        self.sections
+            .iter()
+            .enumerate()
+            .find(|(_, (_, shdr))| {
+                // TLS sections are templates, copied elsewhere at run time
+                shdr.flags & (SHF_ALLOC | SHF_TLS) == SHF_ALLOC
+                    && shdr.addr <= addr
+                    && addr < shdr.addr + shdr.size
+            })
+            .map(|(i, (_, shdr))| (i as u16, shdr))
+    }
+
+    /// Append the section name string table and the section headers to the
+    /// file, and point the ELF header at them.
+    pub fn finish(mut self, bytes: &mut Vec<u8>) {
+        let name = self.name(".shstrtab");
+        let shstrndx = self.sections.len() as u16;
+        let shstrtab = Shdr {
+            _type: SHT_STRTAB,
+            offset: bytes.len() as u64,
+            size: self.names.len() as u64,
+            addralign: 1,
+            ..Shdr::default()
+        };
+        bytes.extend_from_slice(&self.names);
+        self.sections.push((name, shstrtab));
+
+        let shentsize = elf64_shdr::SIZE.unwrap();
+        let shoff = align_up(bytes.len() as u64, 8) as usize;
+        bytes.resize(shoff + self.sections.len() * shentsize, 0);
+        for (i, (name, shdr)) in self.sections.iter().enumerate() {
+            let view = elf64_shdr::View::new(&mut bytes[shoff + i * shentsize..]);
+            set_elf64_shdr(view, *name, shdr);
+        }
+        let mut hdr = elf64_hdr::View::new(&mut bytes[..]);
+        hdr.shoff_mut().write(shoff as u64);
+        hdr.shentsize_mut().write(shentsize as u16);
+        hdr.shnum_mut().write(self.sections.len() as u16);
+        hdr.shstrndx_mut().write(shstrndx);
+    }
+}
+
+/// A symbol table and its string table.
+pub(crate) struct SymbolTable {
+    // the string table, which starts with the empty name
+    names: Vec<u8>,
+    locals: Vec<(u32, Sym)>,
+    globals: Vec<(u32, Sym)>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        SymbolTable {
+            names: vec![0],
+            locals: vec![],
+            globals: vec![],
+        }
+    }
+
+    pub fn add(&mut self, name: &str, sym: Sym) {
+        let offset = self.names.len() as u32;
+        self.names.extend_from_slice(name.as_bytes());
+        self.names.push(0);
+        if sym.bind == STB_LOCAL {
+            self.locals.push((offset, sym));
+        } else {
+            self.globals.push((offset, sym));
+        }
+    }
+
+    /// The index `name` will have in the finished table, if it was added.
+    pub fn index(&self, name: &str) -> Option<u32> {
+        let is_name = |(offset, _): &(u32, Sym)| {
+            let start = *offset as usize;
+            self.names[start..].split(|&b| b == 0).next() == Some(name.as_bytes())
+        };
+        // the null symbol, then all locals before the globals
+        self.locals
+            .iter()
+            .chain(&self.globals)
+            .position(is_name)
+            .map(|i| 1 + i as u32)
+    }
+
+    /// Append `.strtab` and `.symtab` sections to the file, returning the
+    /// index of `.symtab`.
+    pub fn finish(self, bytes: &mut Vec<u8>, sections: &mut SectionTable) -> u16 {
+        let strtab = Shdr {
+            _type: SHT_STRTAB,
+            addralign: 1,
+            ..Shdr::default()
+        };
+        let strndx = sections.add_data(bytes, ".strtab", strtab, &self.names);
+
+        let entsize = elf64_sym::SIZE.unwrap();
+        // the null symbol, then all locals before the globals
+        let syms: Vec<_> = std::iter::once((0, Sym::default()))
+            .chain(self.locals.iter().copied())
+            .chain(self.globals.iter().copied())
+            .collect();
+        let mut data = vec![0u8; syms.len() * entsize];
+        for (i, (name, sym)) in syms.iter().enumerate() {
+            set_elf64_sym(elf64_sym::View::new(&mut data[i * entsize..]), *name, sym);
+        }
+        let symtab = Shdr {
+            _type: SHT_SYMTAB,
+            link: strndx as u32,
+            // index of the first global symbol
+            info: 1 + self.locals.len() as u32,
+            addralign: 8,
+            entsize: entsize as u64,
+            ..Shdr::default()
+        };
+        sections.add_data(bytes, ".symtab", symtab, &data)
+    }
+}
@@ -0,0 +1,114 @@
+//! SHA-1, as used for GNU build IDs (FIPS 180-4).
+//!
+//! SHA-1 is broken for security purposes, but a build ID only needs to tell
+//! different files apart, and `ld --build-id=sha1` uses it too.
+
+const H0: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+// Mix one 64-byte block into the state.
+fn compress(h: &mut [u32; 5], block: &[u8]) {
+    let mut w = [0u32; 80];
+    for (i, word) in block.chunks_exact(4).enumerate() {
+        w[i] = u32::from_be_bytes(word.try_into().unwrap());
+    }
+    for i in 16..80 {
+        w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+    }
+    let [mut a, mut b, mut c, mut d, mut e] = *h;
+    for (i, &w) in w.iter().enumerate() {
+        let (f, k) = match i {
+            0..=19 => ((b & c) | (!b & d), 0x5a827999),
+            20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+            40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+            _ => (b ^ c ^ d, 0xca62c1d6),
+        };
+        let t = a
+            .rotate_left(5)
+            .wrapping_add(f)
+            .wrapping_add(e)
+            .wrapping_add(k)
+            .wrapping_add(w);
+        e = d;
+        d = c;
+        c = b.rotate_left(30);
+        b = a;
+        a = t;
+    }
+    for (h, x) in h.iter_mut().zip([a, b, c, d, e]) {
+        *h = h.wrapping_add(x);
+    }
+}
+
+/// The SHA-1 digest of `data`.
+pub(crate) fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h = H0;
+    let mut blocks = data.chunks_exact(64);
+    for block in &mut blocks {
+        compress(&mut h, block);
+    }
+    // pad with a 1 bit, zeros and the length in bits, to a whole number of
+    // blocks
+    let rest = blocks.remainder();
+    let mut last = rest.to_vec();
+    last.push(0x80);
+    let len = if last.len() > 56 { 128 } else { 64 };
+    last.resize(len - 8, 0);
+    last.extend_from_slice(&(8 * data.len() as u64).to_be_bytes());
+    for block in last.chunks_exact(64) {
+        compress(&mut h, block);
+    }
+
+    let mut digest = [0; 20];
+    for (bytes, h) in digest.chunks_exact_mut(4).zip(h) {
+        bytes.copy_from_slice(&h.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sha1;
+
+    fn hex(digest: [u8; 20]) -> String {
+        digest.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn test_sha1() {
+        // the examples from FIPS 180-4
+        assert_eq!("da39a3ee5e6b4b0d3255bfef95601890afd80709", hex(sha1(b"")));
+        assert_eq!(
+            "a9993e364706816aba3e25717850c26c9cd0d89d",
+            hex(sha1(b"abc"))
+        );
+        // the padding spills into a second block
+        assert_eq!(
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1",
+            hex(sha1(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            ))
+        );
+        assert_eq!(
+            "34aa973cd4c4daa4f61eeb2bdbad27316534016f",
+            hex(sha1(&[b'a'; 1_000_000]))
+        );
+    }
+
+    #[test]
+    fn test_sha1sum() {
+        // every length around a block boundary, checked against sha1sum
+        let data: Vec<u8> = (0..=200u8).collect();
+        let path = crate::test_util::path("sha1sum");
+        for len in [55, 56, 63, 64, 65, 119, 120, 200] {
+            std::fs::write(&path, &data[..len]).unwrap();
+            let out = std::process::Command::new("sha1sum").arg(&path).output();
+            let Ok(out) = out else {
+                // sha1sum is not installed
+                break;
+            };
+            let out = String::from_utf8(out.stdout).unwrap();
+            assert_eq!(&out[..40], hex(sha1(&data[..len])), "{len}");
+        }
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,324 @@
+//! Search for the shortest instruction sequence that sets up a syscall.
+//!
+//! Candidates are built as iced [`Instruction`]s, measured with the
+//! [`Encoder`], and run on a small model of the general-purpose registers and
+//! the stack. The search is breadth-first by encoded size, so the first
+//! sequence that meets the [`Spec`] is a shortest one (among the
+//! instructions the model knows).
+
+use crate::load::GPR64;
+use crate::KnownRegisters;
+use iced_x86::code_asm::AsmRegister64;
+use iced_x86::{Code, Encoder, Instruction, MemoryOperand, Register};
+use std::collections::HashSet;
+
+/// The register values a sequence must produce, and whether it then makes a
+/// syscall.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Spec {
+    registers: Vec<(Register, u64)>,
+    syscall: bool,
+    initial: KnownRegisters,
+}
+
+impl Spec {
+    /// A spec with no requirements, starting with no known registers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Require `reg` to hold `value` at the end.
+    pub fn register(mut self, reg: AsmRegister64, value: u64) -> Self {
+        self.registers.push((reg.into(), value));
+        self
+    }
+
+    /// End the sequence with a `syscall`.
+    pub fn syscall(mut self) -> Self {
+        self.syscall = true;
+        self
+    }
+
+    /// Assume the registers in `known` at the start, such as
+    /// [`KnownRegisters::linux_entry`].
+    pub fn initial(mut self, known: KnownRegisters) -> Self {
+        self.initial = known;
+        self
+    }
+}
+
+// What the model knows: register values by number (see GPR64) and the
+// values this sequence pushed.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct State {
+    regs: [Option<u64>; 16],
+    stack: Vec<Option<u64>>,
+}
+
+fn index(reg: Register) -> usize {
+    GPR64
+        .iter()
+        .position(|&r| r == reg.full_register())
+        .unwrap()
+}
+
+impl State {
+    fn get(&self, reg: Register) -> Option<u64> {
+        self.regs[index(reg)]
+    }
+
+    // Write `value` to `reg`, zero-extending 32-bit registers and merging
+    // 8-bit ones with what was there.
+    fn set(&mut self, reg: Register, value: Option<u64>) {
+        let i = index(reg);
+        self.regs[i] = match reg.size() {
+            1 => value
+                .zip(self.regs[i])
+                .map(|(v, old)| old & !0xff | v & 0xff),
+            4 => value.map(|v| v & 0xffff_ffff),
+            _ => value,
+        };
+    }
+
+    // Run one instruction, or None if the model doesn't allow it.
+    fn step(&self, instr: &Instruction) -> Option<State> {
+        let mut next = self.clone();
+        let r0 = instr.op0_register();
+        let r1 = instr.op1_register();
+        match instr.code() {
+            Code::Xor_rm32_r32 if r0 == r1 => next.set(r0, Some(0)),
+            Code::Mov_r32_imm32 | Code::Mov_r8_imm8 | Code::Mov_r64_imm64 => {
+                next.set(r0, Some(instr.immediate(1)))
+            }
+            Code::Mov_rm64_imm32 => next.set(r0, Some(instr.immediate(1))),
+            Code::Mov_rm32_r32 | Code::Mov_rm64_r64 => next.set(r0, self.get(r1)),
+            Code::Pushq_imm8 | Code::Pushq_imm32 => next.stack.push(Some(instr.immediate(0))),
+            Code::Push_r64 => next.stack.push(self.get(r0)),
+            // popping what the sequence didn't push would move rsp
+            Code::Pop_r64 => {
+                let value = next.stack.pop()?;
+                next.set(r0, value);
+            }
+            Code::Inc_rm32 | Code::Inc_rm64 => {
+                next.set(r0, self.get(r0).map(|v| v.wrapping_add(1)))
+            }
+            Code::Dec_rm32 | Code::Dec_rm64 => {
+                next.set(r0, self.get(r0).map(|v| v.wrapping_sub(1)))
+            }
+            Code::Xchg_r32_EAX => {
+                next.set(r0, self.get(r1));
+                next.set(r1, self.get(r0));
+            }
+            Code::Cdq => {
+                let eax = self.get(Register::EAX);
+                let sign = eax.map(|v| if v as u32 as i32 >= 0 { 0 } else { 0xffff_ffff });
+                next.set(Register::EDX, sign);
+            }
+            Code::Lea_r32_m | Code::Lea_r64_m => {
+                let base = self.get(instr.memory_base());
+                let displ = instr.memory_displacement64();
+                next.set(r0, base.map(|b| b.wrapping_add(displ)));
+            }
+            _ => return None,
+        }
+        Some(next)
+    }
+}
+
+// The instructions to try from `state`, built from the registers and values
+// in the spec.
+fn moves(spec: &Spec, state: &State) -> Vec<Instruction> {
+    let regs: Vec<Register> = spec.registers.iter().map(|&(r, _)| r).collect();
+    let mut moves = vec![];
+    let mut add = |instr: Result<Instruction, _>| moves.push(instr.unwrap());
+    for &(r, value) in &spec.registers {
+        let r32 = r.full_register32();
+        add(Instruction::with2(Code::Xor_rm32_r32, r32, r32));
+        if value <= u32::MAX as u64 {
+            add(Instruction::with2(Code::Mov_r32_imm32, r32, value as u32));
+        }
+        if let Ok(imm) = i32::try_from(value as i64) {
+            add(Instruction::with2(Code::Mov_rm64_imm32, r, imm));
+            if i8::try_from(imm).is_ok() {
+                add(Instruction::with1(Code::Pushq_imm8, imm));
+            } else {
+                add(Instruction::with1(Code::Pushq_imm32, imm));
+            }
+        } else {
+            add(Instruction::with2(Code::Mov_r64_imm64, r, value));
+        }
+        let r8 = crate::load::GPR8[index(r)];
+        add(Instruction::with2(
+            Code::Mov_r8_imm8,
+            r8,
+            value as u32 & 0xff,
+        ));
+        add(Instruction::with1(Code::Push_r64, r));
+        add(Instruction::with1(Code::Pop_r64, r));
+        add(Instruction::with1(Code::Inc_rm32, r32));
+        add(Instruction::with1(Code::Dec_rm32, r32));
+        add(Instruction::with1(Code::Inc_rm64, r));
+        add(Instruction::with1(Code::Dec_rm64, r));
+        if r != Register::RAX {
+            // xchg eax, eax is a nop rather than a zero-extension
+            add(Instruction::with2(Code::Xchg_r32_EAX, r32, Register::EAX));
+        }
+        if r == Register::RDX {
+            add(Ok(Instruction::with(Code::Cdq)));
+        }
+        for &base in &regs {
+            if base != r {
+                add(Instruction::with2(Code::Mov_rm64_r64, r, base));
+                add(Instruction::with2(
+                    Code::Mov_rm32_r32,
+                    r32,
+                    base.full_register32(),
+                ));
+            }
+            let Some(base_value) = state.get(base) else {
+                continue;
+            };
+            let Ok(displ) = i32::try_from(value.wrapping_sub(base_value) as i64) else {
+                continue;
+            };
+            if displ != 0 {
+                let mem = MemoryOperand::with_base_displ(base, displ as i64);
+                add(Instruction::with2(Code::Lea_r64_m, r, mem));
+                add(Instruction::with2(Code::Lea_r32_m, r32, mem));
+            }
+        }
+    }
+    moves
+}
+
+/// Find a shortest sequence that meets `spec`, with at most `max_len` bytes
+/// before the final `syscall` (if any).
+///
+/// Sequences may push and pop, but leave `rsp` where it started. Returns
+/// `None` if nothing short enough was found.
+pub fn superoptimize(spec: &Spec, max_len: usize) -> Option<Vec<Instruction>> {
+    let mut encoder = Encoder::new(64);
+    let mut initial = State {
+        regs: [None; 16],
+        stack: vec![],
+    };
+    for (i, &reg) in GPR64.iter().enumerate() {
+        initial.regs[i] = spec.initial.get(reg);
+    }
+    let done = |state: &State| {
+        state.stack.is_empty()
+            && spec
+                .registers
+                .iter()
+                .all(|&(r, value)| state.get(r) == Some(value))
+    };
+
+    // buckets[n] holds the sequences of n bytes not yet expanded
+    let mut buckets: Vec<Vec<(State, Vec<Instruction>)>> = vec![vec![]; max_len + 1];
+    buckets[0].push((initial, vec![]));
+    let mut seen = HashSet::new();
+    for len in 0..=max_len {
+        for (state, seq) in std::mem::take(&mut buckets[len]) {
+            if !seen.insert(state.clone()) {
+                continue;
+            }
+            if done(&state) {
+                let mut seq = seq;
+                if spec.syscall {
+                    seq.push(Instruction::with(Code::Syscall));
+                }
+                return Some(seq);
+            }
+            for instr in moves(spec, &state) {
+                let Some(next) = state.step(&instr) else {
+                    continue;
+                };
+                let size = encoder.encode(&instr, 0).unwrap();
+                if len + size <= max_len && !seen.contains(&next) {
+                    let mut seq = seq.clone();
+                    seq.push(instr);
+                    buckets[len + size].push((next, seq));
+                }
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{superoptimize, Spec};
+    use crate::test_util::{exit_code, run};
+    use crate::{create_program, elf_from_asm, KnownRegisters, LoadConst};
+    use iced_x86::code_asm::*;
+
+    fn assemble(seq: &[iced_x86::Instruction]) -> CodeAssembler {
+        let mut a = CodeAssembler::new(64).unwrap();
+        for &instr in seq {
+            a.add_instruction(instr).unwrap();
+        }
+        a
+    }
+
+    #[test]
+    fn test_exit_stub() {
+        let spec = Spec::new().register(rax, 60).register(rdi, 0).syscall();
+        let seq = superoptimize(&spec, 16).unwrap();
+        let mut a = assemble(&seq);
+        let bytes = a.assemble(0).unwrap();
+        assert_eq!(create_program().len(), bytes.len());
+        assert_eq!(
+            0,
+            exit_code(&run("superopt_exit", &elf_from_asm(&mut a).unwrap()))
+        );
+    }
+
+    #[test]
+    fn test_exit_stub_at_entry() {
+        // Linux zeroes the registers, so only the low byte of rax needs setting
+        let spec = Spec::new()
+            .initial(KnownRegisters::linux_entry())
+            .register(rax, 60)
+            .register(rdi, 0)
+            .syscall();
+        let seq = superoptimize(&spec, 16).unwrap();
+        let mut a = assemble(&seq);
+        assert_eq!(vec![0xb0, 60, 0x0f, 0x05], a.assemble(0).unwrap());
+        assert_eq!(
+            0,
+            exit_code(&run("superopt_entry", &elf_from_asm(&mut a).unwrap()))
+        );
+    }
+
+    #[test]
+    fn test_matches_load_const() {
+        // the search is never worse than loading each register greedily
+        let spec = Spec::new()
+            .register(rdi, 7)
+            .register(rsi, 7)
+            .register(rax, 60)
+            .syscall();
+        let seq = superoptimize(&spec, 16).unwrap();
+        let mut a = assemble(&seq);
+        let len = a.assemble(0).unwrap().len();
+
+        let mut naive = CodeAssembler::new(64).unwrap();
+        let mut known = KnownRegisters::new();
+        naive.load_const(rdi, 7, &mut known).unwrap();
+        naive.load_const(rsi, 7, &mut known).unwrap();
+        naive.load_const(rax, 60, &mut known).unwrap();
+        naive.syscall().unwrap();
+        assert!(len <= naive.assemble(0).unwrap().len());
+        assert_eq!(
+            7,
+            exit_code(&run("superopt_shared", &elf_from_asm(&mut a).unwrap()))
+        );
+    }
+
+    #[test]
+    fn test_too_short() {
+        let spec = Spec::new().register(rax, 1 << 40);
+        assert_eq!(None, superoptimize(&spec, 9));
+        assert_eq!(1, superoptimize(&spec, 10).unwrap().len());
+    }
+}
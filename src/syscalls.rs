@@ -0,0 +1,467 @@
+//! Typed Linux system calls.
+//!
+//! [`Syscalls`] puts each argument of a system call in the register the
+//! kernel expects it in, loading constants with [`LoadConst`], and takes the
+//! number from the table of the architecture instead of hard-coding it.
+
+use crate::load::{KnownRegisters, LoadConst};
+use iced_x86::code_asm::{r10, r11, r8, r9, rax, rcx, rdi, rdx, rsi};
+use iced_x86::code_asm::{AsmRegister64, CodeAssembler, CodeLabel};
+use iced_x86::{Code, IcedError, Instruction, Register};
+
+/// A Linux system call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Syscall {
+    Read,
+    Write,
+    Open,
+    Close,
+    Mmap,
+    Munmap,
+    Clone,
+    Exit,
+    Wait4,
+    ExitGroup,
+    ArchPrctl,
+}
+
+/// The system call tables of the architectures we generate code for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SyscallAbi {
+    /// 64-bit x86, through `syscall`.
+    X86_64,
+    /// 32-bit x86, through `int 0x80`.
+    I386,
+    /// The table shared by newer architectures, such as AArch64 and RISC-V.
+    Generic,
+}
+
+// (call, x86-64, i386, generic); the generic table leaves out calls with
+// newer replacements, like open for openat, and i386's mmap is mmap2, which
+// takes the offset in pages
+const TABLE: [(Syscall, u32, Option<u32>, Option<u32>); 11] = [
+    (Syscall::Read, 0, Some(3), Some(63)),
+    (Syscall::Write, 1, Some(4), Some(64)),
+    (Syscall::Open, 2, Some(5), None),
+    (Syscall::Close, 3, Some(6), Some(57)),
+    (Syscall::Mmap, 9, Some(192), Some(222)),
+    (Syscall::Munmap, 11, Some(91), Some(215)),
+    (Syscall::Clone, 56, Some(120), Some(220)),
+    (Syscall::Exit, 60, Some(1), Some(93)),
+    (Syscall::Wait4, 61, Some(114), Some(260)),
+    (Syscall::ExitGroup, 231, Some(252), Some(94)),
+    (Syscall::ArchPrctl, 158, Some(384), None),
+];
+
+impl Syscall {
+    /// The number of the call in the table of `abi`, if it has one.
+    pub fn number(self, abi: SyscallAbi) -> Option<u32> {
+        let &(_, x86_64, i386, generic) = TABLE.iter().find(|row| row.0 == self).unwrap();
+        match abi {
+            SyscallAbi::X86_64 => Some(x86_64),
+            SyscallAbi::I386 => i386,
+            SyscallAbi::Generic => generic,
+        }
+    }
+}
+
+/// An argument of a system call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallArg {
+    /// A constant, loaded with [`LoadConst::load_const`].
+    Const(u64),
+    /// The address of a label, loaded with a RIP-relative `lea`.
+    Label(CodeLabel),
+    /// The value of a 64-bit register, such as the result of an earlier call
+    /// in `rax`.
+    Reg(AsmRegister64),
+}
+
+impl From<u64> for SyscallArg {
+    fn from(value: u64) -> Self {
+        SyscallArg::Const(value)
+    }
+}
+
+impl From<u32> for SyscallArg {
+    fn from(value: u32) -> Self {
+        SyscallArg::Const(value as u64)
+    }
+}
+
+/// Sign-extended, so that `-1` is the all-ones file descriptor `mmap`
+/// expects for anonymous memory.
+impl From<i32> for SyscallArg {
+    fn from(value: i32) -> Self {
+        SyscallArg::Const(value as i64 as u64)
+    }
+}
+
+impl From<CodeLabel> for SyscallArg {
+    fn from(label: CodeLabel) -> Self {
+        SyscallArg::Label(label)
+    }
+}
+
+impl From<AsmRegister64> for SyscallArg {
+    fn from(reg: AsmRegister64) -> Self {
+        SyscallArg::Reg(reg)
+    }
+}
+
+// The registers of the first to sixth arguments.
+const ARGS: [AsmRegister64; 6] = [rdi, rsi, rdx, r10, r8, r9];
+
+fn set_known(known: &mut KnownRegisters, reg: Register, value: Option<u64>) {
+    match value {
+        Some(value) => known.set(reg, value),
+        None => known.forget(reg),
+    }
+}
+
+// Make the (destination, source) moves as if all at once: a move waits until
+// no other move still needs its destination, and a cycle is broken with
+// xchg.
+fn move_registers(
+    a: &mut CodeAssembler,
+    mut moves: Vec<(Register, Register)>,
+    known: &mut KnownRegisters,
+) -> Result<(), IcedError> {
+    loop {
+        moves.retain(|(dest, src)| dest != src);
+        if moves.is_empty() {
+            return Ok(());
+        }
+        let ready = moves
+            .iter()
+            .position(|&(dest, _)| !moves.iter().any(|&(_, src)| src == dest));
+        if let Some(i) = ready {
+            let (dest, src) = moves.remove(i);
+            a.add_instruction(Instruction::with2(Code::Mov_rm64_r64, dest, src)?)?;
+            set_known(known, dest, known.get(src));
+        } else {
+            // src now holds what the other moves wanted from dest
+            let (dest, src) = moves.remove(0);
+            a.add_instruction(Instruction::with2(Code::Xchg_rm64_r64, dest, src)?)?;
+            let (old_dest, old_src) = (known.get(dest), known.get(src));
+            set_known(known, dest, old_src);
+            set_known(known, src, old_dest);
+            for m in &mut moves {
+                if m.1 == dest {
+                    m.1 = src;
+                }
+            }
+        }
+    }
+}
+
+/// Linux system calls on x86-64, with their arguments loaded as cheaply as
+/// possible.
+///
+/// `create_program` is `a.exit(0, &mut KnownRegisters::new())`: the same
+/// `push 60; pop rax` and `xor edi, edi` it used to pick by hand.
+pub trait Syscalls {
+    /// Make the system call `call` with up to six `args`, which go in `rdi`,
+    /// `rsi`, `rdx`, `r10`, `r8` and `r9`.
+    ///
+    /// Register arguments are moved into place first, then labels and
+    /// constants are loaded, and the number goes in `rax` last, so any
+    /// register can be an argument. `known` is updated with the arguments,
+    /// and forgets `rax` (the result), `rcx` and `r11`, which `syscall`
+    /// clobbers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are more than six arguments.
+    fn system_call(
+        &mut self,
+        call: Syscall,
+        args: &[SyscallArg],
+        known: &mut KnownRegisters,
+    ) -> Result<(), IcedError>;
+
+    /// `exit(code)`: end the calling thread.
+    fn exit(
+        &mut self,
+        code: impl Into<SyscallArg>,
+        known: &mut KnownRegisters,
+    ) -> Result<(), IcedError> {
+        self.system_call(Syscall::Exit, &[code.into()], known)
+    }
+
+    /// `exit_group(code)`: end every thread of the process.
+    fn exit_group(
+        &mut self,
+        code: impl Into<SyscallArg>,
+        known: &mut KnownRegisters,
+    ) -> Result<(), IcedError> {
+        self.system_call(Syscall::ExitGroup, &[code.into()], known)
+    }
+
+    /// `read(fd, buf, len)`
+    fn read(
+        &mut self,
+        fd: impl Into<SyscallArg>,
+        buf: impl Into<SyscallArg>,
+        len: impl Into<SyscallArg>,
+        known: &mut KnownRegisters,
+    ) -> Result<(), IcedError> {
+        let args = [fd.into(), buf.into(), len.into()];
+        self.system_call(Syscall::Read, &args, known)
+    }
+
+    /// `write(fd, buf, len)`
+    fn write(
+        &mut self,
+        fd: impl Into<SyscallArg>,
+        buf: impl Into<SyscallArg>,
+        len: impl Into<SyscallArg>,
+        known: &mut KnownRegisters,
+    ) -> Result<(), IcedError> {
+        let args = [fd.into(), buf.into(), len.into()];
+        self.system_call(Syscall::Write, &args, known)
+    }
+
+    /// `open(path, flags, mode)`, with `path` a NUL-terminated string.
+    fn open(
+        &mut self,
+        path: impl Into<SyscallArg>,
+        flags: impl Into<SyscallArg>,
+        mode: impl Into<SyscallArg>,
+        known: &mut KnownRegisters,
+    ) -> Result<(), IcedError> {
+        let args = [path.into(), flags.into(), mode.into()];
+        self.system_call(Syscall::Open, &args, known)
+    }
+
+    /// `close(fd)`
+    fn close(
+        &mut self,
+        fd: impl Into<SyscallArg>,
+        known: &mut KnownRegisters,
+    ) -> Result<(), IcedError> {
+        self.system_call(Syscall::Close, &[fd.into()], known)
+    }
+
+    /// `mmap(addr, len, prot, flags, fd, offset)`
+    #[allow(clippy::too_many_arguments)]
+    fn mmap(
+        &mut self,
+        addr: impl Into<SyscallArg>,
+        len: impl Into<SyscallArg>,
+        prot: impl Into<SyscallArg>,
+        flags: impl Into<SyscallArg>,
+        fd: impl Into<SyscallArg>,
+        offset: impl Into<SyscallArg>,
+        known: &mut KnownRegisters,
+    ) -> Result<(), IcedError> {
+        let args = [
+            addr.into(),
+            len.into(),
+            prot.into(),
+            flags.into(),
+            fd.into(),
+            offset.into(),
+        ];
+        self.system_call(Syscall::Mmap, &args, known)
+    }
+
+    /// `munmap(addr, len)`
+    fn munmap(
+        &mut self,
+        addr: impl Into<SyscallArg>,
+        len: impl Into<SyscallArg>,
+        known: &mut KnownRegisters,
+    ) -> Result<(), IcedError> {
+        self.system_call(Syscall::Munmap, &[addr.into(), len.into()], known)
+    }
+
+    /// `clone(flags, stack)`, without thread ID pointers or TLS; use
+    /// [`Syscalls::system_call`] for those.
+    ///
+    /// The child starts after the `syscall` with `rax` zero and `rsp` at
+    /// `stack` (or a copy of the parent's stack, if `stack` is zero).
+    fn clone(
+        &mut self,
+        flags: impl Into<SyscallArg>,
+        stack: impl Into<SyscallArg>,
+        known: &mut KnownRegisters,
+    ) -> Result<(), IcedError> {
+        self.system_call(Syscall::Clone, &[flags.into(), stack.into()], known)
+    }
+
+    /// `arch_prctl(code, addr)`, for example to set `fs` with
+    /// `ARCH_SET_FS` (0x1002).
+    fn arch_prctl(
+        &mut self,
+        code: impl Into<SyscallArg>,
+        addr: impl Into<SyscallArg>,
+        known: &mut KnownRegisters,
+    ) -> Result<(), IcedError> {
+        self.system_call(Syscall::ArchPrctl, &[code.into(), addr.into()], known)
+    }
+}
+
+impl Syscalls for CodeAssembler {
+    fn system_call(
+        &mut self,
+        call: Syscall,
+        args: &[SyscallArg],
+        known: &mut KnownRegisters,
+    ) -> Result<(), IcedError> {
+        use iced_x86::code_asm::ptr;
+        assert!(
+            args.len() <= ARGS.len(),
+            "{call:?} with {} arguments",
+            args.len()
+        );
+        let moves = ARGS.iter().zip(args).filter_map(|(&dest, arg)| match *arg {
+            SyscallArg::Reg(src) => Some((dest.into(), src.into())),
+            _ => None,
+        });
+        move_registers(self, moves.collect(), known)?;
+        for (&dest, arg) in ARGS.iter().zip(args) {
+            match *arg {
+                SyscallArg::Const(value) => {
+                    self.load_const(dest, value, known)?;
+                }
+                SyscallArg::Label(label) => {
+                    self.lea(dest, ptr(label))?;
+                    known.forget(dest);
+                }
+                SyscallArg::Reg(_) => {}
+            }
+        }
+        let number = call.number(SyscallAbi::X86_64).unwrap();
+        self.load_const(rax, number as u64, known)?;
+        self.syscall()?;
+        for reg in [rax, rcx, r11] {
+            known.forget(reg);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Syscall, SyscallAbi, Syscalls};
+    use crate::load::KnownRegisters;
+    use crate::test_util::{exit_code, output};
+    use crate::{create_program, elf_from_asm};
+    use iced_x86::code_asm::*;
+
+    #[test]
+    fn test_numbers() {
+        let exit = |abi| Syscall::Exit.number(abi);
+        assert_eq!(Some(60), exit(SyscallAbi::X86_64));
+        assert_eq!(Some(1), exit(SyscallAbi::I386));
+        assert_eq!(Some(93), exit(SyscallAbi::Generic));
+        assert_eq!(None, Syscall::Open.number(SyscallAbi::Generic));
+    }
+
+    #[test]
+    fn test_exit() {
+        let mut a = CodeAssembler::new(64).unwrap();
+        a.exit(0, &mut KnownRegisters::new()).unwrap();
+        assert_eq!(create_program(), a.assemble(crate::PROGRAM_VADDR).unwrap());
+        // at the entry point rax and rdi are already zero
+        let mut a = CodeAssembler::new(64).unwrap();
+        a.exit(0, &mut KnownRegisters::linux_entry()).unwrap();
+        let bytes = a.assemble(0).unwrap();
+        assert_eq!(vec![0xb0, 60, 0x0f, 0x05], bytes); // mov al, 60; syscall
+    }
+
+    #[test]
+    fn test_write() {
+        let mut known = KnownRegisters::linux_entry();
+        let mut a = CodeAssembler::new(64).unwrap();
+        let mut msg = a.create_label();
+        a.write(1, msg, 6, &mut known).unwrap();
+        // rdi is still 1, so only the low byte of each register changes
+        assert_eq!(Some(1), known.get(rdi));
+        assert_eq!(None, known.get(rax));
+        a.exit(42, &mut known).unwrap();
+        a.set_label(&mut msg).unwrap();
+        a.db(b"hello\n").unwrap();
+
+        let out = output("syscalls_write", &elf_from_asm(&mut a).unwrap());
+        assert_eq!(b"hello\n", &out.stdout[..]);
+        assert_eq!(42, exit_code(&out.status));
+    }
+
+    #[test]
+    fn test_register_args() {
+        // read the ELF magic of the program itself, through the fd in rax
+        let mut known = KnownRegisters::linux_entry();
+        let mut a = CodeAssembler::new(64).unwrap();
+        let mut path = a.create_label();
+        let mut buf = a.create_label();
+        a.open(path, 0, 0, &mut known).unwrap();
+        a.read(rax, buf, 4, &mut known).unwrap();
+        // mmap a page, copy the magic there, and write it from the page
+        a.mmap(0, 4096, 3, 0x22, -1, 0, &mut known).unwrap();
+        a.mov(ecx, dword_ptr(buf)).unwrap();
+        a.mov(dword_ptr(rax), ecx).unwrap();
+        a.write(1, rax, 4, &mut known).unwrap();
+        a.exit(0, &mut known).unwrap();
+        a.set_label(&mut path).unwrap();
+        a.db(b"/proc/self/exe\0").unwrap();
+        a.set_label(&mut buf).unwrap();
+        a.dd(&[0]).unwrap();
+
+        let out = output("syscalls_registers", &elf_from_asm(&mut a).unwrap());
+        assert_eq!(b"\x7fELF", &out.stdout[..]);
+        assert_eq!(0, exit_code(&out.status));
+    }
+
+    #[test]
+    fn test_swap() {
+        // rdi and rsi trade values through xchg
+        let mut known = KnownRegisters::new();
+        let mut a = CodeAssembler::new(64).unwrap();
+        a.mov(esi, 1).unwrap();
+        a.mov(edi, 7).unwrap();
+        known.set(rsi, 1);
+        known.set(rdi, 7);
+        a.system_call(Syscall::Write, &[rsi.into(), rdi.into()], &mut known)
+            .unwrap();
+        assert_eq!((Some(7), Some(1)), (known.get(rsi), known.get(rdi)));
+        let instructions = a.instructions();
+        assert_eq!(
+            iced_x86::Mnemonic::Xchg,
+            instructions[2].mnemonic(),
+            "{}",
+            instructions[2]
+        );
+    }
+
+    #[test]
+    fn test_clone() {
+        // fork: the child writes first, while the parent waits for it
+        let mut known = KnownRegisters::linux_entry();
+        let mut a = CodeAssembler::new(64).unwrap();
+        let mut child = a.create_label();
+        let mut c = a.create_label();
+        let mut p = a.create_label();
+        const SIGCHLD: u64 = 17;
+        a.clone(SIGCHLD, 0, &mut known).unwrap();
+        a.test(eax, eax).unwrap();
+        a.jz(child).unwrap();
+        let args = [(-1).into(), 0.into(), 0.into(), 0.into()];
+        a.system_call(Syscall::Wait4, &args, &mut known).unwrap();
+        a.write(1, p, 1, &mut known).unwrap();
+        a.exit_group(0, &mut known).unwrap();
+
+        let mut known = KnownRegisters::new();
+        a.set_label(&mut child).unwrap();
+        a.write(1, c, 1, &mut known).unwrap();
+        a.exit(0, &mut known).unwrap();
+        a.set_label(&mut c).unwrap();
+        a.db(b"c").unwrap();
+        a.set_label(&mut p).unwrap();
+        a.db(b"p").unwrap();
+
+        let out = output("syscalls_clone", &elf_from_asm(&mut a).unwrap());
+        assert_eq!(b"cp", &out.stdout[..]);
+    }
+}
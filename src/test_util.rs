@@ -0,0 +1,62 @@
+//! Helpers for tests that execute generated binaries.
+
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::os::unix::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output};
+
+/// A fresh path in the temporary directory for a test binary called `name`.
+pub fn path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("minimal-elf-{}-{}", std::process::id(), name))
+}
+
+/// Run the executable at `path`, capturing its output.
+pub fn output_path(path: &Path) -> Output {
+    // Another test thread may fork while we hold the file open for writing,
+    // leaking the descriptor into its child until it calls exec; retry until
+    // that copy is gone rather than failing with ETXTBSY.
+    for _ in 0..100 {
+        match Command::new(path).output() {
+            Err(e) if e.raw_os_error() == Some(26) => {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            r => return r.unwrap(),
+        }
+    }
+    panic!("{} is still busy", path.display());
+}
+
+pub fn run_path(path: &Path) -> ExitStatus {
+    output_path(path).status
+}
+
+/// Write `bytes` to an executable file and run it, capturing its output.
+pub fn output(name: &str, bytes: &[u8]) -> Output {
+    let path = path(name);
+    {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o755)
+            .open(&path)
+            .unwrap();
+        file.write_all(bytes).unwrap();
+    }
+    let out = output_path(&path);
+    std::fs::remove_file(&path).unwrap();
+    out
+}
+
+pub fn run(name: &str, bytes: &[u8]) -> ExitStatus {
+    output(name, bytes).status
+}
+
+/// The exit code of a process, panicking if it was killed by a signal.
+pub fn exit_code(status: &ExitStatus) -> i32 {
+    match status.code() {
+        Some(code) => code,
+        None => panic!("terminated by signal {:?}", status.signal()),
+    }
+}